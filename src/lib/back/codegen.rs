@@ -8,11 +8,15 @@ use std::{fmt, mem};
 use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::str::FromStr;
+use std::iter::once;
 use super::llvm::*;
 use super::gc::*;
 use self::CodegenErr::*;
 use itertools::Itertools;
 
+/// The maximum number of nested layouts of the same recursive data type
+const MAX_ADT_DEPTH: usize = 64;
+
 /// Runtime errors
 #[derive(PartialEq, Eq)]
 enum RuntErr<'s> {
@@ -106,6 +110,7 @@ fn free_vars_in_expr<'src>(e: &ast::Expr<'src>) -> FreeVarInsts<'src> {
         Cast(ref c) => free_vars_in_expr(&c.expr),
         New(ref n) => free_vars_in_exprs(&n.members),
         Match(ref m) => free_vars_in_match(m),
+        Hole(_) => unreachable!(),
     }
 }
 
@@ -131,7 +136,7 @@ fn free_vars_in_lambda_filter_globals<'src, 'ctx>(
 fn free_vars_in_match<'src>(m: &ast::Match<'src>) -> FreeVarInsts<'src> {
     let mut fvs = free_vars_in_expr(&m.expr);
     for case in &m.cases {
-        let mut case_fvs = free_vars_in_expr(&case.body);
+        let mut case_fvs = free_vars_in_exprs(case.guard.iter().chain(once(&case.body)));
         for v in case.patt.variables() {
             case_fvs.remove(v.ident.s);
         }
@@ -140,6 +145,191 @@ fn free_vars_in_match<'src>(m: &ast::Match<'src>) -> FreeVarInsts<'src> {
     fvs
 }
 
+/// A value being matched on in a decision tree, like the matchee or a member of it
+#[derive(Clone)]
+struct Occurrence<'src, 'ctx> {
+    val: &'ctx Value,
+    typ: ast::Type<'src>,
+}
+
+/// A row of the clause matrix of a match being compiled to a decision tree
+///
+/// Each cell is the pattern that the occurrence in that column must match. A cell of
+/// `None` matches anything, without binding it.
+#[derive(Clone)]
+struct ClauseRow<'src: 'ast, 'ast, 'ctx> {
+    patts: Vec<Option<&'ast Pattern<'src>>>,
+    /// The variables bound so far, by patterns already removed from the row
+    bindings: Vec<(&'src str, &'ctx Value)>,
+    /// The index of the case that the row is of
+    case: usize,
+}
+
+/// The cases of a match being compiled to a decision tree
+struct MatchArms<'src, 'ctx> {
+    /// The block of the body of each case, if any leaf of the tree has selected it yet
+    bodies: Vec<Option<&'ctx BasicBlock>>,
+    /// For each case, the block of every leaf that selects it, and the values bound to the
+    /// variables of the pattern in that leaf
+    incoming: Vec<Vec<(Vec<(&'src str, &'ctx Value)>, &'ctx BasicBlock)>>,
+    /// The block to branch to when no case matches
+    fail: &'ctx BasicBlock,
+}
+
+/// The heap cell of a unique value that has been taken apart by a match
+///
+/// No one else references the value, and its members have all been loaded, so the cell may
+/// be reused when constructing a new heap allocated value of the same size. E.g. the cells of
+/// a list may be reused by a `map` over it, even if the element type changes.
+struct ReusableCell<'src, 'ctx> {
+    adt: &'src str,
+    inst: Vec<ast::Type<'src>>,
+    size: u64,
+    ptr: &'ctx Value,
+}
+
+/// Bind variables and expand or-patterns in the cells of `rows`, until every cell is either
+/// a wildcard, or a pattern that must be tested
+fn normalize_clause_rows<'src: 'ast, 'ast, 'ctx>(
+    rows: Vec<ClauseRow<'src, 'ast, 'ctx>>,
+    occs: &[Occurrence<'src, 'ctx>],
+) -> Vec<ClauseRow<'src, 'ast, 'ctx>> {
+    let mut normalized = Vec::new();
+    let mut stack = rows;
+    stack.reverse();
+    while let Some(mut row) = stack.pop() {
+        let mut or_patt = None;
+        for (i, occ) in occs.iter().enumerate() {
+            while let Some(p) = row.patts[i] {
+                match *p {
+                    // Nil has only a single value, so there is nothing to test
+                    Pattern::Wild(_) | Pattern::Nil(_) => row.patts[i] = None,
+                    Pattern::Variable(ref var) => {
+                        row.bindings.push((var.ident.s, occ.val));
+                        row.patts[i] = None
+                    }
+                    Pattern::As(ref a) => {
+                        row.bindings.push((a.var.ident.s, occ.val));
+                        row.patts[i] = Some(&a.patt)
+                    }
+                    Pattern::Or(ref o) => {
+                        or_patt = Some((i, &o.alts));
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+            if or_patt.is_some() {
+                break;
+            }
+        }
+        match or_patt {
+            // Expand the row into one row per alternative, tried in order
+            Some((i, alts)) => for alt in alts.iter().rev() {
+                let mut alt_row = row.clone();
+                alt_row.patts[i] = Some(alt);
+                stack.push(alt_row)
+            },
+            None => normalized.push(row),
+        }
+    }
+    normalized
+}
+
+/// Returns whether the patterns `a` and `b` have the same head constructor
+fn same_head_constr(a: &Pattern, b: &Pattern) -> bool {
+    match (a, b) {
+        (&Pattern::Deconstr(ref d1), &Pattern::Deconstr(ref d2)) => d1.constr.s == d2.constr.s,
+        (&Pattern::NumLit(ref n1), &Pattern::NumLit(ref n2)) => n1.lit == n2.lit,
+        (&Pattern::Cons(_), &Pattern::Cons(_)) => true,
+        _ => false,
+    }
+}
+
+/// Returns the patterns of the distinct head constructors in column `col`, in order of
+/// first occurence
+fn column_head_constrs<'src: 'ast, 'ast, 'ctx>(
+    rows: &[ClauseRow<'src, 'ast, 'ctx>],
+    col: usize,
+) -> Vec<&'ast Pattern<'src>> {
+    let mut heads: Vec<&'ast Pattern<'src>> = Vec::new();
+    for p in rows.iter().filter_map(|row| row.patts[col]) {
+        if !heads.iter().any(|h| same_head_constr(h, p)) {
+            heads.push(p)
+        }
+    }
+    heads
+}
+
+/// Specialize `rows` for values in column `col` constructed like `head`
+///
+/// Keep only the rows that may match such values, and replace their cell in the column with
+/// the subpatterns of the constructor.
+fn specialize_clause_rows<'src: 'ast, 'ast, 'ctx>(
+    rows: &[ClauseRow<'src, 'ast, 'ctx>],
+    col: usize,
+    head: &Pattern<'src>,
+) -> Vec<ClauseRow<'src, 'ast, 'ctx>> {
+    let arity = match *head {
+        Pattern::Deconstr(ref dec) => dec.subpatts.len(),
+        Pattern::Cons(_) => 2,
+        _ => 0,
+    };
+    rows.iter()
+        .filter_map(|row| {
+            let subs = match row.patts[col] {
+                None => vec![None; arity],
+                Some(p) if same_head_constr(p, head) => match *p {
+                    Pattern::Deconstr(ref dec) => dec.subpatts.iter().map(Some).collect(),
+                    Pattern::Cons(ref cons) => vec![Some(&cons.car), Some(&cons.cdr)],
+                    _ => vec![],
+                },
+                Some(_) => return None,
+            };
+            let patts = row.patts[..col]
+                .iter()
+                .cloned()
+                .chain(subs)
+                .chain(row.patts[col + 1..].iter().cloned())
+                .collect();
+            Some(ClauseRow {
+                patts,
+                bindings: row.bindings.clone(),
+                case: row.case,
+            })
+        })
+        .collect()
+}
+
+/// The rows of `rows` that match any value in column `col`, without the column
+fn default_clause_rows<'src: 'ast, 'ast, 'ctx>(
+    rows: &[ClauseRow<'src, 'ast, 'ctx>],
+    col: usize,
+) -> Vec<ClauseRow<'src, 'ast, 'ctx>> {
+    rows.iter()
+        .filter(|row| row.patts[col].is_none())
+        .map(|row| {
+            let mut row = row.clone();
+            row.patts.remove(col);
+            row
+        })
+        .collect()
+}
+
+/// Returns `occs` with the occurrence in column `col` replaced by `subs`
+fn replace_occurrence<'src, 'ctx>(
+    occs: &[Occurrence<'src, 'ctx>],
+    col: usize,
+    subs: Vec<Occurrence<'src, 'ctx>>,
+) -> Vec<Occurrence<'src, 'ctx>> {
+    occs[..col]
+        .iter()
+        .cloned()
+        .chain(subs)
+        .chain(occs[col + 1..].iter().cloned())
+        .collect()
+}
+
 enum CodegenErr {
     NumParseErr(String),
     ICE(String),
@@ -317,7 +507,7 @@ fn separate_func_bindings_mono<'src, 'ast>(
 }
 
 fn is_arithm_binop(op_name: &str) -> bool {
-    let arithm_binops = hashset!{ "add", "sub", "mul", "div" };
+    let arithm_binops = hashset!{ "add", "sub", "mul", "div", "bit-and", "bit-or", "bit-xor" };
     arithm_binops.contains(op_name)
 }
 
@@ -326,6 +516,12 @@ fn is_relational_binop(op_name: &str) -> bool {
     relational_binops.contains(op_name)
 }
 
+/// Returns whether the core binops are generated for operands of type `t`
+fn is_core_op_type(t: &ast::Type, default: &ast::Type) -> bool {
+    let t = t.var_to_default(default);
+    t.is_numeric() || t.get_const() == Some("Bool")
+}
+
 /// A codegenerator that visits all nodes in the AST, wherein it builds expressions
 pub struct CodeGenerator<'ctx, 'src> {
     ctx: &'ctx Context,
@@ -337,6 +533,13 @@ pub struct CodeGenerator<'ctx, 'src> {
     named_types: NamedTypes<'ctx, 'src>,
     adts: ast::Adts<'src>,
     gc: Gc<'ctx, 'src>,
+    /// The cell of a consumed unique value that the next construction in the current match
+    /// arm may reuse, instead of allocating
+    reusable_cell: Option<ReusableCell<'src, 'ctx>>,
+    /// The type that numeric type variables left in operator types are generated as
+    default_type: ast::Type<'src>,
+    /// The names of the recursive data types currently being laid out, innermost last
+    adts_in_progress: Vec<&'src str>,
 }
 
 impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
@@ -345,6 +548,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         builder: &'ctx Builder,
         module: &'ctx Module,
         adts: ast::Adts<'src>,
+        default_type: ast::Type<'src>,
     ) -> Self {
         let named_types = NamedTypes {
             real_world: StructType::new_named(ctx, "RealWorld", &[], false),
@@ -362,6 +566,9 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             named_types,
             adts,
             gc,
+            reusable_cell: None,
+            default_type,
+            adts_in_progress: Vec::new(),
         }
     }
 
@@ -459,6 +666,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     false,
                 ),
                 "Ptr" => PointerType::new(self.gen_type(&ts[0])),
+                // Uniqueness only matters to the type checker
+                "Unique" => self.gen_type(&ts[0]),
                 // It's not a builtin type function, which means it
                 // has to be a user-defined algebraic data type,
                 // unless bug in typechecker.
@@ -514,12 +723,23 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             .expect("ICE: non-opaque struct in populate_recursive_adt");
     }
 
+    /// Returns the LLVM type of the algebraic data type `name` instantiated with `inst`,
+    /// generating it if not already done
+    ///
+    /// Newtypes are not generated at all, but are represented by the type of their member.
     fn get_or_gen_adt_by_name_and_inst(
         &mut self,
         name: &'src str,
         inst: &[ast::Type<'src>],
     ) -> &'ctx Type {
-        if let Some(t) = self.named_types.adts.get(&(name, inst.to_vec())).cloned() {
+        let adt_type = if inst.is_empty() {
+            ast::Type::Const(name, None)
+        } else {
+            ast::Type::App(box ast::TypeFunc::Const(name), inst.to_vec())
+        };
+        if let Some(inner) = self.adts.newtype_inner_type(&adt_type) {
+            self.gen_type(&inner)
+        } else if let Some(t) = self.named_types.adts.get(&(name, inst.to_vec())).cloned() {
             t
         } else {
             let adt = self.adts
@@ -533,13 +753,31 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 })
                 .clone();
             if self.adts.adt_is_recursive(&adt) {
+                // A nested data type, like `(data (Nested a) (N a (Nested (List a))))`, would
+                // require layouts for ever larger instantiations
+                if self.adts_in_progress.iter().filter(|&&n| n == name).count() > MAX_ADT_DEPTH {
+                    adt.name.pos.error(format!(
+                        "Data type `{}` can not be laid out, as it contains itself at ever \
+                         larger types, like `{}`",
+                        name,
+                        ast::Type::App(box ast::TypeFunc::Const(name), inst.to_vec())
+                    ));
+                    adt.name.pos.print_help(
+                        "Nested data types, like `(Nested a)` containing a `(Nested (List a))`, \
+                         are not supported. Recursive uses of a data type must have the same \
+                         type arguments as its definition",
+                    );
+                    exit()
+                }
                 let inner = StructType::new_opaque(self.ctx, &format!("{}_in", name));
                 self.named_types
                     .adts_inner
                     .insert((name, inst.to_vec()), inner);
                 let ptr = PointerType::new(inner);
                 self.named_types.adts.insert((name, inst.to_vec()), ptr);
+                self.adts_in_progress.push(name);
                 self.populate_recursive_adt(&adt, inst, inner);
+                self.adts_in_progress.pop();
             } else {
                 let t = self.gen_adt(&adt, inst);
                 self.named_types.adts.insert((name, inst.to_vec()), t);
@@ -662,8 +900,18 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             ("sub", Builder::build_sub),
             ("mul", Builder::build_mul),
         ];
-        let int_arithm_binops = [("div", Builder::build_sdiv as BinopBuilder<'ctx>)];
-        let uint_arithm_binops = [("div", Builder::build_udiv as BinopBuilder<'ctx>)];
+        let int_arithm_binops = [
+            ("div", Builder::build_sdiv as BinopBuilder<'ctx>),
+            ("bit-and", Builder::build_and),
+            ("bit-or", Builder::build_or),
+            ("bit-xor", Builder::build_xor),
+        ];
+        let uint_arithm_binops = [
+            ("div", Builder::build_udiv as BinopBuilder<'ctx>),
+            ("bit-and", Builder::build_and),
+            ("bit-or", Builder::build_or),
+            ("bit-xor", Builder::build_xor),
+        ];
         let float_arithm_binops = [("div", Builder::build_fdiv as BinopBuilder<'ctx>)];
         let relational_binops = [
             ("eq", Builder::build_eq as BinopBuilder<'ctx>),
@@ -697,6 +945,11 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 }
             }
         }
+
+        // Of the relational binops, `Bool` only has equality
+        let bool_eq_type = ast::Type::new_relational_binop(ast::Type::Const("Bool", None));
+        let func = self.gen_binop_func("eq-Bool", &bool_eq_type, Builder::build_eq);
+        env.add_global_mono("eq-Bool", Global::Func(func))
     }
 
     fn gen_extern_decls(
@@ -773,7 +1026,9 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         match env.get(var.ident.s, inst) {
             // NOTE: Ugly hack to fix generic codegen for some binops
             Some(Var::Global(_)) if is_arithm_binop(var.ident.s) => {
-                let maybe_op_typ = type_canon.get_cons_binop().map(|t| t.var_to_int64());
+                let maybe_op_typ = type_canon
+                    .get_cons_binop()
+                    .map(|t| t.var_to_default(&self.default_type));
                 let op_typ = maybe_op_typ
                     .unwrap_or_else(|| panic!("ICE: binop has bad type {}", type_canon));
                 assert!(
@@ -788,15 +1043,22 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                 var2.ident.s = &f;
                 self.gen_variable(env, &var2)
             }
-            Some(Var::Global(_)) if is_relational_binop(var.ident.s) => {
+            // Relational binops of user defined types are defined by instances, and are
+            // monomorphized as any other global
+            Some(Var::Global(_))
+                if is_relational_binop(var.ident.s)
+                    && type_canon
+                        .get_cons_relational_binop()
+                        .map_or(true, |t| is_core_op_type(t, &self.default_type)) =>
+            {
                 let maybe_op_typ = type_canon
                     .get_cons_relational_binop()
-                    .map(|t| t.var_to_int64());
+                    .map(|t| t.var_to_default(&self.default_type));
                 let op_typ = maybe_op_typ.unwrap_or_else(|| {
                     panic!("ICE: binary relational op has bad type {}", type_canon)
                 });
                 assert!(
-                    op_typ.is_int() || op_typ.is_uint() || op_typ.is_float(),
+                    op_typ.is_numeric() || op_typ.get_const() == Some("Bool"),
                     "ICE: relational binop has bad type {}",
                     type_canon
                 );
@@ -1081,6 +1343,11 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
     fn gen_obj_visitor(&mut self, typ: &ast::Type<'src>) -> Option<&'ctx Function> {
         if self.gc.obj_visitors.contains_key(typ) {
             self.gc.obj_visitors[typ]
+        } else if let Some(inner) = self.adts.newtype_inner_type(typ) {
+            // A newtype is represented as its member
+            let func = self.gen_obj_visitor(&inner);
+            self.gc.obj_visitors.insert(typ.clone(), func);
+            func
         } else {
             let func = match *typ {
                 ast::Type::Const(name, _) if self.adts.defs.contains_key(name) => {
@@ -1098,6 +1365,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     "->" => Some(self.gc.closure_obj_visitor),
                     "Cons" => self.gen_cons_obj_visitor(&ts[0], &ts[1]),
                     "Ptr" => self.gen_ptr_obj_visitor(&ts[0]),
+                    "Unique" => self.gen_obj_visitor(&ts[0]),
                     name if self.adts.defs.contains_key(name) => {
                         Some(if self.adts.adt_of_name_is_recursive(name) {
                             self.gc.handle_self_obj_visitor
@@ -1260,6 +1528,9 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         let lambda_name = format!("lambda_{}_{}", parent_name, name);
         let func = self.gen_closure_func_decl(lambda_name, &lam.typ);
         let parent_func = mem::replace(&mut *self.current_func.borrow_mut(), Some(func));
+        // The cell is a value of the parent function, and the closure may be called any
+        // number of times
+        let parent_reusable_cell = self.reusable_cell.take();
         let entry = func.append("entry");
         let parent_block = mem::replace(&mut *self.current_block.borrow_mut(), Some(entry));
         self.builder.position_at_end(entry);
@@ -1316,6 +1587,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
 
         // Restore state of code generator
         env.locals = old_locals;
+        self.reusable_cell = parent_reusable_cell;
         *self.current_func.borrow_mut() = parent_func;
         *self.current_block.borrow_mut() = parent_block;
         self.builder
@@ -1590,21 +1862,16 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         })
     }
 
-    fn build_of_variant(&mut self, val: &'ctx Value, variant: &str) -> &'ctx Value {
-        let e_i = self.adts
-            .variant_index(variant)
-            .expect("ICE: No variant_index in gen_of_variant");
-        let expected_i = (e_i as u16).compile(self.ctx);
-        let found_i = if self.adts.adt_of_variant_is_recursive(variant) {
+    /// Returns the tag of `val`, a value of the parent ADT of `variant`
+    fn build_variant_tag(&mut self, val: &'ctx Value, variant: &str) -> &'ctx Value {
+        let tag = if self.adts.adt_of_variant_is_recursive(variant) {
             // If ADT is recursive, it's also behind a pointer
             self.build_load_car(val)
         } else {
             self.build_extract_car(val)
         };
-        found_i.set_name("of-variant_found-variant-i");
-        let r = self.builder.build_eq(expected_i, found_i);
-        r.set_name("of-variant_is-of-expected-variant");
-        r
+        tag.set_name("variant-tag");
+        tag
     }
 
     fn build_as_variant(
@@ -1685,10 +1952,15 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             .parent_adt_of_variant(variant)
             .expect("ICE: No parent_adt_of_variant in gen_new")
             .clone();
+        if adt.is_newtype {
+            // A newtype is represented as its member, so construction is a no-op
+            return self.gen_expr(env, &n.members[0], Some("gen-new_newtype"));
+        }
         let i = adt.variant_index(variant)
             .expect("ICE: No variant_index in gen_new");
         let tag = (i as u16).compile(self.ctx);
-        let adt_inst = n.typ.get_adt_inst_args().unwrap_or(&[]);
+        let typ = n.typ.get_unique().unwrap_or(&n.typ);
+        let adt_inst = typ.get_adt_inst_args().unwrap_or(&[]);
         let largest_type = self.gen_largest_adt_variant_type(&adt, adt_inst);
         let unwrapped = self.gen_tuple(env, &n.members);
         unwrapped.set_name("gen-new_unwrapped");
@@ -1709,7 +1981,11 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             let wrapped_largest =
                 self.build_struct_of_type(&[tag, unwrapped_largest], adt_inner_type);
             wrapped_largest.set_name("gen-new_wrapped-larg");
-            let wrapped_largest_heap = self.gen_gc_alloc_adt(adt.name.s, adt_inst, adt_inner_type);
+            let reused = self.take_reusable_cell(adt.name.s, adt_inst, adt_inner_type);
+            let wrapped_largest_heap = match reused {
+                Some(cell) => cell,
+                None => self.gen_gc_alloc_adt(adt.name.s, adt_inst, adt_inner_type),
+            };
             self.builder
                 .build_store(wrapped_largest, wrapped_largest_heap);
             wrapped_largest_heap.set_name("gen-new_wrapped-larg-heap");
@@ -1722,132 +1998,400 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
         }
     }
 
-    fn gen_match_case_(
+    /// Take the reusable cell to store a value of the ADT `name` of instantiation `inst` in,
+    /// if the cell is of the same size as `inner_type`
+    ///
+    /// If the cell held a value of another type, the object visitor of the cell is updated,
+    /// so that the GC traces the members of the new value.
+    fn take_reusable_cell(
+        &mut self,
+        name: &str,
+        inst: &[ast::Type<'src>],
+        inner_type: &'ctx Type,
+    ) -> Option<&'ctx Value> {
+        let size = self.size_of_(inner_type);
+        let fits = self.reusable_cell
+            .as_ref()
+            .map_or(false, |cell| cell.size == size);
+        if fits {
+            let cell = self.reusable_cell.take().unwrap();
+            let ptr = self.builder
+                .build_bit_cast(cell.ptr, type_generic_ptr(self.ctx));
+            ptr.set_name("reused-cell");
+            if cell.adt != name || &cell.inst[..] != inst {
+                let visitor = self.gen_adt_obj_visitor(name, inst);
+                self.gc.build_update_obj_visitor(ptr, visitor);
+            }
+            Some(ptr)
+        } else {
+            None
+        }
+    }
+
+    /// If the matchee is unique and of a heap allocated ADT, and `case` takes it apart with
+    /// a constructor pattern, returns the cell of the matchee to reuse in the body of `case`
+    ///
+    /// Only the top level pattern of the case is considered. The cells of unique members
+    /// taken apart by nested constructor patterns are not reused. Neither is the matchee of
+    /// an as-pattern, as it's still bound as a whole in the body.
+    fn reusable_cell_of_case(
+        &self,
+        case: &ast::Case<'src>,
+        matchee: &Occurrence<'src, 'ctx>,
+        matchee_is_unique: bool,
+    ) -> Option<ReusableCell<'src, 'ctx>> {
+        match case.patt {
+            Pattern::Deconstr(ref dec) if matchee_is_unique => {
+                let adt = self.adts.parent_adt_of_variant(dec.constr.s)?;
+                if self.adts.adt_is_recursive(adt) && !adt.is_newtype {
+                    let inst = matchee.typ.get_adt_inst_args().unwrap_or(&[]).to_vec();
+                    let inner_type = *self.named_types
+                        .adts_inner
+                        .get(&(adt.name.s, inst.clone()))?;
+                    Some(ReusableCell {
+                        adt: adt.name.s,
+                        inst,
+                        size: self.size_of_(inner_type),
+                        ptr: matchee.val,
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the values of the members of `occ`, as deconstructed by the constructor
+    /// pattern `patt`
+    fn build_sub_occurrences(
+        &mut self,
+        patt: &Pattern<'src>,
+        occ: &Occurrence<'src, 'ctx>,
+    ) -> Vec<Occurrence<'src, 'ctx>> {
+        match *patt {
+            Pattern::Deconstr(ref dec) => {
+                let variant = dec.constr.s;
+                let inst = occ.typ.get_adt_inst_args().unwrap_or(&[]);
+                let member_ts = self.adts
+                    .members_with_inst_of_variant_with_name(variant, inst)
+                    .expect("ICE: No members of variant in build_sub_occurrences");
+                let (last_t, init_ts) = match member_ts.split_last() {
+                    Some(x) => x,
+                    None => return vec![],
+                };
+                // A newtype has a single variant, and is represented as its member
+                let mut remaining = if self.adts.adt_of_variant_is_newtype(variant) {
+                    occ.val
+                } else {
+                    self.build_as_variant(occ.val, variant, inst)
+                };
+                let mut occs = Vec::new();
+                for t in init_ts {
+                    occs.push(Occurrence {
+                        val: self.build_extract_car(remaining),
+                        typ: t.clone(),
+                    });
+                    remaining = self.build_extract_cdr(remaining);
+                }
+                occs.push(Occurrence {
+                    val: remaining,
+                    typ: last_t.clone(),
+                });
+                occs
+            }
+            Pattern::Cons(_) => {
+                let (car_t, cdr_t) = occ.typ
+                    .get_cons()
+                    .expect("ICE: Type of occurrence not cons in build_sub_occurrences");
+                vec![
+                    Occurrence {
+                        val: self.build_extract_car(occ.val),
+                        typ: car_t.clone(),
+                    },
+                    Occurrence {
+                        val: self.build_extract_cdr(occ.val),
+                        typ: cdr_t.clone(),
+                    },
+                ]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Generate the subtree of the decision tree where the occurrence in column `col`
+    /// matched the constructor of `head`
+    fn gen_decision_subtree(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        bindings: &mut BTreeMap<&'src str, (&'ast SrcPos<'src>, &'ctx Value)>,
-        matchee: &'ctx Value,
-        matchee_adt_inst: &[ast::Type<'src>],
-        patt: &'ast Pattern<'src>,
-        body_type: &'ctx Type,
-        next_branch: &'ctx BasicBlock,
+        cases: &'ast [ast::Case<'src>],
+        arms: &mut MatchArms<'src, 'ctx>,
+        rows: &[ClauseRow<'src, 'ast, 'ctx>],
+        occs: &[Occurrence<'src, 'ctx>],
+        col: usize,
+        head: &Pattern<'src>,
     ) {
-        match *patt {
-            Pattern::Nil(_) => (),
-            Pattern::NumLit(ref lit) => {
-                let n = self.gen_num(lit);
-                let eq = self.builder.build_eq(matchee, n);
+        let sub_occs = self.build_sub_occurrences(head, &occs[col]);
+        let rows = specialize_clause_rows(rows, col, head);
+        let occs = replace_occurrence(occs, col, sub_occs);
+        self.gen_decision_tree(env, cases, arms, rows, &occs)
+    }
+
+    /// Switch on the tag of the ADT in column `col`, and continue with the rows that match
+    /// each variant
+    fn gen_variant_switch(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        cases: &'ast [ast::Case<'src>],
+        arms: &mut MatchArms<'src, 'ctx>,
+        rows: Vec<ClauseRow<'src, 'ast, 'ctx>>,
+        occs: &[Occurrence<'src, 'ctx>],
+        col: usize,
+    ) {
+        let heads = column_head_constrs(&rows, col);
+        let variants = heads
+            .iter()
+            .map(|head| match **head {
+                Pattern::Deconstr(ref dec) => dec.constr.s,
+                _ => unreachable!("ICE: Non-variant pattern in gen_variant_switch"),
+            })
+            .collect::<Vec<_>>();
+        let n_variants = self.adts
+            .parent_adt_of_variant(variants[0])
+            .expect("ICE: No parent adt of variant in gen_variant_switch")
+            .variants
+            .len();
+        let tag = self.build_variant_tag(occs[col].val, variants[0]);
+        let parent_func = self.current_func.borrow().unwrap();
+        // If all variants are tested for, no value can fall through to the default
+        let default_block = if variants.len() == n_variants {
+            arms.fail
+        } else {
+            parent_func.append("switch_default")
+        };
+        let variant_blocks = variants
+            .iter()
+            .map(|v| parent_func.append(&format!("variant_{}", v)))
+            .collect::<Vec<_>>();
+        let switch_cases = variants
+            .iter()
+            .zip(&variant_blocks)
+            .map(|(v, &block)| {
+                let i = self.adts
+                    .variant_index(v)
+                    .expect("ICE: No variant_index in gen_variant_switch");
+                ((i as u16).compile(self.ctx), block)
+            })
+            .collect::<Vec<_>>();
+        self.builder.build_switch(tag, default_block, &switch_cases);
+        for (head, &block) in heads.iter().zip(&variant_blocks) {
+            self.builder.position_at_end(block);
+            *self.current_block.borrow_mut() = Some(block);
+            self.gen_decision_subtree(env, cases, arms, &rows, occs, col, head);
+        }
+        if variants.len() != n_variants {
+            self.builder.position_at_end(default_block);
+            *self.current_block.borrow_mut() = Some(default_block);
+            let occs = replace_occurrence(occs, col, vec![]);
+            self.gen_decision_tree(env, cases, arms, default_clause_rows(&rows, col), &occs)
+        }
+    }
+
+    /// Test the number in column `col` against each distinct literal of the column in turn,
+    /// and continue with the rows that match it
+    fn gen_num_lit_tests(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        cases: &'ast [ast::Case<'src>],
+        arms: &mut MatchArms<'src, 'ctx>,
+        rows: Vec<ClauseRow<'src, 'ast, 'ctx>>,
+        occs: &[Occurrence<'src, 'ctx>],
+        col: usize,
+    ) {
+        for head in column_head_constrs(&rows, col) {
+            let lit = match *head {
+                Pattern::NumLit(ref lit) => lit,
+                _ => unreachable!("ICE: Non-number pattern in gen_num_lit_tests"),
+            };
+            let n = self.gen_num(lit);
+            let eq = self.builder.build_eq(occs[col].val, n);
+            let parent_func = self.current_func.borrow().unwrap();
+            let then_br = parent_func.append("num_eq");
+            let else_br = parent_func.append("num_neq");
+            self.builder.build_cond_br(eq, then_br, else_br);
+            self.builder.position_at_end(then_br);
+            *self.current_block.borrow_mut() = Some(then_br);
+            self.gen_decision_subtree(env, cases, arms, &rows, occs, col, head);
+            self.builder.position_at_end(else_br);
+            *self.current_block.borrow_mut() = Some(else_br);
+        }
+        let occs = replace_occurrence(occs, col, vec![]);
+        self.gen_decision_tree(env, cases, arms, default_clause_rows(&rows, col), &occs)
+    }
+
+    /// Branch from the current block to the body of the arm of `row`
+    fn gen_br_to_arm(
+        &mut self,
+        arms: &mut MatchArms<'src, 'ctx>,
+        row: ClauseRow<'src, 'ast, 'ctx>,
+    ) {
+        let body_block = match arms.bodies[row.case] {
+            Some(block) => block,
+            None => {
                 let parent_func = self.current_func.borrow().unwrap();
-                let then_br = parent_func.append("cond_then");
-                self.builder.build_cond_br(eq, then_br, next_branch);
-                self.builder.position_at_end(then_br);
-                *self.current_block.borrow_mut() = Some(then_br);
+                let block = parent_func.append(&format!("case_{}", row.case));
+                arms.bodies[row.case] = Some(block);
+                block
             }
-            Pattern::StrLit(_) => unimplemented!(),
-            Pattern::Variable(ref var) => {
-                if var.ident.s != "_" {
-                    if let Some((_, _)) = bindings.insert(var.ident.s, (&var.ident.pos, matchee)) {
-                        unimplemented!("multiple occurences of identifier")
-                    }
+        };
+        self.builder.build_br(body_block);
+        let from = self.current_block.borrow().unwrap();
+        arms.incoming[row.case].push((row.bindings, from));
+    }
+
+    /// Select the arm of the first row, if its guard holds
+    ///
+    /// If the guard does not hold, continue matching with the remaining rows.
+    fn gen_decision_leaf(
+        &mut self,
+        env: &mut Env<'src, 'ctx>,
+        cases: &'ast [ast::Case<'src>],
+        arms: &mut MatchArms<'src, 'ctx>,
+        mut rows: Vec<ClauseRow<'src, 'ast, 'ctx>>,
+        occs: &[Occurrence<'src, 'ctx>],
+    ) {
+        let row = rows.remove(0);
+        match cases[row.case].guard {
+            Some(ref guard) => {
+                for &(var, val) in &row.bindings {
+                    env.push_local_mono(var, val);
+                }
+                let holds = self.gen_expr(env, guard, Some("case_guard"));
+                for &(var, _) in &row.bindings {
+                    env.pop_local(var);
                 }
-            }
-            Pattern::Deconstr(ref deconst) => {
-                let variant = deconst.constr.s;
-                let variant_member_types = self.adts
-                    .members_with_inst_of_variant_with_name(variant, matchee_adt_inst)
-                    .unwrap();
-                let of_variant = self.build_of_variant(matchee, variant);
                 let parent_func = self.current_func.borrow().unwrap();
-                let then_br = parent_func.append("cond_then");
-                self.builder.build_cond_br(of_variant, then_br, next_branch);
+                let then_br = parent_func.append("guard_then");
+                let else_br = parent_func.append("guard_else");
+                self.builder.build_cond_br(holds, then_br, else_br);
                 self.builder.position_at_end(then_br);
                 *self.current_block.borrow_mut() = Some(then_br);
-                if let Some((last_sub, subs)) = deconst.subpatts.split_last() {
-                    let (last_member_t, member_ts) = variant_member_types.split_last().unwrap();
-                    let inner = self.build_as_variant(matchee, variant, matchee_adt_inst);
-                    let mut remaining = inner;
-                    for (sub, member_t) in subs.into_iter().zip(member_ts) {
-                        let sub_matchee = self.build_extract_car(remaining);
-                        let sub_matchee_adt_inst = member_t.get_adt_inst_args().unwrap_or(&[]);
-                        self.gen_match_case_(
-                            env,
-                            bindings,
-                            sub_matchee,
-                            sub_matchee_adt_inst,
-                            sub,
-                            body_type,
-                            next_branch,
-                        );
-                        remaining = self.build_extract_cdr(remaining);
-                    }
-                    let sub_matchee = remaining;
-                    let sub_matchee_adt_inst = last_member_t.get_adt_inst_args().unwrap_or(&[]);
-                    self.gen_match_case_(
-                        env,
-                        bindings,
-                        sub_matchee,
-                        sub_matchee_adt_inst,
-                        last_sub,
-                        body_type,
-                        next_branch,
-                    );
-                }
+                self.gen_br_to_arm(arms, row);
+                self.builder.position_at_end(else_br);
+                *self.current_block.borrow_mut() = Some(else_br);
+                self.gen_decision_tree(env, cases, arms, rows, occs)
             }
+            None => self.gen_br_to_arm(arms, row),
         }
     }
 
-    fn gen_match_case(
+    /// Generate a decision tree, starting in the current block, that matches the
+    /// occurrences `occs` against the clause matrix `rows`, and branches to the arm of the
+    /// first row that matches
+    fn gen_decision_tree(
         &mut self,
         env: &mut Env<'src, 'ctx>,
-        matchee: &'ctx Value,
-        matchee_adt_inst: &[ast::Type<'src>],
-        case: &ast::Case<'src>,
-        next_branch: &'ctx BasicBlock,
-    ) -> &'ctx Value {
-        let mut patt_bindings = BTreeMap::new();
-        let body_type = self.gen_type(case.body.get_type());
-        self.gen_match_case_(
-            env,
-            &mut patt_bindings,
-            matchee,
-            matchee_adt_inst,
-            &case.patt,
-            body_type,
-            next_branch,
-        );
-        for (var, &(_, val)) in &patt_bindings {
-            env.push_local_mono(var, val);
+        cases: &'ast [ast::Case<'src>],
+        arms: &mut MatchArms<'src, 'ctx>,
+        rows: Vec<ClauseRow<'src, 'ast, 'ctx>>,
+        occs: &[Occurrence<'src, 'ctx>],
+    ) {
+        let rows = normalize_clause_rows(rows, occs);
+        if rows.is_empty() {
+            self.builder.build_br(arms.fail);
+            return;
         }
-        let r = self.gen_expr(env, &case.body, Some("case_body"));
-        for (var, _) in patt_bindings {
-            env.pop_local(var);
+        // Test the first column that the first row does not match unconditionally
+        let (col, head) = match rows[0].patts.iter().position(|p| p.is_some()) {
+            Some(col) => (col, rows[0].patts[col].unwrap()),
+            None => return self.gen_decision_leaf(env, cases, arms, rows, occs),
+        };
+        match *head {
+            Pattern::Deconstr(ref dec) if !self.adts.adt_of_variant_is_newtype(dec.constr.s) => {
+                self.gen_variant_switch(env, cases, arms, rows, occs, col)
+            }
+            Pattern::NumLit(_) => self.gen_num_lit_tests(env, cases, arms, rows, occs, col),
+            Pattern::StrLit(_) => unimplemented!(),
+            // Newtypes and cons pairs only have a single constructor, so there is nothing to test
+            Pattern::Deconstr(_) | Pattern::Cons(_) => {
+                self.gen_decision_subtree(env, cases, arms, &rows, occs, col, head)
+            }
+            _ => unreachable!("ICE: Pattern not normalized in gen_decision_tree"),
         }
-        r
     }
 
+    /// Generate a match by compiling its cases to a decision tree
+    ///
+    /// Along any path of the tree, each test of the matchee or its members is done at most
+    /// once, and the tags of ADTs are tested with a `switch`. The body of each case is
+    /// generated once, with the variables of the pattern joined by phi nodes from every leaf
+    /// of the tree that selects the case.
     fn gen_match(&mut self, env: &mut Env<'src, 'ctx>, m: &'ast ast::Match<'src>) -> &'ctx Value {
         let expr = self.gen_expr(env, &m.expr, Some("matchee"));
-        let expr_adt_inst = m.expr.get_type().get_adt_inst_args().unwrap_or(&[]);
+        // The cell of an enclosing match may only be reused on paths through a single arm of
+        // this match, so to be simple, it's not reused at all within it
+        let outer_reusable_cell = self.reusable_cell.take();
+        let matchee_is_unique = m.expr.get_type().get_unique().is_some();
         let parent_func = self.current_func.borrow().unwrap();
-
-        let case_blocks = m.cases
-            .iter()
-            .enumerate()
-            .map(|(i, case)| (case, parent_func.append(&format!("case_{}", i))))
-            .collect::<Vec<_>>();
         let default_block = parent_func.append("case_default");
         let final_block = parent_func.append("case_final");
-        let mut case_phi_nodes = Vec::new();
-
-        let first_case_block = case_blocks.get(0).expect("ICE: No cases in gen_match").1;
-        self.builder.build_br(first_case_block);
+        let mut arms = MatchArms {
+            bodies: vec![None; m.cases.len()],
+            incoming: vec![Vec::new(); m.cases.len()],
+            fail: default_block,
+        };
+        let rows = m.cases
+            .iter()
+            .enumerate()
+            .map(|(i, case)| ClauseRow {
+                patts: vec![Some(&case.patt)],
+                bindings: Vec::new(),
+                case: i,
+            })
+            .collect();
+        let occs = [
+            Occurrence {
+                val: expr,
+                typ: m.expr
+                    .get_type()
+                    .get_unique()
+                    .unwrap_or(m.expr.get_type())
+                    .clone(),
+            },
+        ];
+        self.gen_decision_tree(env, &m.cases, &mut arms, rows, &occs);
 
-        let mut it = case_blocks.iter().peekable();
-        while let Some(&(case, block)) = it.next() {
+        let mut case_phi_nodes = Vec::new();
+        for (i, case) in m.cases.iter().enumerate() {
+            // No leaf of the tree selects an unreachable case
+            let block = match arms.bodies[i] {
+                Some(block) => block,
+                None => continue,
+            };
             self.builder.position_at_end(block);
             *self.current_block.borrow_mut() = Some(block);
-            let next_block = it.peek().map(|&&(_, b)| b).unwrap_or(default_block);
-            let case_val = self.gen_match_case(env, expr, expr_adt_inst, &case, next_block);
+            let vars = case.patt.variable_names();
+            for &var in &vars {
+                let incoming = arms.incoming[i]
+                    .iter()
+                    .map(|&(ref bindings, from)| {
+                        let val = bindings
+                            .iter()
+                            .find(|&&(v, _)| v == var)
+                            .expect("ICE: Pattern variable not bound in leaf of decision tree")
+                            .1;
+                        (val, from)
+                    })
+                    .collect::<Vec<_>>();
+                let val = self.builder.build_phi(incoming[0].0.get_type(), &incoming);
+                env.push_local_mono(var, val);
+            }
+            self.reusable_cell = self.reusable_cell_of_case(case, &occs[0], matchee_is_unique);
+            let case_val = self.gen_expr(env, &case.body, Some("case_body"));
+            self.reusable_cell = None;
+            for var in vars {
+                env.pop_local(var);
+            }
             // The block jumped from to `final_block` on successful match.
             // I.e., the one to use in the phi node
             let case_last_block = self.current_block.borrow().unwrap();
@@ -1855,6 +2399,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             case_phi_nodes.push((case_val, case_last_block));
         }
 
+        self.reusable_cell = outer_reusable_cell;
+
         // Default block. What to do when all user-defined cases fell through (runtime error)
         self.builder.position_at_end(default_block);
         *self.current_block.borrow_mut() = Some(default_block);
@@ -1895,6 +2441,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             Expr::Cast(ref c) => opt_set_name(self.gen_cast(env, c), name),
             Expr::New(ref n) => opt_set_name(self.gen_new(env, n), name),
             Expr::Match(ref m) => opt_set_name(self.gen_match(env, m), name),
+            // Compilation stops after type checking if there are any holes
+            Expr::Hole(_) => unreachable!(),
         }
     }
 
@@ -2022,7 +2570,8 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
             if main.sig.body != expect {
                 let error_msg = format!(
                     "main function has wrong type. Expected type `{}`, found type `{}`",
-                    expect, main.sig
+                    ast.type_aliases.resugar(&expect),
+                    main.sig
                 );
                 if main.sig.is_monomorphic() {
                     main.pos.error_exit(error_msg)
@@ -2031,7 +2580,7 @@ impl<'src: 'ast, 'ast, 'ctx> CodeGenerator<'ctx, 'src> {
                     main.pos.print_help(
                         "Try adding type annotations to enforce correct type \
                          during type-checking.\n\
-                         E.g. `(define: main (-> RealWorld (Cons Nil RealWorld)) ...)`",
+                         E.g. `(define: main (IO Nil) ...)`",
                     );
                     exit()
                 }
@@ -24,7 +24,13 @@ pub fn compile(
     let builder = Builder::new(&context);
     let module = Module::new("main", &context);
 
-    let mut codegenerator = CodeGenerator::new(&context, &builder, &module, ast.adts.clone());
+    let mut codegenerator = CodeGenerator::new(
+        &context,
+        &builder,
+        &module,
+        ast.adts.clone(),
+        ast.default_type.clone(),
+    );
     time_action(
         || codegenerator.gen_executable(&ast),
         |t| println!("    Generated LLVM code in {}s", t),
@@ -170,5 +176,6 @@ pub fn compile(
                 );
             }
         }
+        Emission::ImportGraph => panic!("ICE: Import graph emission reached code generation"),
     }
 }
@@ -1,4 +1,4 @@
-use lib::set_of;
+use lib::{map_of, set_of};
 use super::SrcPos;
 use itertools::{zip, Itertools};
 use std::collections::{BTreeMap, BTreeSet};
@@ -39,6 +39,22 @@ impl<'s> Poly<'s> {
     pub fn is_monomorphic(&self) -> bool {
         self.is_monomorphic_in_context(&mut BTreeSet::new())
     }
+
+    /// Returns the explicit type parameters, declared in a signature, in the order of the
+    /// quantifier list, like `a b` in `(for [a b] ...)`
+    ///
+    /// This is the order in which explicit type arguments, like in `(@ f Int64)`, are given.
+    /// Implicit parameters, which are only inferred, can't be given explicitly.
+    pub fn explicit_params(&self) -> Vec<TVar<'s>> {
+        self.params
+            .keys()
+            .filter(|tv| match **tv {
+                TVar::Explicit(_) => true,
+                TVar::Implicit(_) => false,
+            })
+            .cloned()
+            .collect()
+    }
 }
 
 impl<'s> Display for Poly<'s> {
@@ -63,6 +79,19 @@ impl<'s> Display for Poly<'s> {
 pub enum TypeFunc<'s> {
     Const(&'s str),
     Poly(Poly<'s>),
+    /// A type variable of higher kind, like `f` in `(f a)`
+    Var(TVar<'s>),
+}
+
+impl<'s> TypeFunc<'s> {
+    /// Returns the type function as a type, without applying it
+    pub fn to_type(&self) -> Type<'s> {
+        match *self {
+            TypeFunc::Const(c) => Type::Const(c, None),
+            TypeFunc::Poly(ref p) => Type::Poly(Box::new(p.clone())),
+            TypeFunc::Var(tv) => Type::Var(tv),
+        }
+    }
 }
 
 impl<'s> Display for TypeFunc<'s> {
@@ -70,6 +99,7 @@ impl<'s> Display for TypeFunc<'s> {
         match *self {
             TypeFunc::Const(s) => Display::fmt(s, f),
             TypeFunc::Poly(ref p) => Display::fmt(p, f),
+            TypeFunc::Var(ref tv) => Display::fmt(tv, f),
         }
     }
 }
@@ -120,10 +150,7 @@ impl<'s> Type<'s> {
     }
 
     pub fn new_io(ret: Type<'s>) -> Self {
-        Type::new_func(
-            TYPE_REALWORLD.clone(),
-            Type::new_cons(ret, TYPE_REALWORLD.clone()),
-        )
+        Type::App(Box::new(TypeFunc::Const("IO")), vec![ret])
     }
 
     pub fn new_cons(car_typ: Type<'s>, cdr_typ: Type<'s>) -> Self {
@@ -145,6 +172,10 @@ impl<'s> Type<'s> {
         Type::App(Box::new(TypeFunc::Const("Ptr")), vec![typ])
     }
 
+    pub fn new_unique(typ: Type<'s>) -> Self {
+        Type::App(Box::new(TypeFunc::Const("Unique")), vec![typ])
+    }
+
     pub fn new_binop(typ: Type<'s>) -> Self {
         Type::new_func(Type::new_cons(typ.clone(), typ.clone()), typ)
     }
@@ -153,6 +184,28 @@ impl<'s> Type<'s> {
         Type::new_func(Type::new_cons(typ.clone(), typ), Type::Const("Bool", None))
     }
 
+    /// Apply the type, as a type function, to `args`
+    ///
+    /// The type must be a type constructor, a type variable, or a partial application
+    /// of either. E.g. applying `(Pair Int64)` to `[Bool]` results in `(Pair Int64 Bool)`.
+    pub fn applied_to(self, args: Vec<Type<'s>>) -> Self {
+        if args.is_empty() {
+            return self;
+        }
+        match self {
+            Type::Var(tv) => Type::App(Box::new(TypeFunc::Var(tv)), args),
+            Type::Const(c, _) => Type::App(Box::new(TypeFunc::Const(c)), args),
+            Type::App(box TypeFunc::Poly(p), ts) => {
+                Type::App(Box::new(TypeFunc::Poly(p)), ts).canonicalize().applied_to(args)
+            }
+            Type::App(f, mut ts) => {
+                ts.extend(args);
+                Type::App(f, ts)
+            }
+            Type::Poly(p) => panic!("ICE: Application of polytype `{}`", p),
+        }
+    }
+
     /// If this type is an instantiated polytype, return the instantiation args
     pub fn get_inst_args(&self) -> Option<&[Type<'s>]> {
         match *self {
@@ -180,6 +233,7 @@ impl<'s> Type<'s> {
                 let all_args_mono = args.iter().all(|arg| arg.is_monomorphic_in_context(bound));
                 match **f {
                     TypeFunc::Const(_) => all_args_mono,
+                    TypeFunc::Var(ref v) => bound.contains(v) && all_args_mono,
                     TypeFunc::Poly(ref p) => {
                         let mut dup = BTreeSet::new();
                         for (&tv, _) in &p.params {
@@ -208,6 +262,52 @@ impl<'s> Type<'s> {
         self.is_monomorphic_in_context(&mut BTreeSet::new())
     }
 
+    /// Returns the explicit type variables that occur free in the type
+    pub fn explicit_type_vars(&self) -> BTreeSet<TVar<'s>> {
+        match *self {
+            Type::Var(tv @ TVar::Explicit(_)) => set_of(tv),
+            Type::Var(_) | Type::Const(..) => BTreeSet::new(),
+            Type::App(ref f, ref args) => {
+                let mut tvs = args.iter()
+                    .flat_map(|t| t.explicit_type_vars())
+                    .collect::<BTreeSet<_>>();
+                if let TypeFunc::Var(tv @ TVar::Explicit(_)) = **f {
+                    tvs.insert(tv);
+                }
+                tvs
+            }
+            Type::Poly(ref p) => {
+                let mut tvs = p.body.explicit_type_vars();
+                for tv in p.params.keys() {
+                    tvs.remove(tv);
+                }
+                tvs
+            }
+        }
+    }
+
+    /// Returns all type variables that occur free in the type, explicit and implicit
+    pub fn type_vars(&self) -> BTreeSet<TVar<'s>> {
+        match *self {
+            Type::Var(tv) => set_of(tv),
+            Type::Const(..) => BTreeSet::new(),
+            Type::App(ref f, ref args) => {
+                let mut tvs = args.iter().flat_map(|t| t.type_vars()).collect::<BTreeSet<_>>();
+                if let TypeFunc::Var(tv) = **f {
+                    tvs.insert(tv);
+                }
+                tvs
+            }
+            Type::Poly(ref p) => {
+                let mut tvs = p.body.type_vars();
+                for tv in p.params.keys() {
+                    tvs.remove(tv);
+                }
+                tvs
+            }
+        }
+    }
+
     pub fn canonicalize_in_context(&self, s: &mut BTreeMap<TVar<'s>, Type<'s>>) -> Type<'s> {
         match *self {
             Type::Const(_, _) => self.clone(),
@@ -218,6 +318,15 @@ impl<'s> Type<'s> {
                     .map(|arg| arg.canonicalize_in_context(s))
                     .collect(),
             ),
+            Type::App(box TypeFunc::Var(ref tv), ref args) => {
+                let args = args.iter()
+                    .map(|arg| arg.canonicalize_in_context(s))
+                    .collect();
+                match s.get(tv).cloned() {
+                    Some(f) => f.applied_to(args),
+                    None => Type::App(Box::new(TypeFunc::Var(*tv)), args),
+                }
+            }
             Type::App(box TypeFunc::Poly(ref p), ref args) => {
                 let shadoweds = zip(&p.params, args)
                     .filter_map(|((&param_v, _), arg)| {
@@ -299,11 +408,11 @@ impl<'s> Type<'s> {
         self.float_size().is_some()
     }
 
-    /// If a type variable with only the `Num` constraint, translate
-    /// to default integer type Int64
-    pub fn var_to_int64(&self) -> Self {
+    /// If a type variable with only numeric constraints, translate
+    /// to the default type `default`
+    pub fn var_to_default(&self, default: &Type<'s>) -> Self {
         match *self {
-            Type::Var(_) => Type::Const("Int64", None),
+            Type::Var(_) => default.clone(),
             _ => self.clone(),
         }
     }
@@ -359,26 +468,33 @@ impl<'s> Type<'s> {
         self.get_bin("Cons")
     }
 
+    /// If the type is of the form `(Unique T)`, return `T`
+    pub fn get_unique(&self) -> Option<&Type<'s>> {
+        match *self {
+            Type::App(box TypeFunc::Const("Unique"), ref ts) => Some(&ts[0]),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the type is an integer or a float
+    pub fn is_numeric(&self) -> bool {
+        self.is_int() || self.is_uint() || self.is_float()
+    }
+
+    /// Returns whether the type fulfills all of the builtin class constraints `cs`
+    ///
+    /// `Bool` is `Eq`, but not `Num`, as no arithmetic is generated for it. It used to be `Num`
+    /// only so that it could be compared with `=`, which now just requires `Eq`.
+    ///
+    /// A class that is not builtin is not fulfilled. It's up to the caller to look for an
+    /// instance of it, or to report the class as undefined.
     pub fn fulfills_constraints(&self, cs: &BTreeSet<&str>) -> bool {
-        use self::Type::*;
         cs.iter().all(|c| match *c {
-            "Num" => match *self {
-                Const("Int8", _)
-                | Const("Int16", _)
-                | Const("Int32", _)
-                | Const("Int64", _)
-                | Const("IntPtr", _)
-                | Const("UInt8", _)
-                | Const("UInt16", _)
-                | Const("UInt32", _)
-                | Const("UInt64", _)
-                | Const("UIntPtr", _)
-                | Const("Bool", _)
-                | Const("Float32", _)
-                | Const("Float64", _) => true,
-                _ => false,
-            },
-            _ => unimplemented!(),
+            "Eq" => self.is_numeric() || self.get_const() == Some("Bool"),
+            "Ord" | "Num" => self.is_numeric(),
+            "Integral" | "Bits" => self.is_int() || self.is_uint(),
+            "Fractional" => self.is_float(),
+            _ => false,
         })
     }
 }
@@ -531,6 +647,9 @@ impl<'s> Display for StrLit<'s> {
 pub struct Variable<'s> {
     pub ident: Ident<'s>,
     pub typ: Type<'s>,
+    /// Explicit type arguments, as in `(@ read-num Int64)`, filling the leading type
+    /// parameters declared in the signature of the variable
+    pub type_args: Vec<Type<'s>>,
 }
 
 impl<'s> Display for Variable<'s> {
@@ -539,6 +658,23 @@ impl<'s> Display for Variable<'s> {
     }
 }
 
+/// A typed hole, `?` or `?name`, standing in for an expression yet to be written
+///
+/// Type checking reports the type expected of each hole and the bindings in scope that fit
+/// it, and compilation stops afterwards.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Hole<'s> {
+    pub name: Option<&'s str>,
+    pub typ: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Display for Hole<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "?{}", self.name.unwrap_or(""))
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Bool<'s> {
     pub val: bool,
@@ -1026,24 +1162,108 @@ impl<'s> Display for Deconstr<'s> {
     }
 }
 
+/// An as-pattern, like `(@ xs (List x _))`. Binds the whole matchee to `var`, while
+/// also matching it against `patt`
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AsPattern<'s> {
+    pub var: Variable<'s>,
+    pub patt: Pattern<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Display for AsPattern<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(@ {} {})", self.var, self.patt)
+    }
+}
+
+/// An or-pattern, like `(or CmpLt CmpEq)`. Matches if any of the alternatives match.
+/// All alternatives bind the same variables
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct OrPattern<'s> {
+    pub alts: Vec<Pattern<'s>>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Display for OrPattern<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "(or {})",
+            self.alts
+                .iter()
+                .map(|p| p.to_string())
+                .intersperse(" ".to_string())
+                .collect::<String>(),
+        )
+    }
+}
+
+/// A pattern deconstructing a `cons` pair, like `(cons x y)`
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ConsPattern<'s> {
+    pub car: Pattern<'s>,
+    pub cdr: Pattern<'s>,
+    pub typ: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> Display for ConsPattern<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(cons {} {})", self.car, self.cdr)
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Pattern<'s> {
+    /// The wildcard pattern `_`. Matches anything without binding it
+    Wild(SrcPos<'s>),
     Nil(Nil<'s>),
     NumLit(NumLit<'s>),
     StrLit(StrLit<'s>),
     Variable(Variable<'s>),
     Deconstr(Box<Deconstr<'s>>),
+    As(Box<AsPattern<'s>>),
+    Or(Box<OrPattern<'s>>),
+    Cons(Box<ConsPattern<'s>>),
 }
 
 impl<'s> Pattern<'s> {
+    pub fn pos(&self) -> &SrcPos<'s> {
+        match *self {
+            Pattern::Wild(ref pos) => pos,
+            Pattern::Nil(ref n) => &n.pos,
+            Pattern::NumLit(ref n) => &n.pos,
+            Pattern::StrLit(ref s) => &s.pos,
+            Pattern::Variable(ref v) => &v.ident.pos,
+            Pattern::Deconstr(ref d) => &d.pos,
+            Pattern::As(ref a) => &a.pos,
+            Pattern::Or(ref o) => &o.pos,
+            Pattern::Cons(ref c) => &c.pos,
+        }
+    }
+
+    /// Returns the variables bound by the pattern
+    ///
+    /// As all alternatives of an or-pattern bind the same variables, only the variables of
+    /// the first alternative are included.
     pub fn variables(&self) -> BTreeSet<&Variable<'s>> {
         match *self {
             Pattern::Variable(ref v) => set_of(v),
             Pattern::Deconstr(ref d) => d.subpatts.iter().flat_map(|p| p.variables()).collect(),
+            Pattern::As(ref a) => once(&a.var).chain(a.patt.variables()).collect(),
+            Pattern::Or(ref o) => o.alts[0].variables(),
+            Pattern::Cons(ref c) => {
+                let mut vars = c.car.variables();
+                vars.extend(c.cdr.variables());
+                vars
+            }
             _ => BTreeSet::new(),
         }
     }
 
+    /// Returns the variables bound by the pattern, including those of all alternatives of
+    /// or-patterns
     pub fn variables_mut(&mut self) -> BTreeSet<&mut Variable<'s>> {
         match *self {
             Pattern::Variable(ref mut v) => set_of(v),
@@ -1051,29 +1271,44 @@ impl<'s> Pattern<'s> {
                 .iter_mut()
                 .flat_map(|p| p.variables_mut())
                 .collect(),
+            Pattern::As(ref mut a) => {
+                let AsPattern {
+                    ref mut var,
+                    ref mut patt,
+                    ..
+                } = **a;
+                once(var).chain(patt.variables_mut()).collect()
+            }
+            Pattern::Or(ref mut o) => o.alts.iter_mut().flat_map(|p| p.variables_mut()).collect(),
+            Pattern::Cons(ref mut c) => {
+                let ConsPattern {
+                    ref mut car,
+                    ref mut cdr,
+                    ..
+                } = **c;
+                car.variables_mut().into_iter().chain(cdr.variables_mut()).collect()
+            }
             _ => BTreeSet::new(),
         }
     }
 
     pub fn variable_names(&self) -> BTreeSet<&'s str> {
-        match *self {
-            Pattern::Variable(ref v) => set_of(v.ident.s),
-            Pattern::Deconstr(ref d) => {
-                d.subpatts.iter().flat_map(|p| p.variable_names()).collect()
-            }
-            _ => BTreeSet::new(),
-        }
+        self.variables().into_iter().map(|v| v.ident.s).collect()
     }
 }
 
 impl<'s> Display for Pattern<'s> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Pattern::Wild(_) => write!(f, "_"),
             Pattern::Nil(ref n) => n.fmt(f),
             Pattern::NumLit(ref n) => n.fmt(f),
             Pattern::StrLit(ref s) => s.fmt(f),
             Pattern::Variable(ref v) => v.fmt(f),
             Pattern::Deconstr(ref dec) => dec.fmt(f),
+            Pattern::As(ref a) => a.fmt(f),
+            Pattern::Or(ref o) => o.fmt(f),
+            Pattern::Cons(ref c) => c.fmt(f),
         }
     }
 }
@@ -1082,17 +1317,24 @@ impl<'s> Display for Pattern<'s> {
 pub struct Case<'s> {
     pub patt: Pattern<'s>,
     pub patt_typ: Type<'s>,
+    /// A guard, like `(when (> x 0))`. The case only matches if the guard also holds
+    pub guard: Option<Expr<'s>>,
     pub body: Expr<'s>,
     pub pos: SrcPos<'s>,
 }
 
 impl<'s> Case<'s> {
     fn to_string_indent(&self, n: usize) -> String {
+        let guard = match self.guard {
+            Some(ref g) => format!("\n{}(when {})", spaces(n + 1), g.to_string_indent(n + 7)),
+            None => String::new(),
+        };
         format!(
-            "[(: {} {})\n\
+            "[(: {} {}){}\n\
              {}{}]",
             self.patt,
             self.patt_typ,
+            guard,
             spaces(n + 1),
             self.body.to_string_indent(n + 1),
         )
@@ -1144,6 +1386,7 @@ pub enum Expr<'s> {
     Cast(Box<Cast<'s>>),
     New(Box<New<'s>>),
     Match(Box<Match<'s>>),
+    Hole(Hole<'s>),
 }
 
 impl<'s> Expr<'s> {
@@ -1165,6 +1408,7 @@ impl<'s> Expr<'s> {
             Expr::Cast(ref c) => &c.pos,
             Expr::New(ref n) => &n.pos,
             Expr::Match(ref m) => &m.pos,
+            Expr::Hole(ref h) => &h.pos,
         }
     }
 
@@ -1193,6 +1437,7 @@ impl<'s> Expr<'s> {
             Expr::Cast(ref c) => &c.typ,
             Expr::New(ref n) => &n.typ,
             Expr::Match(ref m) => &m.typ,
+            Expr::Hole(ref h) => &h.typ,
         }
     }
 
@@ -1241,6 +1486,7 @@ impl<'s> Expr<'s> {
             Expr::Cast(ref c) => c.to_string_indent(n),
             Expr::New(ref new) => new.to_string_indent(n),
             Expr::Match(ref m) => m.to_string_indent(n),
+            Expr::Hole(ref h) => h.to_string(),
         }
     }
 }
@@ -1286,6 +1532,18 @@ pub struct AdtDef<'s> {
     pub name: Ident<'s>,
     pub params: Vec<&'s str>,
     pub variants: Vec<AdtVariant<'s>>,
+    /// The classes to automatically derive instances of, as given in a `deriving` clause
+    pub derivings: Vec<Ident<'s>>,
+    /// Whether the type is declared with `newtype`
+    ///
+    /// A newtype has a single variant of a single member, and is represented exactly as
+    /// the member, without a tag.
+    pub is_newtype: bool,
+    /// The names of the fields, if the type is declared with `record`
+    ///
+    /// A record has a single variant with the same name as the type, of which the fields are
+    /// the members.
+    pub fields: Option<Vec<Ident<'s>>>,
     pub pos: SrcPos<'s>,
 }
 
@@ -1308,16 +1566,45 @@ impl<'s> AdtDef<'s> {
                     .collect::<String>()
             )
         };
+        let derivings = if self.derivings.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "\n{}(deriving {})",
+                spaces(n + 2),
+                self.derivings
+                    .iter()
+                    .map(|c| c.to_string())
+                    .intersperse(" ".to_string())
+                    .collect::<String>()
+            )
+        };
+        if let Some(ref fields) = self.fields {
+            let members = &self.variants[0].members;
+            return format!(
+                "(record {}\n\
+                 {}{}{})",
+                binding,
+                spaces(n + 2),
+                zip(fields, members)
+                    .map(|(f, t)| format!("({} {})", f, t))
+                    .intersperse(format!("\n{}", spaces(n + 2)))
+                    .collect::<String>(),
+                derivings
+            );
+        }
         format!(
-            "(data {}\n\
-             {}{})",
+            "({} {}\n\
+             {}{}{})",
+            if self.is_newtype { "newtype" } else { "data" },
             binding,
             spaces(n + 2),
             self.variants
                 .iter()
                 .map(|v| v.to_string())
                 .intersperse(format!("\n{}", spaces(n + 2)))
-                .collect::<String>()
+                .collect::<String>(),
+            derivings
         )
     }
 }
@@ -1344,6 +1631,15 @@ impl<'s> Adts<'s> {
         }
     }
 
+    /// Returns the definitions of the builtin data types, i.e. `IO`
+    pub fn with_builtins() -> Self {
+        let io = io_adt_def();
+        Adts {
+            variants: map_of(IO_CONSTR, io.name.s),
+            defs: map_of(io.name.s, io),
+        }
+    }
+
     /// History keeps track of the definitions we've entered, so we
     /// don't get stuck in loops for cases like
     ///
@@ -1371,6 +1667,7 @@ impl<'s> Adts<'s> {
         match *tf {
             TypeFunc::Const(s) => self.is_rec_const(s, origin, history),
             TypeFunc::Poly(ref p) => self.is_rec_type(&p.body, origin, history),
+            TypeFunc::Var(_) => false,
         }
     }
 
@@ -1421,6 +1718,37 @@ impl<'s> Adts<'s> {
         self.adt_is_recursive(adt)
     }
 
+    /// Returns the record that has a field of name `field`
+    pub fn record_of_field<'a>(&'a self, field: &str) -> Option<&'a AdtDef<'s>> {
+        self.defs.values().find(|adt| {
+            adt.fields
+                .as_ref()
+                .map(|fields| fields.iter().any(|f| f.s == field))
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn adt_of_variant_is_newtype(&self, v: &str) -> bool {
+        self.parent_adt_of_variant(v)
+            .expect("ICE: No parent adt of variant in adt_of_variant_is_newtype")
+            .is_newtype
+    }
+
+    /// If `t` is an instantiation of a newtype, returns the type of the wrapped member
+    pub fn newtype_inner_type(&self, t: &Type<'s>) -> Option<Type<'s>> {
+        let (name, inst) = match *t {
+            Type::Const(name, _) => (name, &[][..]),
+            Type::App(box TypeFunc::Const(name), ref inst) => (name, &inst[..]),
+            _ => return None,
+        };
+        let adt = match self.defs.get(name) {
+            Some(adt) if adt.is_newtype => adt,
+            _ => return None,
+        };
+        self.members_with_inst_of_variant(&adt.variants[0], inst)
+            .map(|mut members| members.remove(0))
+    }
+
     pub fn parent_adt_of_variant<'a>(&'a self, v: &str) -> Option<&'a AdtDef<'s>> {
         self.variants.get(v).and_then(|t| self.defs.get(t))
     }
@@ -1492,6 +1820,346 @@ impl<'s> Adts<'s> {
     }
 }
 
+/// The declaration of a method of a type class
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MethodDecl<'s> {
+    pub ident: Ident<'s>,
+    /// The type of the method
+    ///
+    /// Quantified over the class parameter, constrained by the class, as well as any other
+    /// type variables occuring in the declared type
+    pub sig: Poly<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+/// A type class definition
+///
+/// # Examples
+/// ```
+/// (class (constrain [(Eq a)] (Ord a))
+///   (: < (-> a a Bool))
+///   (: <= (-> a a Bool))
+///   (define (<= x y) (or (< x y) (== x y))))
+/// ```
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ClassDef<'s> {
+    pub name: Ident<'s>,
+    /// The type parameter of the class
+    pub param: &'s str,
+    /// Classes that any instance of this class must also be an instance of
+    pub superclasses: BTreeSet<&'s str>,
+    pub methods: BTreeMap<&'s str, MethodDecl<'s>>,
+    /// Default definitions of methods, used by instances that do not define the method
+    pub defaults: BTreeMap<&'s str, Binding<'s>>,
+    pub pos: SrcPos<'s>,
+}
+
+/// An instance of a type class for a type
+///
+/// # Examples
+/// ```
+/// (instance (constrain [(Eq a)] (Eq (List a)))
+///   (define (== xs ys) ...))
+/// ```
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct InstanceDef<'s> {
+    pub class: Ident<'s>,
+    /// The type variables of the instance head, and the classes they are constrained by
+    pub params: BTreeMap<TVar<'s>, BTreeSet<&'s str>>,
+    /// The type the class is instantiated for. Either a type constant, or a type constructor
+    /// applied to distinct type variables
+    pub head: Type<'s>,
+    /// The method definitions of the instance, with the types of the class methods
+    /// instantiated for the head type
+    pub methods: BTreeMap<&'s str, Binding<'s>>,
+    pub pos: SrcPos<'s>,
+}
+
+/// The name of the constructor of the builtin `IO` newtype
+///
+/// The name can't be written in source code, which keeps `IO` abstract. Actions are only
+/// built and taken apart by the `primitive-io` and `primitive-bind` forms.
+pub const IO_CONSTR: &str = "{IO}";
+
+/// The definition of the builtin newtype of IO actions
+///
+/// An action is represented as a function threading the world token through its effects,
+/// as if defined `(newtype (IO a) ({IO} (-> RealWorld (Cons a RealWorld))))`.
+pub fn io_adt_def<'s>() -> AdtDef<'s> {
+    let pos = SrcPos::new_pos(path::Path::new(""), "", 0);
+    let result = Type::Var(TVar::Explicit("a"));
+    let action = Type::new_func(
+        TYPE_REALWORLD.clone(),
+        Type::new_cons(result, TYPE_REALWORLD.clone()),
+    );
+    AdtDef {
+        name: Ident::new("IO", pos.clone()),
+        params: vec!["a"],
+        variants: vec![
+            AdtVariant {
+                name: Ident::new(IO_CONSTR, pos.clone()),
+                members: vec![action],
+                pos: pos.clone(),
+            },
+        ],
+        derivings: Vec::new(),
+        is_newtype: true,
+        fields: None,
+        pos,
+    }
+}
+
+/// The builtin constraint classes and their superclasses
+///
+/// The instances of these are the primitive types for which the corresponding core
+/// operations are generated in codegen, e.g. `div` for `Integral` and `Fractional`.
+pub const BUILTIN_CLASSES: &[(&str, &[&str])] = &[
+    ("Eq", &[]),
+    ("Ord", &["Eq"]),
+    ("Num", &["Eq"]),
+    ("Integral", &["Num", "Ord"]),
+    ("Fractional", &["Num", "Ord"]),
+    ("Bits", &["Eq"]),
+];
+
+pub fn builtin_superclasses(class: &str) -> Option<&'static [&'static str]> {
+    BUILTIN_CLASSES
+        .iter()
+        .find(|&&(c, _)| c == class)
+        .map(|&(_, supers)| supers)
+}
+
+pub fn is_builtin_class(class: &str) -> bool {
+    builtin_superclasses(class).is_some()
+}
+
+/// If `method` is the core operation of a builtin class that can be instantiated for user
+/// defined types, returns the class
+pub fn builtin_class_of_method(method: &str) -> Option<&'static str> {
+    match method {
+        "eq" => Some("Eq"),
+        "lt" => Some("Ord"),
+        _ => None,
+    }
+}
+
+/// Returns the definition of the builtin class `class`, if instances of it can be
+/// defined or derived for user defined types
+///
+/// The single method of such a class is the binary relational core operation of the
+/// class, which is declared in `std`.
+pub fn builtin_class_def<'s>(class: &str) -> Option<ClassDef<'s>> {
+    let (name, method, superclasses): (&'static str, &'static str, &'static [&'static str]) =
+        match class {
+            "Eq" => ("Eq", "eq", &[]),
+            "Ord" => ("Ord", "lt", &["Eq"]),
+            _ => return None,
+        };
+    let pos = SrcPos::new_pos(path::Path::new(""), "", 0);
+    let param = TVar::Explicit("t");
+    let decl = MethodDecl {
+        ident: Ident::new(method, pos.clone()),
+        sig: Poly {
+            params: map_of(param, set_of(name)),
+            body: Type::new_relational_binop(Type::Var(param)),
+        },
+        pos: pos.clone(),
+    };
+    Some(ClassDef {
+        name: Ident::new(name, pos.clone()),
+        param: "t",
+        superclasses: superclasses.iter().cloned().collect(),
+        methods: map_of(method, decl),
+        defaults: BTreeMap::new(),
+        pos,
+    })
+}
+
+/// Returns the name of the type constructor of an instance head type
+pub fn head_type_constructor<'s>(t: &Type<'s>) -> Option<&'s str> {
+    match *t {
+        Type::Const(c, _) => Some(c),
+        Type::App(box TypeFunc::Const(c), _) => Some(c),
+        _ => None,
+    }
+}
+
+/// Type class definitions and instances
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Classes<'s> {
+    pub defs: BTreeMap<&'s str, ClassDef<'s>>,
+    /// Auxiliary map for quicker access to the class of a method
+    pub methods: BTreeMap<&'s str, &'s str>,
+    /// Instances by class and type constructor of the instance head
+    pub instances: BTreeMap<(&'s str, &'s str), InstanceDef<'s>>,
+}
+
+impl<'s> Classes<'s> {
+    pub fn new() -> Self {
+        Classes {
+            defs: BTreeMap::new(),
+            methods: BTreeMap::new(),
+            instances: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_class(&self, name: &str) -> bool {
+        self.defs.contains_key(name)
+    }
+
+    pub fn class_of_method<'a>(&'a self, method: &str) -> Option<&'a ClassDef<'s>> {
+        self.methods.get(method).and_then(|c| self.defs.get(c))
+    }
+
+    pub fn method_decl<'a>(&'a self, method: &str) -> Option<&'a MethodDecl<'s>> {
+        self.class_of_method(method)
+            .and_then(|class| class.methods.get(method))
+    }
+
+    /// Returns the transitive superclasses of `class`, including `class` itself
+    pub fn superclasses_closure(&self, class: &'s str) -> BTreeSet<&'s str> {
+        let mut closure = BTreeSet::new();
+        let mut stack = vec![class];
+        while let Some(c) = stack.pop() {
+            if closure.insert(c) {
+                if let Some(def) = self.defs.get(c) {
+                    stack.extend(def.superclasses.iter().cloned());
+                } else if let Some(supers) = builtin_superclasses(c) {
+                    stack.extend(supers.iter().cloned());
+                }
+            }
+        }
+        closure
+    }
+
+    /// Returns whether the constraints `constrs` imply the constraint `class`,
+    /// directly or through superclasses
+    pub fn entails(&self, constrs: &BTreeSet<&'s str>, class: &str) -> bool {
+        constrs
+            .iter()
+            .any(|&c| c == class || self.superclasses_closure(c).contains(class))
+    }
+
+    /// Find the instance of `class` for the type `t`
+    ///
+    /// On success, returns the instance and the mapping of the type variables of the
+    /// instance head to the corresponding parts of `t`
+    pub fn instance_for<'a>(
+        &'a self,
+        class: &str,
+        t: &Type<'s>,
+    ) -> Option<(&'a InstanceDef<'s>, BTreeMap<TVar<'s>, Type<'s>>)> {
+        let con = head_type_constructor(t)?;
+        let inst = self.instances.get(&(class, con))?;
+        let s = match (&inst.head, t) {
+            (&Type::App(_, ref params), &Type::App(_, ref args)) if params.len() == args.len() => {
+                zip(params, args)
+                    .filter_map(|(p, a)| match *p {
+                        Type::Var(tv) => Some((tv, a.clone())),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            (&Type::Const(..), &Type::Const(..)) => BTreeMap::new(),
+            _ => return None,
+        };
+        Some((inst, s))
+    }
+}
+
+/// A type alias, like `(type (IO a) (-> RealWorld (Cons a RealWorld)))`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeAlias<'s> {
+    pub name: Ident<'s>,
+    /// The parameters of the alias, renamed to unique implicit type variables so that
+    /// they don't clash with the arguments when expanded
+    pub params: Vec<TVar<'s>>,
+    /// The type the alias expands to
+    pub body: Type<'s>,
+    pub pos: SrcPos<'s>,
+}
+
+impl<'s> TypeAlias<'s> {
+    /// If `t` is an expansion of this alias, returns the arguments it was expanded with
+    fn match_expansion(&self, t: &Type<'s>) -> Option<Vec<Type<'s>>> {
+        fn match_type<'s>(
+            pattern: &Type<'s>,
+            t: &Type<'s>,
+            params: &[TVar<'s>],
+            s: &mut BTreeMap<TVar<'s>, Type<'s>>,
+        ) -> bool {
+            match (pattern, t) {
+                (&Type::Var(tv), _) if params.contains(&tv) => {
+                    if let Some(u) = s.get(&tv) {
+                        return u == t;
+                    }
+                    s.insert(tv, t.clone());
+                    true
+                }
+                (&Type::App(ref f, ref ps), &Type::App(ref g, ref ts)) => {
+                    f == g && ps.len() == ts.len()
+                        && zip(ps, ts).all(|(p, u)| match_type(p, u, params, s))
+                }
+                _ => pattern == t,
+            }
+        }
+        let mut s = BTreeMap::new();
+        if match_type(&self.body, t, &self.params, &mut s) {
+            self.params.iter().map(|tv| s.remove(tv)).collect()
+        } else {
+            None
+        }
+    }
+}
+
+/// Type alias definitions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeAliases<'s> {
+    pub defs: BTreeMap<&'s str, TypeAlias<'s>>,
+}
+
+impl<'s> TypeAliases<'s> {
+    pub fn new() -> Self {
+        TypeAliases {
+            defs: BTreeMap::new(),
+        }
+    }
+
+    /// Replace the parts of `t` that are expansions of aliases with applications of the
+    /// aliases
+    ///
+    /// Aliases are expanded as they are parsed, so this is used to show types in error
+    /// messages in the terms the user wrote them. Only aliases of type applications are
+    /// considered, as an alias of a constant or a variable would match too eagerly.
+    pub fn resugar(&self, t: &Type<'s>) -> Type<'s> {
+        let sugared = self.defs
+            .values()
+            .filter(|alias| match alias.body {
+                Type::App(..) => true,
+                _ => false,
+            })
+            .filter_map(|alias| alias.match_expansion(t).map(|args| (alias.name.s, args)))
+            .next();
+        match sugared {
+            Some((name, ref args)) if args.is_empty() => Type::Const(name, None),
+            Some((name, args)) => Type::App(
+                Box::new(TypeFunc::Const(name)),
+                args.iter().map(|a| self.resugar(a)).collect(),
+            ),
+            None => match *t {
+                Type::App(ref f, ref ts) => {
+                    Type::App(f.clone(), ts.iter().map(|u| self.resugar(u)).collect())
+                }
+                Type::Poly(ref p) => Type::Poly(Box::new(Poly {
+                    params: p.params.clone(),
+                    body: self.resugar(&p.body),
+                })),
+                _ => t.clone(),
+            },
+        }
+    }
+}
+
 /// A module of definitions and declarations of functions and variables
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Ast<'s> {
@@ -1507,6 +2175,17 @@ pub struct Ast<'s> {
     pub globals: TopologicallyOrderedDependencyGroups<'s>,
     /// Algebraic Data Type definitions
     pub adts: Adts<'s>,
+    /// Type class definitions and instances
+    pub classes: Classes<'s>,
+    /// Type alias definitions. Already expanded in all types of the AST, but kept to show
+    /// the aliases in error messages
+    pub type_aliases: TypeAliases<'s>,
+    /// The type that ambiguous types are defaulted to during monomorphization. `Int64`, unless
+    /// declared otherwise with `(default T)`
+    pub default_type: Type<'s>,
+    /// The modules that allow implicit widening coercions between numeric types, as declared
+    /// with `(allow-coercion)`
+    pub coercion_modules: BTreeSet<&'s path::Path>,
 }
 
 impl<'s> Ast<'s> {
@@ -18,6 +18,9 @@ fn sibling_refs_match<'s>(m: &Match<'s>, siblings: &mut BTreeSet<&'s str>) -> BT
             .into_iter()
             .filter_map(|id| if siblings.remove(id) { Some(id) } else { None })
             .collect::<Vec<_>>();
+        if let Some(ref guard) = case.guard {
+            refs.extend(sibling_refs(guard, siblings));
+        }
         refs.extend(sibling_refs(&case.body, siblings));
         siblings.extend(shadoweds)
     }
@@ -78,7 +81,7 @@ fn sibling_refs<'src>(e: &Expr<'src>, siblings: &mut BTreeSet<&'src str>) -> BTr
             .flat_map(|e2| sibling_refs(e2, siblings))
             .collect(),
         Match(ref m) => sibling_refs_match(m, siblings),
-        Nil(_) | NumLit(_) | StrLit(_) | Bool(_) => BTreeSet::new(),
+        Nil(_) | NumLit(_) | StrLit(_) | Bool(_) | Hole(_) => BTreeSet::new(),
     }
 }
 
@@ -0,0 +1,421 @@
+//! Exhaustiveness and redundancy checking of pattern matches
+//!
+//! Based on the usefulness algorithm of Luc Maranget's
+//! [Warnings for pattern matching](http://moscova.inria.fr/~maranget/papers/warn/index.html).
+//! A pattern is *useful* with respect to a list of patterns if there is some value that it
+//! matches, but none of the patterns in the list do. An arm is unreachable if its pattern is
+//! not useful with respect to the patterns of the arms before it, and a match is exhaustive if
+//! the wildcard pattern is not useful with respect to all its patterns.
+
+use lib::front::ast::*;
+use std::fmt::{self, Display};
+use std::iter::{once, repeat};
+
+/// A constructor of values, as matched on by a pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Constr<'s> {
+    Nil,
+    Cons,
+    Variant(&'s str),
+    /// A numeric literal. There are too many numbers to match on them all
+    Num(&'s str),
+    /// A string literal. There are too many strings to match on them all
+    Str(String),
+}
+
+/// A simplified pattern, where all variable bindings are wildcards
+#[derive(Clone, Debug)]
+enum Pat<'s> {
+    Wild,
+    Constr(Constr<'s>, Vec<Pat<'s>>),
+    Or(Vec<Pat<'s>>),
+}
+
+impl<'s> Pat<'s> {
+    fn from_pattern(p: &Pattern<'s>) -> Self {
+        match *p {
+            Pattern::Nil(_) => Pat::Constr(Constr::Nil, vec![]),
+            Pattern::NumLit(ref n) => Pat::Constr(Constr::Num(n.lit), vec![]),
+            Pattern::StrLit(ref s) => Pat::Constr(Constr::Str(s.lit.to_string()), vec![]),
+            Pattern::Wild(_) | Pattern::Variable(_) => Pat::Wild,
+            Pattern::Deconstr(ref d) => Pat::Constr(
+                Constr::Variant(d.constr.s),
+                d.subpatts.iter().map(Pat::from_pattern).collect(),
+            ),
+            Pattern::As(ref a) => Pat::from_pattern(&a.patt),
+            Pattern::Or(ref o) => Pat::Or(o.alts.iter().map(Pat::from_pattern).collect()),
+            Pattern::Cons(ref c) => Pat::Constr(
+                Constr::Cons,
+                vec![Pat::from_pattern(&c.car), Pat::from_pattern(&c.cdr)],
+            ),
+        }
+    }
+
+    fn head_constr(&self) -> Option<&Constr<'s>> {
+        match *self {
+            Pat::Wild => None,
+            Pat::Constr(ref c, _) => Some(c),
+            Pat::Or(_) => panic!("ICE: Or-pattern not expanded in head_constr"),
+        }
+    }
+}
+
+impl<'s> Display for Pat<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Pat::Wild => write!(f, "_"),
+            Pat::Constr(Constr::Nil, _) => write!(f, "nil"),
+            Pat::Constr(Constr::Cons, ref args) => write!(f, "(cons {} {})", args[0], args[1]),
+            Pat::Constr(Constr::Num(n), _) => write!(f, "{}", n),
+            Pat::Constr(Constr::Str(ref s), _) => write!(f, "{:?}", s),
+            Pat::Constr(Constr::Variant(v), ref args) if args.is_empty() => write!(f, "{}", v),
+            Pat::Constr(Constr::Variant(v), ref args) => {
+                write!(f, "({}", v)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Pat::Or(ref alts) => {
+                write!(f, "(or")?;
+                for alt in alts {
+                    write!(f, " {}", alt)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A matrix of patterns. Each row is the patterns of an arm, and each column is matched
+/// against a part of the matchee
+type Matrix<'s> = Vec<Vec<Pat<'s>>>;
+
+struct Checker<'a, 's: 'a> {
+    adts: &'a Adts<'s>,
+}
+
+impl<'a, 's: 'a> Checker<'a, 's> {
+    fn arity(&self, c: &Constr<'s>) -> usize {
+        match *c {
+            Constr::Variant(v) => self.adts
+                .adt_variant_of_name(v)
+                .expect("ICE: No variant of name in arity")
+                .members
+                .len(),
+            Constr::Cons => 2,
+            Constr::Nil | Constr::Num(_) | Constr::Str(_) => 0,
+        }
+    }
+
+    /// Expand the rows of `m` with an or-pattern at the head into one row per alternative
+    fn expand_or_heads(&self, m: &Matrix<'s>) -> Matrix<'s> {
+        let mut expanded = Matrix::new();
+        for row in m {
+            match row[0] {
+                Pat::Or(ref alts) => for alt in alts {
+                    let alt_row = once(alt.clone()).chain(row[1..].to_vec()).collect();
+                    expanded.extend(self.expand_or_heads(&vec![alt_row]))
+                },
+                _ => expanded.push(row.clone()),
+            }
+        }
+        expanded
+    }
+
+    /// Returns the constructors occuring at the head of the first column of `m`
+    fn head_constrs(&self, m: &Matrix<'s>) -> Vec<Constr<'s>> {
+        let mut constrs = Vec::new();
+        for c in m.iter().filter_map(|row| row[0].head_constr()) {
+            if !constrs.contains(c) {
+                constrs.push(c.clone())
+            }
+        }
+        constrs
+    }
+
+    /// Returns all constructors of the type of `constrs`, if `constrs` is non-empty and the
+    /// type is finitely constructed
+    fn all_constrs(&self, constrs: &[Constr<'s>]) -> Option<Vec<Constr<'s>>> {
+        match constrs.first() {
+            Some(&Constr::Nil) => Some(vec![Constr::Nil]),
+            Some(&Constr::Cons) => Some(vec![Constr::Cons]),
+            Some(&Constr::Variant(v)) => {
+                let adt = self.adts
+                    .parent_adt_of_variant(v)
+                    .expect("ICE: No parent adt of variant in all_constrs");
+                Some(adt.variants
+                    .iter()
+                    .map(|v| Constr::Variant(v.name.s))
+                    .collect())
+            }
+            Some(&Constr::Num(_)) | Some(&Constr::Str(_)) | None => None,
+        }
+    }
+
+    /// Returns whether the constructors `constrs` cover all values of their type
+    fn is_complete(&self, constrs: &[Constr<'s>]) -> bool {
+        self.all_constrs(constrs)
+            .map(|all| all.iter().all(|c| constrs.contains(c)))
+            .unwrap_or(false)
+    }
+
+    /// Specialize the matrix `m` by the constructor `c`
+    ///
+    /// Keep only the rows that match values constructed by `c`, and replace the head of
+    /// each row with the subpatterns of the constructor.
+    fn specialize(&self, c: &Constr<'s>, m: &Matrix<'s>) -> Matrix<'s> {
+        let arity = self.arity(c);
+        m.iter()
+            .filter_map(|row| {
+                let (head, rest) = row.split_first().expect("ICE: Empty row in specialize");
+                match *head {
+                    Pat::Wild => Some(repeat(Pat::Wild).take(arity).chain(rest.to_vec()).collect()),
+                    Pat::Constr(ref c2, ref args) if c2 == c => {
+                        Some(args.iter().cloned().chain(rest.to_vec()).collect())
+                    }
+                    Pat::Constr(..) => None,
+                    Pat::Or(_) => panic!("ICE: Or-pattern not expanded in specialize"),
+                }
+            })
+            .collect()
+    }
+
+    /// The default matrix of `m`. The rows of `m` with a wildcard head, without the head
+    fn default_matrix(&self, m: &Matrix<'s>) -> Matrix<'s> {
+        m.iter()
+            .filter(|row| row[0].head_constr().is_none())
+            .map(|row| row[1..].to_vec())
+            .collect()
+    }
+
+    /// Returns whether the row `q` is useful with respect to the matrix `m`
+    fn is_useful(&self, m: &Matrix<'s>, q: &[Pat<'s>]) -> bool {
+        let (head, rest) = match q.split_first() {
+            Some(x) => x,
+            None => return m.is_empty(),
+        };
+        let m = &self.expand_or_heads(m);
+        match *head {
+            Pat::Or(ref alts) => alts.iter().any(|alt| {
+                let q2 = once(alt.clone()).chain(rest.to_vec()).collect::<Vec<_>>();
+                self.is_useful(m, &q2)
+            }),
+            Pat::Constr(ref c, ref args) => {
+                let q2 = args.iter().cloned().chain(rest.to_vec()).collect::<Vec<_>>();
+                self.is_useful(&self.specialize(c, m), &q2)
+            }
+            Pat::Wild => {
+                let constrs = self.head_constrs(m);
+                if self.is_complete(&constrs) {
+                    constrs.iter().any(|c| {
+                        let q2 = repeat(Pat::Wild)
+                            .take(self.arity(c))
+                            .chain(rest.to_vec())
+                            .collect::<Vec<_>>();
+                        self.is_useful(&self.specialize(c, m), &q2)
+                    })
+                } else {
+                    self.is_useful(&self.default_matrix(m), rest)
+                }
+            }
+        }
+    }
+
+    /// Returns a row of `n` patterns matching values that no row of `m` matches, if any
+    fn missing(&self, m: &Matrix<'s>, n: usize) -> Option<Vec<Pat<'s>>> {
+        if n == 0 {
+            return if m.is_empty() { Some(vec![]) } else { None };
+        }
+        let m = &self.expand_or_heads(m);
+        let constrs = self.head_constrs(m);
+        if self.is_complete(&constrs) {
+            constrs
+                .iter()
+                .filter_map(|c| {
+                    let arity = self.arity(c);
+                    self.missing(&self.specialize(c, m), arity + n - 1)
+                        .map(|mut ps| {
+                            let rest = ps.split_off(arity);
+                            once(Pat::Constr(c.clone(), ps)).chain(rest).collect()
+                        })
+                })
+                .next()
+        } else {
+            let rest = self.missing(&self.default_matrix(m), n - 1)?;
+            // Give an example of a constructor not matched, if there is a finite number of them
+            let head = self.all_constrs(&constrs)
+                .and_then(|all| all.into_iter().find(|c| !constrs.contains(c)))
+                .map(|c| {
+                    let arity = self.arity(&c);
+                    Pat::Constr(c, vec![Pat::Wild; arity])
+                })
+                .unwrap_or(Pat::Wild);
+            Some(once(head).chain(rest).collect())
+        }
+    }
+
+    fn check_match(&self, m: &Match<'s>) {
+        let mut matrix = Matrix::new();
+        for case in &m.cases {
+            let row = vec![Pat::from_pattern(&case.patt)];
+            if !self.is_useful(&matrix, &row) {
+                case.pos.print_warn("Unreachable match arm");
+            }
+            // A guarded arm may fail to match whatever its pattern, so it can't help cover
+            // any values
+            if case.guard.is_none() {
+                matrix.push(row);
+            }
+        }
+        if let Some(witness) = self.missing(&matrix, 1) {
+            m.pos.error_exit(format!(
+                "Non-exhaustive patterns in match. Pattern `{}` not covered",
+                witness[0]
+            ))
+        }
+    }
+
+    fn check_expr(&self, e: &Expr<'s>) {
+        match *e {
+            Expr::App(ref app) => {
+                self.check_expr(&app.func);
+                self.check_expr(&app.arg);
+            }
+            Expr::If(ref cond) => {
+                self.check_expr(&cond.predicate);
+                self.check_expr(&cond.consequent);
+                self.check_expr(&cond.alternative);
+            }
+            Expr::Lambda(ref lam) => self.check_expr(&lam.body),
+            Expr::Let(ref l) => {
+                for binding in l.bindings.bindings() {
+                    self.check_expr(&binding.val)
+                }
+                self.check_expr(&l.body)
+            }
+            Expr::TypeAscript(ref a) => self.check_expr(&a.expr),
+            Expr::Cons(ref c) => {
+                self.check_expr(&c.car);
+                self.check_expr(&c.cdr);
+            }
+            Expr::Car(ref c) => self.check_expr(&c.expr),
+            Expr::Cdr(ref c) => self.check_expr(&c.expr),
+            Expr::Cast(ref c) => self.check_expr(&c.expr),
+            Expr::New(ref n) => for member in &n.members {
+                self.check_expr(member)
+            },
+            Expr::Match(ref m) => {
+                self.check_expr(&m.expr);
+                for case in &m.cases {
+                    if let Some(ref guard) = case.guard {
+                        self.check_expr(guard)
+                    }
+                    self.check_expr(&case.body)
+                }
+                self.check_match(m)
+            }
+            Expr::Variable(_) => (),
+            Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) | Expr::Hole(_) => (),
+        }
+    }
+}
+
+/// Check that all matches in the program are exhaustive, and warn about unreachable arms
+///
+/// Must be done after type checking, as the subpatterns of deconstructions are expected to
+/// agree with the number of members of the variants.
+pub fn check_matches<'s>(ast: &Ast<'s>) {
+    let checker = Checker { adts: &ast.adts };
+    for binding in ast.globals.bindings() {
+        checker.check_expr(&binding.val)
+    }
+    for class in ast.classes.defs.values() {
+        for binding in class.defaults.values() {
+            checker.check_expr(&binding.val)
+        }
+    }
+    for inst in ast.classes.instances.values() {
+        for binding in inst.methods.values() {
+            checker.check_expr(&binding.val)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lib::collections::AddMap;
+    use lib::front::TypeVarGen;
+    use lib::front::ast::*;
+    use lib::front::parse::parse_test_program;
+    use super::{Checker, Matrix, Pat};
+
+    const OPT: &str = "(data Opt None (Some Int64))";
+
+    /// Returns the pattern matrix of the match in the body of the function `name`
+    fn match_matrix<'s>(ast: &Ast<'s>, name: &str) -> Matrix<'s> {
+        let binding = ast.globals
+            .bindings()
+            .find(|b| b.ident.s == name)
+            .expect("binding not found");
+        let mut e = &binding.val;
+        while let Expr::Lambda(ref lam) = *e {
+            e = &lam.body;
+        }
+        match *e {
+            Expr::Match(ref m) => m.cases
+                .iter()
+                .map(|case| vec![Pat::from_pattern(&case.patt)])
+                .collect(),
+            _ => panic!("body of `{}` is not a match", name),
+        }
+    }
+
+    #[test]
+    fn test_is_useful() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let src = format!(
+            "{}
+             (define (f o) (match o [None 0] [(Some x) x] [y 1]))
+             (define (g o) (match o [(Some 1) 0] [(Some x) x]))",
+            OPT
+        );
+        let ast = parse_test_program(&src, &sources, &mut tvg);
+        let checker = Checker { adts: &ast.adts };
+        let f = match_matrix(&ast, "f");
+        assert!(checker.is_useful(&vec![], &f[0]));
+        assert!(checker.is_useful(&f[..1].to_vec(), &f[1]));
+        assert!(!checker.is_useful(&f[..2].to_vec(), &f[2]));
+        let g = match_matrix(&ast, "g");
+        assert!(checker.is_useful(&g[..1].to_vec(), &g[1]));
+        assert!(!checker.is_useful(&g[1..].to_vec(), &g[0]));
+    }
+
+    #[test]
+    fn test_missing() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let src = format!(
+            "{}
+             (define (f o) (match o [None 0]))
+             (define (g o) (match o [None 0] [(Some 1) 1]))
+             (define (h o) (match o [(Some x) x] [None 0]))",
+            OPT
+        );
+        let ast = parse_test_program(&src, &sources, &mut tvg);
+        let checker = Checker { adts: &ast.adts };
+        let missing_s = |name: &str| {
+            checker
+                .missing(&match_matrix(&ast, name), 1)
+                .map(|ps| ps.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            checker.missing(&vec![], 1).map(|ps| ps[0].to_string()),
+            Some("_".to_string())
+        );
+        assert_eq!(missing_s("f"), Some(vec!["(Some _)".to_string()]));
+        assert_eq!(missing_s("g"), Some(vec!["(Some _)".to_string()]));
+        assert_eq!(missing_s("h"), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! The graph of module imports of a program
+//!
+//! Built by the parser while following `import`s, and used to report unused imports and
+//! to emit the module dependency structure of a program in DOT format.
+
+use super::SrcPos;
+use lib::CanonPathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+/// An `import` of a module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Import<'s> {
+    /// The name of the module as written in the `import`
+    pub name: &'s str,
+    /// The canonical path of the imported module
+    pub path: CanonPathBuf,
+    pub pos: SrcPos<'s>,
+}
+
+/// A node in the import graph
+#[derive(Clone, Debug)]
+pub struct Module<'s> {
+    /// The modules directly imported by this module, in order of import
+    pub imports: Vec<Import<'s>>,
+    /// The names of the top level items defined in this module
+    pub defs: BTreeSet<&'s str>,
+    /// All identifiers referred to by the top level items of this module
+    pub refs: BTreeSet<&'s str>,
+}
+
+impl<'s> Module<'s> {
+    pub fn new() -> Self {
+        Module {
+            imports: Vec::new(),
+            defs: BTreeSet::new(),
+            refs: BTreeSet::new(),
+        }
+    }
+}
+
+/// The directed, acyclic graph of modules and their imports
+#[derive(Clone, Debug)]
+pub struct ImportGraph<'s> {
+    pub modules: BTreeMap<CanonPathBuf, Module<'s>>,
+}
+
+/// Returns the name of the module at `path`, i.e. the file stem
+fn module_name(path: &CanonPathBuf) -> String {
+    path.path()
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.path().display().to_string())
+}
+
+impl<'s> ImportGraph<'s> {
+    pub fn new() -> Self {
+        ImportGraph {
+            modules: BTreeMap::new(),
+        }
+    }
+
+    pub fn contains(&self, path: &CanonPathBuf) -> bool {
+        self.modules.contains_key(path)
+    }
+
+    pub fn add_module(&mut self, path: CanonPathBuf) {
+        self.modules.entry(path).or_insert_with(Module::new);
+    }
+
+    pub fn get_mut(&mut self, path: &CanonPathBuf) -> &mut Module<'s> {
+        self.modules
+            .get_mut(path)
+            .expect("ICE: Module not in import graph")
+    }
+
+    /// Returns the names defined in the module at `path` and in all modules it transitively
+    /// imports
+    ///
+    /// As all modules share a single namespace, these are the names that are brought into
+    /// scope by importing the module.
+    fn defs_reachable_from(&self, path: &CanonPathBuf) -> BTreeSet<&'s str> {
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![path];
+        let mut defs = BTreeSet::new();
+        while let Some(p) = stack.pop() {
+            if visited.insert(p) {
+                if let Some(module) = self.modules.get(p) {
+                    defs.extend(module.defs.iter().cloned());
+                    stack.extend(module.imports.iter().map(|import| &import.path));
+                }
+            }
+        }
+        defs
+    }
+
+    /// Returns all imports from which no definitions are referred to by the importing module
+    pub fn unused_imports(&self) -> Vec<&Import<'s>> {
+        let mut unused = Vec::new();
+        for module in self.modules.values() {
+            for import in &module.imports {
+                let reachable = self.defs_reachable_from(&import.path);
+                if module.refs.is_disjoint(&reachable) {
+                    unused.push(import)
+                }
+            }
+        }
+        unused
+    }
+
+    pub fn warn_unused_imports(&self) {
+        for import in self.unused_imports() {
+            import.pos.print_warn(format!("Unused import of module `{}`", import.name));
+        }
+    }
+
+    /// Write the graph in the DOT graph description language
+    pub fn write_dot<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let node_id = |path: &CanonPathBuf| format!("{:?}", path.path().display().to_string());
+        writeln!(w, "digraph imports {{")?;
+        for (path, module) in &self.modules {
+            writeln!(
+                w,
+                "    {} [label={:?}];",
+                node_id(path),
+                module_name(path)
+            )?;
+            for import in &module.imports {
+                writeln!(w, "    {} -> {};", node_id(path), node_id(&import.path))?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+}
@@ -17,10 +17,16 @@ use self::InferenceErr::*;
 use lib::set_of;
 use lib::front::*;
 use lib::front::ast::*;
+use lib::front::exhaustiveness::check_matches;
+use lib::front::kinds::check_kinds;
+use lib::front::linearity::check_linearity;
 use lib::front::monomorphization::*;
 use lib::front::substitution::*;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{self, Display};
+use std::iter::once;
+use std::mem;
+use std::path::Path;
 use itertools::{zip, Itertools};
 
 lazy_static! {
@@ -28,14 +34,22 @@ lazy_static! {
 }
 
 enum InferenceErr<'s> {
-    /// Type mismatch. (expected, found)
-    TypeMis(Type<'s>, Type<'s>),
+    /// Type mismatch. (expected, found, provenance)
+    TypeMis(Type<'s>, Type<'s>, Vec<String>),
     /// Type mismatch with specified mismatching nodes
     TypeMisSub {
         expected: Type<'s>,
         found: Type<'s>,
         sub_expected: Type<'s>,
         sub_found: Type<'s>,
+        /// Explanations of why the types were inferred as they were
+        provenance: Vec<String>,
+    },
+    /// A type that lacks an instance of a class it is constrained by
+    NoInstance {
+        class: &'s str,
+        typ: Type<'s>,
+        provenance: Vec<String>,
     },
     ArmsDiffer(Type<'s>, Type<'s>),
     ConstrWrongNumArgs {
@@ -47,22 +61,37 @@ enum InferenceErr<'s> {
 impl<'s> Display for InferenceErr<'s> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            TypeMis(ref expected, ref found) => write!(
-                f,
-                "Type mismatch. Expected `{}`, found `{}`",
-                expected, found
-            ),
+            TypeMis(ref expected, ref found, ref provenance) => {
+                write!(
+                    f,
+                    "Type mismatch. Expected `{}`, found `{}`",
+                    expected, found
+                )?;
+                write_provenance(f, provenance)
+            }
             TypeMisSub {
                 ref expected,
                 ref found,
                 ref sub_expected,
                 ref sub_found,
-            } => write!(
-                f,
-                "Type mismatch: Expected `{}`, found `{}`\n\
-                 Cannot infer or coerce `{}` to `{}`",
-                expected, found, sub_found, sub_expected
-            ),
+                ref provenance,
+            } => {
+                write!(
+                    f,
+                    "Type mismatch: Expected `{}`, found `{}`\n\
+                     Cannot infer or coerce `{}` to `{}`",
+                    expected, found, sub_found, sub_expected
+                )?;
+                write_provenance(f, provenance)
+            }
+            NoInstance {
+                class,
+                ref typ,
+                ref provenance,
+            } => {
+                write!(f, "No instance of class `{}` for type `{}`", class, typ)?;
+                write_provenance(f, provenance)
+            }
             ArmsDiffer(ref c, ref a) => write!(
                 f,
                 "Consequent and alternative have different types. Expected `{}` from \
@@ -78,26 +107,66 @@ impl<'s> Display for InferenceErr<'s> {
     }
 }
 
-fn type_mis<'s>(
-    type_var_map: &mut BTreeMap<TVar<'s>, Type<'s>>,
-    expected: &Type<'s>,
-    found: &Type<'s>,
-) -> InferenceErr<'s> {
-    TypeMis(subst(expected, type_var_map), subst(found, type_var_map))
+/// Returns the numeric types that values of the numeric type `t` can be converted to
+/// without loss of precision
+///
+/// The pointer sized integers are excluded, as their size depends on the target.
+fn widenings(t: &str) -> &'static [&'static str] {
+    match t {
+        "Int8" => &["Int16", "Int32", "Int64", "Float32", "Float64"],
+        "Int16" => &["Int32", "Int64", "Float32", "Float64"],
+        "Int32" => &["Int64", "Float64"],
+        "UInt8" => &["Int16", "Int32", "Int64", "UInt16", "UInt32", "UInt64", "Float32", "Float64"],
+        "UInt16" => &["Int32", "Int64", "UInt32", "UInt64", "Float32", "Float64"],
+        "UInt32" => &["Int64", "UInt64", "Float64"],
+        "Float32" => &["Float64"],
+        _ => &[],
+    }
+}
+
+fn write_provenance(f: &mut fmt::Formatter, provenance: &[String]) -> fmt::Result {
+    for line in provenance {
+        write!(f, "\n{}", line)?;
+    }
+    Ok(())
 }
 
-fn type_mis_sub<'s>(
-    type_var_map: &mut BTreeMap<TVar<'s>, Type<'s>>,
-    expected: &Type<'s>,
-    found: &Type<'s>,
-    sub_expected: &Type<'s>,
-    sub_found: &Type<'s>,
-) -> InferenceErr<'s> {
-    TypeMisSub {
-        expected: subst(expected, type_var_map),
-        found: subst(found, type_var_map),
-        sub_expected: subst(sub_expected, type_var_map),
-        sub_found: subst(sub_found, type_var_map),
+/// The expression that caused a type variable to be substituted
+#[derive(Clone, Debug)]
+struct Provenance<'s> {
+    pos: SrcPos<'s>,
+    /// What the expression is, e.g. "the call" or "the variable `x`"
+    reason: String,
+}
+
+impl<'s> Display for Provenance<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (_, _, row, col) = self.pos.line_len_row_col();
+        write!(f, "{} at {}:{}", self.reason, row, col)
+    }
+}
+
+/// Returns a short description of `e`, for explaining why a type was inferred
+fn describe_expr(e: &Expr) -> String {
+    match *e {
+        Expr::Nil(_) => "`nil`".to_string(),
+        Expr::NumLit(ref l) if l.lit.contains('.') => "the floating point literal".to_string(),
+        Expr::NumLit(_) => "the numeric literal".to_string(),
+        Expr::StrLit(_) => "the string literal".to_string(),
+        Expr::Bool(_) => "the boolean literal".to_string(),
+        Expr::Variable(ref v) => format!("the variable `{}`", v.ident.s),
+        Expr::App(_) => "the call".to_string(),
+        Expr::If(_) => "the conditional".to_string(),
+        Expr::Lambda(_) => "the lambda".to_string(),
+        Expr::Let(_) => "the let-expression".to_string(),
+        Expr::TypeAscript(_) => "the type ascription".to_string(),
+        Expr::Cons(_) => "the cons-expression".to_string(),
+        Expr::Car(_) => "the use of `car`".to_string(),
+        Expr::Cdr(_) => "the use of `cdr`".to_string(),
+        Expr::Cast(_) => "the cast".to_string(),
+        Expr::New(_) => "the construction".to_string(),
+        Expr::Match(_) => "the match".to_string(),
+        Expr::Hole(_) => "the hole".to_string(),
     }
 }
 
@@ -108,6 +177,9 @@ fn occurs_in(t: &TVar, u: &Type, s: &BTreeMap<TVar, Type>) -> bool {
     match *u {
         Type::Var(ref tv) if t == tv => true,
         Type::Var(ref tv) => s.get(&tv).map(|u2| occurs_in(t, u2, s)).unwrap_or(false),
+        Type::App(box TypeFunc::Var(ref f), ref us) => {
+            occurs_in(t, &Type::Var(*f), s) || us.iter().any(|u2| occurs_in(t, u2, s))
+        }
         Type::App(_, ref us) => us.iter().any(|u2| occurs_in(t, u2, s)),
         // TODO: Verify that this is correct
         //        Type::Scheme(ref is, ref u2) => !is.contains(&t) && occurs_in(t, u2, s),
@@ -131,6 +203,9 @@ fn wrap_vars_types_in_apps_match<'s>(
             .into_iter()
             .filter_map(|id| vars.remove(id).map(|p| (id, p)))
             .collect::<Vec<_>>();
+        if let Some(ref mut guard) = case.guard {
+            wrap_vars_types_in_apps_(guard, vars, app_args);
+        }
         wrap_vars_types_in_apps_(&mut case.body, vars, app_args);
         vars.extend(shadoweds)
     }
@@ -190,7 +265,7 @@ fn wrap_vars_types_in_apps_<'s>(
             wrap_vars_types_in_apps_(member, vars, app_args)
         },
         Expr::Match(ref mut m) => wrap_vars_types_in_apps_match(m, vars, app_args),
-        Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) => (),
+        Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) | Expr::Hole(_) => (),
     }
 }
 
@@ -222,6 +297,15 @@ enum TypeDef {
     // TODO: Type alias
 }
 
+/// A typed hole encountered during inference
+struct TypedHole<'s> {
+    name: Option<&'s str>,
+    typ: Type<'s>,
+    pos: SrcPos<'s>,
+    /// The variables in scope at the hole, and their types
+    scope: Vec<(&'s str, Type<'s>)>,
+}
+
 struct Inferrer<'a, 's: 'a> {
     /// The environment of variables from let-bindings and function-parameters
     var_env: BTreeMap<&'s str, Vec<Type<'s>>>,
@@ -234,16 +318,39 @@ struct Inferrer<'a, 's: 'a> {
     type_var_gen: &'a mut TypeVarGen,
     /// Defined algebraic data types
     adts: &'a Adts<'s>,
+    /// Defined type classes and instances
+    classes: &'a Classes<'s>,
+    /// Defined type aliases. Already expanded, but used to show types in errors
+    type_aliases: &'a TypeAliases<'s>,
     /// A map of core types and used defined types
     ///
-    /// Numeric types, cons, (TODO) type aliases, data type definitions
+    /// Numeric types, cons, data type definitions
     type_defs: BTreeMap<&'s str, TypeDef>,
+    /// Typed holes encountered so far, to be reported when inference is done
+    holes: Vec<TypedHole<'s>>,
+    /// For each substitution in `type_var_map`, the expression that introduced it
+    provenances: BTreeMap<TVar<'s>, Provenance<'s>>,
+    /// The expression currently being inferred, to which new substitutions are attributed
+    cause: Option<Provenance<'s>>,
+    /// Type variables of freshly constructed values, not yet decided to be unique or not,
+    /// mapped to the type of the value when not unique
+    fresh: BTreeMap<TVar<'s>, Type<'s>>,
+    /// The modules in which arguments may be implicitly coerced to wider numeric types
+    coercion_modules: &'a BTreeSet<&'s Path>,
+    /// The type variables of integer literals, which are defaulted if they stay ambiguous
+    literals: BTreeMap<TVar<'s>, SrcPos<'s>>,
+    /// The type that ambiguous types are defaulted to
+    default_type: &'a Type<'s>,
 }
 
 impl<'a, 's: 'a> Inferrer<'a, 's> {
     fn new(
         externs: &'a BTreeMap<&'s str, ExternDecl<'s>>,
         adts: &'a Adts<'s>,
+        classes: &'a Classes<'s>,
+        type_aliases: &'a TypeAliases<'s>,
+        coercion_modules: &'a BTreeSet<&'s Path>,
+        default_type: &'a Type<'s>,
         type_var_gen: &'a mut TypeVarGen,
     ) -> Self {
         use self::TypeDef::*;
@@ -272,10 +379,165 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
             type_var_map: BTreeMap::new(),
             type_var_gen,
             adts,
+            classes,
+            type_aliases,
             type_defs,
+            holes: Vec::new(),
+            provenances: BTreeMap::new(),
+            cause: None,
+            fresh: BTreeMap::new(),
+            coercion_modules,
+            literals: BTreeMap::new(),
+            default_type,
+        }
+    }
+
+    /// Set the cause of substitutions made from here on, returning the previous cause
+    fn set_cause(&mut self, pos: &SrcPos<'s>, reason: String) -> Option<Provenance<'s>> {
+        let cause = Provenance {
+            pos: pos.clone(),
+            reason,
+        };
+        mem::replace(&mut self.cause, Some(cause))
+    }
+
+    /// Substitute `t` for the type variable `tv`, attributing the substitution to the current
+    /// cause
+    fn bind_type_var(&mut self, tv: TVar<'s>, t: Type<'s>) {
+        self.type_var_map.insert(tv, t);
+        if let Some(ref cause) = self.cause {
+            self.provenances.insert(tv, cause.clone());
+        }
+    }
+
+    /// Explain why the type variables in `ts` were inferred as the types they were
+    ///
+    /// Each variable is followed through its substitutions, back to the expression that forced
+    /// it to a concrete type. E.g. "`Int64` was inferred because of the call at 12:5, via the
+    /// variable `x` at 14:2".
+    ///
+    /// A variable that is not forced to a concrete type, but is the type of an integer literal,
+    /// is explained as being defaulted.
+    fn explain_inferred(&mut self, ts: &[&Type<'s>]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for tv in ts.iter().flat_map(|t| t.type_vars()) {
+            let mut via = Vec::new();
+            let mut current = tv;
+            let mut explanation = None;
+            let mut literal = self.literals.get(&tv).cloned();
+            while let Some(u) = self.type_var_map.get(&current).cloned() {
+                let cause = match self.provenances.get(&current) {
+                    Some(cause) => cause.clone(),
+                    None => break,
+                };
+                match u {
+                    Type::Var(next) => {
+                        via.push(cause);
+                        current = next;
+                        literal = literal.or_else(|| self.literals.get(&current).cloned());
+                    }
+                    _ => {
+                        explanation = Some((u, cause));
+                        break;
+                    }
+                }
+            }
+            if let Some((u, cause)) = explanation {
+                let u = self.type_aliases.resugar(&subst(&u, &mut self.type_var_map));
+                let mut line = format!("`{}` was inferred because of {}", u, cause);
+                if !via.is_empty() {
+                    line += &format!(", via {}", via.iter().join(", via "));
+                }
+                if !lines.contains(&line) {
+                    lines.push(line);
+                }
+            } else if let Some(pos) = literal {
+                let lit = Provenance {
+                    pos,
+                    reason: "the numeric literal".to_string(),
+                };
+                let line = format!(
+                    "`{}` is the type of {}, and is defaulted to `{}` if it stays ambiguous",
+                    Type::Var(current),
+                    lit,
+                    self.default_type
+                );
+                if !lines.contains(&line) {
+                    lines.push(line);
+                }
+            }
+        }
+        lines
+    }
+
+    fn type_mis(&mut self, expected: &Type<'s>, found: &Type<'s>) -> InferenceErr<'s> {
+        let provenance = self.explain_inferred(&[expected, found]);
+        if let Some((class, typ)) = self.unfulfilled_constraint(expected, found) {
+            return NoInstance {
+                class,
+                typ: self.type_aliases.resugar(&typ),
+                provenance,
+            };
+        }
+        TypeMis(
+            self.type_aliases
+                .resugar(&subst(expected, &mut self.type_var_map)),
+            self.type_aliases
+                .resugar(&subst(found, &mut self.type_var_map)),
+            provenance,
+        )
+    }
+
+    fn type_mis_sub(
+        &mut self,
+        expected: &Type<'s>,
+        found: &Type<'s>,
+        sub_expected: &Type<'s>,
+        sub_found: &Type<'s>,
+    ) -> InferenceErr<'s> {
+        let provenance = self.explain_inferred(&[expected, found]);
+        let aliases = self.type_aliases;
+        if let Some((class, typ)) = self.unfulfilled_constraint(sub_expected, sub_found) {
+            return NoInstance {
+                class,
+                typ: aliases.resugar(&typ),
+                provenance,
+            };
+        }
+        TypeMisSub {
+            expected: aliases.resugar(&subst(expected, &mut self.type_var_map)),
+            found: aliases.resugar(&subst(found, &mut self.type_var_map)),
+            sub_expected: aliases.resugar(&subst(sub_expected, &mut self.type_var_map)),
+            sub_found: aliases.resugar(&subst(sub_found, &mut self.type_var_map)),
+            provenance,
         }
     }
 
+    /// If one of `t` and `u` is a type variable with class constraints that the other, a
+    /// concrete type, does not fulfill, returns the first unfulfilled class and the concrete type
+    ///
+    /// A failure to unify such types is better explained as a missing instance than as a type
+    /// mismatch.
+    fn unfulfilled_constraint(
+        &mut self,
+        t: &Type<'s>,
+        u: &Type<'s>,
+    ) -> Option<(&'s str, Type<'s>)> {
+        let t = subst(t, &mut self.type_var_map);
+        let u = subst(u, &mut self.type_var_map);
+        let (tv, other) = match (&t, &u) {
+            (&Type::Var(_), &Type::Var(_)) => return None,
+            (&Type::Var(tv @ TVar::Implicit(_)), other)
+            | (other, &Type::Var(tv @ TVar::Implicit(_))) => (tv, other.clone()),
+            _ => return None,
+        };
+        let constrs = self.get_type_var_constraints(&tv).clone();
+        constrs
+            .into_iter()
+            .find(|&c| !self.fulfills_constraint(&other, c))
+            .map(|c| (c, other))
+    }
+
     // pub fn get_type(&self) -> Type<'s> {
     //     Type::new_tuple(&self.members)
     // }
@@ -377,6 +639,10 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     fn free_type_vars(&self, t: &Type<'s>) -> BTreeSet<TVar<'s>> {
         match *t {
             Type::Var(ref tv) => self.free_type_vars_var(tv),
+            Type::App(box TypeFunc::Var(ref f), ref ts) => once(self.free_type_vars_var(f))
+                .chain(ts.iter().map(|t2| self.free_type_vars(t2)))
+                .flat_map(|tvs| tvs)
+                .collect(),
             Type::App(_, ref ts) => ts.iter()
                 .flat_map(move |t2| self.free_type_vars(t2))
                 .collect(),
@@ -439,6 +705,88 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         }
     }
 
+    /// Fill the leading type parameters of `var.typ`, the instantiation of the polytype `typ`,
+    /// with the explicit type arguments of `var`
+    ///
+    /// The arguments are given for the type variables of the signature, in the order of the
+    /// quantifier list of the polytype.
+    fn apply_type_args(&mut self, var: &Variable<'s>, typ: &Type<'s>) {
+        let p = match *typ {
+            Type::Poly(ref p) => p,
+            _ => var.ident.pos.error_exit(format!(
+                "`{}` of type `{}` is not polymorphic, and can not be applied to types",
+                var.ident.s,
+                self.type_aliases.resugar(typ)
+            )),
+        };
+        let params = p.explicit_params();
+        if params.is_empty() {
+            var.ident.pos.error(format!(
+                "`{}` has no explicit type signature, and can not be applied to types",
+                var.ident.s
+            ));
+            var.ident.pos.print_help(format!(
+                "Declare the type parameters of `{}` in a signature, like `(: {} (-> a a))`",
+                var.ident.s, var.ident.s
+            ));
+            exit()
+        }
+        if var.type_args.len() > params.len() {
+            var.ident.pos.error_exit(format!(
+                "Too many type arguments for `{}`. Expected at most {}, found {}",
+                var.ident.s,
+                params.len(),
+                var.type_args.len()
+            ))
+        }
+        let fresh_tvs = match var.typ {
+            Type::App(_, ref ts) => zip(p.params.keys(), ts).collect::<BTreeMap<_, _>>(),
+            _ => panic!("ICE: instantiation of polytype is not an application"),
+        };
+        for (param, arg) in zip(params, &var.type_args) {
+            self.unify(fresh_tvs[&param], arg).unwrap_or_else(|_| {
+                var.ident.pos.error_exit(format!(
+                    "Type argument `{}` does not fulfill the constraints {{ {} }} of `{}` in `{}`",
+                    self.type_aliases.resugar(arg),
+                    p.params[&param].iter().join(", "),
+                    param,
+                    var.ident.s
+                ))
+            });
+        }
+    }
+
+    /// Returns whether the type `t` fulfills all of the class constraints `constrs`
+    ///
+    /// Constraints on an implicit type variable are simply added to the variable. Otherwise,
+    /// an instance of the class must exist for the type constructor of `t`, and the
+    /// constraints of the instance context are in turn imposed on the corresponding type
+    /// arguments of `t`. Builtin classes are also fulfilled by the primitive types of the class.
+    fn fulfills_constraints(&mut self, t: &Type<'s>, constrs: &BTreeSet<&'s str>) -> bool {
+        let t = subst(t, &mut self.type_var_map);
+        constrs.iter().all(|c| self.fulfills_constraint(&t, c))
+    }
+
+    fn fulfills_constraint(&mut self, t: &Type<'s>, class: &'s str) -> bool {
+        let classes = self.classes;
+        match *t {
+            Type::Var(tv @ TVar::Implicit(_)) => {
+                self.type_var_env
+                    .entry(tv)
+                    .or_insert(BTreeSet::new())
+                    .insert(class);
+                true
+            }
+            Type::Var(ref tv) => classes.entails(self.get_type_var_constraints(tv), class),
+            _ => match classes.instance_for(class, t) {
+                Some((inst, s)) => inst.params
+                    .iter()
+                    .all(|(tv, constrs)| self.fulfills_constraints(&s[tv], constrs)),
+                None => is_builtin_class(class) && t.fulfills_constraints(&set_of(class)),
+            },
+        }
+    }
+
     fn unify_vars(&mut self, t: &TVar<'s>, u: &TVar<'s>) -> Result<TVar<'s>, (Type<'s>, Type<'s>)> {
         use self::TVar::*;
         match (*t, *u) {
@@ -451,11 +799,13 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 let is_subset = {
                     let t_constrs = self.get_type_var_constraints(t);
                     let u_constrs = self.get_type_var_constraints(u);
-                    t_constrs.is_subset(&u_constrs)
+                    t_constrs
+                        .iter()
+                        .all(|c| self.classes.entails(u_constrs, c))
                 };
                 let v = u.clone();
                 if is_subset {
-                    self.type_var_map.insert(*t, Type::Var(v));
+                    self.bind_type_var(*t, Type::Var(v));
                     Ok(v)
                 } else {
                     Err((Type::Var(t.clone()), Type::Var(v)))
@@ -470,15 +820,91 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 };
                 let joined_constrs_tv = self.type_var_gen.gen_tv();
                 self.type_var_env.insert(joined_constrs_tv, joined_constrs);
-                self.type_var_map.insert(*t, Type::Var(joined_constrs_tv));
+                self.bind_type_var(*t, Type::Var(joined_constrs_tv));
                 if a != b {
-                    self.type_var_map.insert(*u, Type::Var(joined_constrs_tv));
+                    self.bind_type_var(*u, Type::Var(joined_constrs_tv));
                 }
                 Ok(joined_constrs_tv)
             }
         }
     }
 
+    /// Unify the application of the higher kinded type variable `f` to `ts1` with the
+    /// application of `g` to `ts2`
+    ///
+    /// As type constructors are curried, `f` is unified with `g` partially applied to
+    /// all but the last `ts1.len()` types in `ts2`. E.g. `(f a)` and `(Pair Int64 Bool)`
+    /// are unified by `f = (Pair Int64)` and `a = Bool`.
+    fn unify_var_headed_app(
+        &mut self,
+        f: TVar<'s>,
+        ts1: &[Type<'s>],
+        g: &TypeFunc<'s>,
+        ts2: &[Type<'s>],
+    ) -> Option<Type<'s>> {
+        let n_partial = ts2.len() - ts1.len();
+        let g_partial = g.to_type().applied_to(ts2[..n_partial].to_vec());
+        let f_u = self.unify(&Type::Var(f), &g_partial).ok()?;
+        let us = zip(ts1, &ts2[n_partial..])
+            .map(|(t1, t2)| self.unify(t1, t2))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        Some(f_u.applied_to(us))
+    }
+
+    /// Unify the type variable `tv` of a fresh value with `t`
+    ///
+    /// The value is unique if it's used where a `(Unique T)` is expected, and an ordinary value
+    /// otherwise. Unconstrained type variables are merely bound to `tv`, as they don't decide
+    /// anything.
+    fn unify_fresh(
+        &mut self,
+        tv: TVar<'s>,
+        t: &Type<'s>,
+    ) -> Result<Type<'s>, (Type<'s>, Type<'s>)> {
+        match *t {
+            Type::Var(u) if u == tv => Ok(t.clone()),
+            Type::Var(u) if self.fresh.contains_key(&u) => {
+                let u_plain = self.fresh.remove(&u).unwrap();
+                let tv_plain = self.fresh[&tv].clone();
+                self.unify(&tv_plain, &u_plain)?;
+                self.bind_type_var(u, Type::Var(tv));
+                Ok(Type::Var(tv))
+            }
+            Type::Var(u @ TVar::Implicit(_)) if self.get_type_var_constraints(&u).is_empty() => {
+                self.bind_type_var(u, Type::Var(tv));
+                Ok(Type::Var(tv))
+            }
+            _ => {
+                let plain = self.fresh.remove(&tv).unwrap();
+                match t.get_unique() {
+                    Some(inner) => {
+                        let unique = Type::new_unique(self.unify(&plain, inner)?);
+                        self.bind_type_var(tv, unique.clone());
+                        Ok(unique)
+                    }
+                    None => {
+                        self.bind_type_var(tv, plain.clone());
+                        self.unify(&plain, t)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decide that the fresh values of the type variables in `t` are not unique
+    ///
+    /// Must be done before generalizing `t`, as a generalized variable would no longer be tied
+    /// to the type of the value.
+    fn settle_fresh(&mut self, t: &Type<'s>) {
+        for tv in self.free_type_vars(t) {
+            if let Some(plain) = self.fresh.remove(&tv) {
+                self.bind_type_var(tv, plain.clone());
+                self.settle_fresh(&plain)
+            }
+        }
+    }
+
     // TODO: Instantiation of circular type. Can it happen?
     /// Unify two types
     ///
@@ -496,6 +922,13 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 let t = self.type_var_map[tv].clone();
                 self.unify(&t, x)
             }
+            (&App(box TypeFunc::Var(ref f), ref ts), x)
+            | (x, &App(box TypeFunc::Var(ref f), ref ts))
+                if self.type_var_map.contains_key(f) =>
+            {
+                let t = self.type_var_map[f].clone().applied_to(ts.clone());
+                self.unify(&t, x)
+            }
             (&App(box TypeFunc::Poly(ref p), ref ts), x)
             | (x, &App(box TypeFunc::Poly(ref p), ref ts)) => {
                 assert_eq!(p.params.len(), ts.len());
@@ -511,24 +944,35 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 }
                 self.unify(&t, x)
             }
+            (&Var(ref tv), x) | (x, &Var(ref tv)) if self.fresh.contains_key(tv) => {
+                self.unify_fresh(*tv, x)
+            }
             (&Var(ref t), &Var(ref u)) => self.unify_vars(t, u).map(Type::Var),
             (&Var(ref tv), _) if occurs_in(tv, b, &self.type_var_map) => {
                 panic!("ICE: unify: `{}` occurs in `{}`", tv, b);
             }
             (&Var(TVar::Explicit(_)), _) => Err((a.clone(), b.clone())),
             (&Var(ref tv), _) => {
-                let fulfills_constrs = {
-                    let tv_constrs = self.get_type_var_constraints(tv);
-                    b.fulfills_constraints(&tv_constrs)
-                };
-                if fulfills_constrs {
-                    self.type_var_map.insert(*tv, b.clone());
+                let tv_constrs = self.get_type_var_constraints(tv).clone();
+                if self.fulfills_constraints(b, &tv_constrs) {
+                    self.bind_type_var(*tv, b.clone());
                     Ok(b.clone())
                 } else {
                     Err((a.clone(), b.clone()))
                 }
             }
             (_, &Var(_)) => self.unify(b, a),
+            (&App(box TypeFunc::Var(f), ref ts1), &App(ref g, ref ts2))
+                if ts1.len() <= ts2.len() =>
+            {
+                self.unify_var_headed_app(f, ts1, g, ts2)
+                    .ok_or_else(|| (a.clone(), b.clone()))
+            }
+            (&App(_, ref ts1), &App(box TypeFunc::Var(_), ref ts2))
+                if ts2.len() <= ts1.len() =>
+            {
+                self.unify(b, a)
+            }
             (&App(box TypeFunc::Const(c1), ref ts1), &App(box TypeFunc::Const(c2), ref ts2))
                 if c1 == c2 && ts1.len() == ts2.len() =>
             {
@@ -556,19 +1000,25 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     /// Check that the expected type of a nil expression is unifiable with the nil type
     fn infer_nil(&mut self, nil: &mut Nil<'s>, expected_type: &Type<'s>) -> Type<'s> {
         self.unify(expected_type, &TYPE_NIL)
-            .unwrap_or_else(|(e, f)| nil.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f)))
+            .unwrap_or_else(|(e, f)| {
+                nil.pos.error_exit(self.type_mis(&e, &f))
+            })
     }
 
     /// Check that the expected type of a string literal is unifiable with the string type
     fn infer_str_lit(&mut self, lit: &mut StrLit<'s>, expected_type: &Type<'s>) -> Type<'s> {
         self.unify(expected_type, &TYPE_STRING)
-            .unwrap_or_else(|(e, f)| lit.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f)))
+            .unwrap_or_else(|(e, f)| {
+                lit.pos.error_exit(self.type_mis(&e, &f))
+            })
     }
 
     /// Check that the expected type of a boolean literal is unifiable with the boolean type
     fn infer_bool(&mut self, b: &mut Bool<'s>, expected_type: &Type<'s>) -> Type<'s> {
         self.unify(expected_type, &TYPE_BOOL)
-            .unwrap_or_else(|(e, f)| b.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f)))
+            .unwrap_or_else(|(e, f)| {
+                b.pos.error_exit(self.type_mis(&e, &f))
+            })
     }
 
     /// Infer the type of a numeric literal
@@ -582,13 +1032,14 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         if lit.lit.contains('.') {
             lit.typ = self.unify(expected_type, &TYPE_FLOAT64)
                 .unwrap_or_else(|(e, f)| {
-                    lit.pos.error_exit(type_mis(&mut self.type_var_map, &e, &f))
+                    lit.pos.error_exit(self.type_mis(&e, &f))
                 });
             &lit.typ
         } else {
             let num_constraint = set_of("Num");
             let tv_num = self.type_var_gen.gen_tv();
             self.type_var_env.insert(tv_num, num_constraint);
+            self.literals.insert(tv_num, lit.pos.clone());
             lit.typ = self.unify(expected_type, &Type::Var(tv_num))
                 .unwrap_or_else(|_| {
                     lit.pos.error_exit(format!(
@@ -604,9 +1055,16 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     ///
     /// If the variable does not refer to an extern, instantiate the variable
     /// and unify with expected type. If it does refer to an extern,
-    /// unify type of extern with expected type.
+    /// unify type of extern with expected type. Methods of type classes are
+    /// instantiated like any other polymorphic variable.
     fn infer_variable(&mut self, var: &mut Variable<'s>, expected_type: &Type<'s>) -> Type<'s> {
-        if let Some(typ) = self.get_var(var.ident.s).cloned() {
+        let classes = self.classes;
+        let maybe_typ = self.get_var(var.ident.s).cloned().or_else(|| {
+            classes
+                .method_decl(var.ident.s)
+                .map(|decl| Type::Poly(box decl.sig.clone()))
+        });
+        if let Some(typ) = maybe_typ {
             // Either not an extern, or shadowing an extern. I.e. a lambda parameter or let binding
 
             // Do not assign unified type to `var.typ`. If definition of var
@@ -614,24 +1072,30 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
             // they may be used in the body of the definition but not show up
             // in the resulting type of the application.
             var.typ = self.instantiate(&typ);
+            if !var.type_args.is_empty() {
+                self.apply_type_args(var, &typ)
+            }
             let unif = self.unify(expected_type, &var.typ).unwrap_or_else(|_| {
+                let provenance = self.explain_inferred(&[expected_type]);
                 var.ident.pos.error_exit(format!(
-                    "Variable of type `{}` cannot be instantiated to expected type `{}`",
-                    typ, expected_type
+                    "Variable of type `{}` cannot be instantiated to expected type `{}`{}",
+                    self.type_aliases.resugar(&typ),
+                    self.type_aliases.resugar(expected_type),
+                    provenance.iter().map(|l| format!("\n{}", l)).join("")
                 ))
             });
             unif
         } else if let Some(ext) = self.externs.get(var.ident.s) {
             // An extern. Check that type of extern is unifiable with expected type
+            if !var.type_args.is_empty() {
+                var.ident.pos.error_exit(format!(
+                    "`{}` is an extern, which is not polymorphic, and can not be applied to types",
+                    var.ident.s
+                ))
+            }
             var.typ = self.unify(expected_type, &ext.typ)
                 .unwrap_or_else(|(e, f)| {
-                    var.ident.pos.error_exit(type_mis_sub(
-                        &mut self.type_var_map,
-                        expected_type,
-                        &ext.typ,
-                        &e,
-                        &f,
-                    ))
+                    var.ident.pos.error_exit(self.type_mis_sub(expected_type, &ext.typ, &e, &f))
                 });
             var.typ.clone()
         } else {
@@ -658,38 +1122,118 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         let (func_param_type, func_ret_type) = func_type
             .get_func()
             .expect("ICE: func_type was not func type in infer_app");
-        self.unify(func_param_type, &arg_type)
-            .unwrap_or_else(|(e, f)| {
-                app.arg.pos().error_exit(type_mis_sub(
-                    &mut self.type_var_map,
-                    func_param_type,
-                    &arg_type,
-                    &e,
-                    &f,
-                ))
-            });
+        // Substitutions made by unifying with the parameter type are due to the argument
+        let outer_cause = self.set_cause(app.arg.pos(), describe_expr(&app.arg));
+        let param_unification = self.unify(func_param_type, &arg_type);
+        self.cause = outer_cause;
+        if let Err((e, f)) = param_unification {
+            if self.coercion_modules.contains(app.arg.pos().filename()) {
+                self.coerce_arg(app, func_param_type, &arg_type, &e, &f)
+            } else {
+                app.arg.pos().error_exit(self.type_mis_sub(func_param_type, &arg_type, &e, &f))
+            }
+        }
         let ret_unification = self.unify(expected_type, func_ret_type)
             .unwrap_or_else(|(e, f)| {
-                app.pos.error_exit(type_mis_sub(
-                    &mut self.type_var_map,
-                    expected_type,
-                    func_ret_type,
-                    &e,
-                    &f,
-                ))
+                app.pos.error_exit(self.type_mis_sub(expected_type, func_ret_type, &e, &f))
             });
         app.typ = ret_unification;
         &app.typ
     }
 
+    /// Returns the cause of the substitution that bound the type variable `t`, followed through
+    /// other variables, to a type that is not a variable
+    fn concrete_cause(&self, t: &Type<'s>) -> Option<&Provenance<'s>> {
+        let mut current = match *t {
+            Type::Var(tv) => tv,
+            _ => return None,
+        };
+        loop {
+            match self.type_var_map.get(&current) {
+                Some(&Type::Var(next)) => current = next,
+                Some(_) => return self.provenances.get(&current),
+                None => return None,
+            }
+        }
+    }
+
+    /// If the type of the parameter that the argument of `app` is passed to was inferred from
+    /// a preceding argument of the call, like `x` in `(+ x y)`, returns the cause
+    fn ambiguous_coercion_cause(
+        &self,
+        app: &App<'s>,
+        param_type: &Type<'s>,
+    ) -> Option<&Provenance<'s>> {
+        let mut earlier_args = Vec::new();
+        let mut func = &app.func;
+        while let Expr::App(ref inner) = *func {
+            earlier_args.push(inner.arg.pos().clone());
+            func = &inner.func;
+        }
+        match self.concrete_cause(param_type) {
+            Some(cause) if earlier_args.contains(&cause.pos) => Some(cause),
+            _ => None,
+        }
+    }
+
+    /// Coerce the argument of `app` to the type of the parameter, by wrapping it in a cast
+    ///
+    /// Only widening coercions between numeric types are implicit, as lossy conversions must be
+    /// explicit. The coercion is ambiguous if the parameter type is not declared, but inferred
+    /// from another argument of the call. E.g. in `(+ x y)` of an `Int64` and an `Int32`,
+    /// either argument could be coerced.
+    fn coerce_arg(
+        &mut self,
+        app: &mut App<'s>,
+        param_type: &Type<'s>,
+        arg_type: &Type<'s>,
+        sub_expected: &Type<'s>,
+        sub_found: &Type<'s>,
+    ) {
+        let to = subst(param_type, &mut self.type_var_map);
+        let from = subst(arg_type, &mut self.type_var_map);
+        let pos = app.arg.pos().clone();
+        if !from.is_numeric() || !to.is_numeric() {
+            pos.error_exit(self.type_mis_sub(param_type, arg_type, sub_expected, sub_found))
+        }
+        let (from_s, to_s) = (from.get_const().unwrap(), to.get_const().unwrap());
+        if !widenings(from_s).iter().any(|&t| t == to_s) {
+            pos.error(self.type_mis(&to, &from));
+            pos.print_help(format!(
+                "`{}` can not be implicitly coerced to `{}`, as precision may be lost. \
+                 Use an explicit `cast`",
+                from, to
+            ));
+            exit()
+        }
+        if let Some(cause) = self.ambiguous_coercion_cause(app, param_type) {
+            pos.error(format!(
+                "Ambiguous coercion of `{}` to `{}`. The type of the parameter is not \
+                 declared, but inferred from another argument",
+                from, to
+            ));
+            cause.pos.print_note(format!("The type `{}` was inferred from here", to));
+            pos.print_help("Use an explicit `cast` to specify which argument to coerce");
+            exit()
+        }
+        let uncoerced = mem::replace(&mut app.arg, Expr::Nil(Nil { pos: pos.clone() }));
+        app.arg = Expr::Cast(box Cast {
+            expr: uncoerced,
+            typ: to,
+            pos,
+        })
+    }
+
     fn infer_if<'i>(&mut self, cond: &'i mut If<'s>, expected_typ: &Type<'s>) -> &'i Type<'s> {
         self.infer_expr(&mut cond.predicate, &TYPE_BOOL);
         let consequent_type = self.infer_expr(&mut cond.consequent, expected_typ);
         let alternative_type = self.infer_expr(&mut cond.alternative, expected_typ);
         cond.typ = self.unify(&consequent_type, &alternative_type)
             .unwrap_or_else(|_| {
-                cond.pos
-                    .error_exit(ArmsDiffer(consequent_type, alternative_type))
+                cond.pos.error_exit(ArmsDiffer(
+                    self.type_aliases.resugar(&consequent_type),
+                    self.type_aliases.resugar(&alternative_type),
+                ))
             });
         &cond.typ
     }
@@ -702,15 +1246,17 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     ) -> &'l Type<'s> {
         // Infer type of param by adding it to the environment and applying constraints based on
         // how it is used during inference of lambda body.
-
-        lam.typ = Type::new_func(
-            self.type_var_gen.gen_type_var(),
-            self.type_var_gen.gen_type_var(),
-        );
+        //
+        // If the param was annotated, the parser already put the annotation in the type.
+        let param_type = match lam.typ.get_func() {
+            Some((p, _)) => p.clone(),
+            None => self.type_var_gen.gen_type_var(),
+        };
+        lam.typ = Type::new_func(param_type, self.type_var_gen.gen_type_var());
         let (expected_param_type, expected_body_type) = self.unify(expected_type, &lam.typ)
             .unwrap_or_else(|_| {
                 lam.pos
-                    .error_exit(type_mis(&mut self.type_var_map, expected_type, &lam.typ))
+                    .error_exit(self.type_mis(expected_type, &lam.typ))
             })
             .get_func()
             .map(|(p, b)| (p.clone(), b.clone()))
@@ -751,48 +1297,110 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         }
     }
 
+    /// Extend the type variable environment with the type variables of the signature of
+    /// `binding`
+    ///
+    /// This scopes the explicit type variables of the signature over the definition, so that
+    /// they can be referred to in nested ascriptions, casts, and signatures of local bindings.
+    fn extend_type_var_env_with_sig(&mut self, binding: &Binding<'s>) {
+        self.extend_type_var_env(binding.sig.params.clone());
+        self.extend_type_var_env_no_constrs(&binding.sig.body.explicit_type_vars());
+    }
+
+    /// Returns the polytype of the signature of `binding`, if the signature is full
+    ///
+    /// A full signature leaves no part of the type to be inferred. All of its explicit type
+    /// variables, except those in scope from an enclosing signature, are parameters.
+    fn full_signature(&self, binding: &Binding<'s>) -> Option<Poly<'s>> {
+        let tvs = binding.sig.body.type_vars();
+        if tvs.iter().any(|tv| match *tv {
+            TVar::Implicit(_) => true,
+            TVar::Explicit(_) => false,
+        }) {
+            return None;
+        }
+        let mut params = binding.sig.params.clone();
+        for tv in tvs {
+            if !self.type_var_env.contains_key(&tv) {
+                params.entry(tv).or_insert(BTreeSet::new());
+            }
+        }
+        Some(Poly {
+            params,
+            body: binding.sig.body.clone(),
+        })
+    }
+
     /// Infer types for a group of mutually recursively defined bindings
-    fn infer_recursion_group(&mut self, group: &mut Group<'s>) {
+    ///
+    /// Bindings of the group that have full signatures may be used at other types than their
+    /// own within the group, i.e. polymorphic recursion. Others are inferred monomorphically
+    /// within the group before being generalized.
+    ///
+    /// A `local` binding of a fresh value is not generalized, so that whether the value is
+    /// unique may be decided by its uses in the body of the let.
+    fn infer_recursion_group(&mut self, group: &mut Group<'s>, local: bool) {
         match *group {
             Group::Uncircular(id, ref mut binding) => {
                 let old_tv_env = self.type_var_env.clone();
-                self.extend_type_var_env(binding.sig.params.clone());
+                self.extend_type_var_env_with_sig(binding);
                 self.infer_expr(&mut binding.val, &binding.sig.body);
-                let generalized_params = self.generalize(&binding.sig.body, &old_tv_env);
-                binding.sig.params = generalized_params;
+                let is_fresh = match subst(&binding.sig.body, &mut self.type_var_map) {
+                    Type::Var(tv) => self.fresh.contains_key(&tv),
+                    _ => false,
+                };
+                if !(local && is_fresh) {
+                    self.settle_fresh(&binding.sig.body);
+                    let generalized_params = self.generalize(&binding.sig.body, &old_tv_env);
+                    binding.sig.params = generalized_params;
+                }
                 self.push_var(id, binding.get_type());
                 self.type_var_env = old_tv_env;
             }
             Group::Circular(ref mut bindings) => {
                 let old_tv_env = self.type_var_env.clone();
-                let mut bindings_ids = vec![];
+                let bindings_ids = bindings.keys().cloned().collect::<Vec<_>>();
+                // Bindings with full signatures are polymorphic from the start, also in
+                // recursive refs, which allows polymorphic recursion. They are checked against
+                // their signatures after the rest of the group has been inferred.
+                let full_sigs = bindings
+                    .iter()
+                    .filter_map(|(&id, b)| self.full_signature(b).map(|p| (id, p)))
+                    .collect::<BTreeMap<_, _>>();
+                for (&id, p) in &full_sigs {
+                    self.push_var(id, Type::Poly(box p.clone()))
+                }
+                let is_full = |id: &&'s str| full_sigs.contains_key(id);
                 // Add bindings being inferred to env to allow recursive refs.
-                for (&id, binding) in bindings.iter() {
+                for (&id, binding) in bindings.iter().filter(|&(id, _)| !is_full(id)) {
                     self.push_var(id, binding.sig.body.clone());
-                    bindings_ids.push(id);
-                    self.extend_type_var_env(binding.sig.params.clone());
+                    self.extend_type_var_env_with_sig(binding);
                 }
                 // Infer bindings
-                for (_, binding) in bindings.iter_mut() {
+                for (_, binding) in bindings.iter_mut().filter(|&(id, _)| !is_full(id)) {
                     self.infer_recursive_binding(binding, &bindings_ids)
                 }
-                for (id, _) in bindings.iter() {
+                for (id, _) in bindings.iter().filter(|&(id, _)| !is_full(id)) {
                     self.pop_var(id).unwrap_or_else(|| {
                         panic!("ICE: infer_recursion_group: binding gone from var_env")
                     });
                 }
-                // Because of mutual recursion, all bindings in group must have the
+                for (_, binding) in bindings.iter().filter(|&(id, _)| !is_full(id)) {
+                    self.settle_fresh(&binding.sig.body)
+                }
+                // Because of mutual recursion, all inferred bindings in group must have the
                 // same polytype arguments
                 let generalized_params = bindings
-                    .values()
-                    .flat_map(|b| self.generalize(&b.sig.body, &old_tv_env))
+                    .iter()
+                    .filter(|&(id, _)| !is_full(id))
+                    .flat_map(|(_, b)| self.generalize(&b.sig.body, &old_tv_env))
                     .collect::<BTreeMap<_, _>>();
                 let mut vars_polys = BTreeMap::new();
-                for (id, binding) in bindings.iter_mut() {
+                for (id, binding) in bindings.iter_mut().filter(|&(id, _)| !is_full(id)) {
                     binding.sig.params = generalized_params.clone();
                     vars_polys.insert(*id, binding.sig.clone());
                 }
-                for (_, binding) in bindings.iter_mut() {
+                for (_, binding) in bindings.iter_mut().filter(|&(id, _)| !is_full(id)) {
                     wrap_vars_types_in_apps(
                         &mut binding.val,
                         &mut vars_polys,
@@ -801,25 +1409,55 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 }
                 // Push vars to env again to make available for the
                 // next group in the topological order
-                for (id, binding) in bindings.iter() {
+                for (id, binding) in bindings.iter().filter(|&(id, _)| !is_full(id)) {
                     self.push_var(*id, Type::Poly(box binding.sig.clone()))
                 }
-                self.type_var_env = old_tv_env;
+                self.type_var_env = old_tv_env.clone();
+                // Check bindings with full signatures. Refs to them already instantiate their
+                // polytypes, so there is nothing to wrap.
+                for (id, binding) in bindings.iter_mut() {
+                    if let Some(p) = full_sigs.get(id) {
+                        binding.sig = p.clone();
+                        self.extend_type_var_env(p.params.clone());
+                        self.infer_recursive_binding(binding, &bindings_ids);
+                        self.type_var_env = old_tv_env.clone();
+                    }
+                }
             }
         }
     }
 
-    /// Infer types for global bindings or bindings of a let-form
+    /// Infer types for the definition of a method in a class or instance, checking it against
+    /// the method signature
+    ///
+    /// Unlike other bindings, the definition is not generalized or added to the environment,
+    /// as uses of the method refer to the class method.
+    fn infer_method_def(&mut self, binding: &mut Binding<'s>) {
+        let old_tv_env = self.type_var_env.clone();
+        self.extend_type_var_env_with_sig(binding);
+        self.infer_expr(&mut binding.val, &binding.sig.body);
+        self.type_var_env = old_tv_env;
+    }
+
+    /// Infer types for global bindings or `local` bindings of a let-form
     /// and push them to the environment.
-    fn infer_bindings(&mut self, bindings: &mut TopologicallyOrderedDependencyGroups<'s>) {
+    fn infer_bindings(
+        &mut self,
+        bindings: &mut TopologicallyOrderedDependencyGroups<'s>,
+        local: bool,
+    ) {
         for mut recursion_group in bindings.groups_mut().rev() {
-            self.infer_recursion_group(recursion_group);
+            self.infer_recursion_group(recursion_group, local);
         }
     }
 
     fn infer_let<'l>(&mut self, let_: &'l mut Let<'s>, expected_type: &Type<'s>) -> &'l Type<'s> {
-        self.infer_bindings(&mut let_.bindings);
+        self.infer_bindings(&mut let_.bindings, true);
         let_.typ = self.infer_expr(&mut let_.body, expected_type).clone();
+        // Fresh values not used as unique in the body are ordinary values
+        for binding in let_.bindings.bindings() {
+            self.settle_fresh(&binding.sig.body)
+        }
         for name in let_.bindings.ids() {
             self.pop_var(name)
                 .unwrap_or_else(|| panic!("ICE: binding gone from var_env in infer_let"));
@@ -837,7 +1475,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         match expr.remove_type_ascription() {
             Some(ascribed) => {
                 let expected_type2 = self.unify(expected_type, &ascribed).unwrap_or_else(|_| {
-                    ascr_pos.error_exit(type_mis(&mut self.type_var_map, expected_type, &ascribed))
+                    ascr_pos.error_exit(self.type_mis(expected_type, &ascribed))
                 });
                 self.infer_expr(expr, &expected_type2)
             }
@@ -852,11 +1490,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         );
         let expected_type2 = self.unify(expected_type, &arbitrary_cons_type)
             .unwrap_or_else(|_| {
-                cons.pos.error_exit(type_mis(
-                    &mut self.type_var_map,
-                    expected_type,
-                    &arbitrary_cons_type,
-                ))
+                cons.pos.error_exit(self.type_mis(expected_type, &arbitrary_cons_type))
             });
         let (expected_car_type, expected_cdr_type) = expected_type2
             .get_cons()
@@ -896,17 +1530,50 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         self.infer_expr(&mut cast.expr, &expected_from);
         cast.typ = self.unify(expected_type, &cast.typ).unwrap_or_else(|_| {
             cast.pos
-                .error_exit(type_mis(&mut self.type_var_map, expected_type, &cast.typ))
+                .error_exit(self.type_mis(expected_type, &cast.typ))
         });
         &cast.typ
     }
 
+    /// Infer the type of a construction
+    ///
+    /// A freshly constructed value is not referenced from anywhere else, and may be unique.
+    /// If it's not yet known whether it must be, it's given the type variable of a fresh value,
+    /// which is decided when the value is used. E.g. when passed to a function taking a
+    /// `(Unique T)`, or when a let-bound value is used as one.
     fn infer_new<'n>(&mut self, n: &'n mut New<'s>, expected_type: &Type<'s>) -> &'n Type<'s> {
+        match subst(expected_type, &mut self.type_var_map) {
+            Type::App(box TypeFunc::Const("Unique"), ref ts) => {
+                let inner_typ = self.infer_new_plain(n, &ts[0]).clone();
+                n.typ = Type::new_unique(inner_typ);
+            }
+            Type::Var(tv @ TVar::Implicit(_))
+                if self.fresh.contains_key(&tv) || self.get_type_var_constraints(&tv).is_empty() =>
+            {
+                let plain = self.type_var_gen.gen_type_var();
+                let plain_typ = self.infer_new_plain(n, &plain).clone();
+                let fresh_tv = self.type_var_gen.gen_tv();
+                self.fresh.insert(fresh_tv, plain_typ);
+                n.typ = self.unify(expected_type, &Type::Var(fresh_tv))
+                    .expect("ICE: unification with fresh type variable failed in infer_new");
+            }
+            _ => {
+                self.infer_new_plain(n, expected_type);
+            }
+        }
+        &n.typ
+    }
+
+    fn infer_new_plain<'n>(
+        &mut self,
+        n: &'n mut New<'s>,
+        expected_type: &Type<'s>,
+    ) -> &'n Type<'s> {
         n.typ = self.parent_type_of_variant(n.constr.s)
             .expect("ICE: No type_of_variant in infer_new");
         n.typ = self.unify(expected_type, &n.typ).unwrap_or_else(|_| {
             n.pos
-                .error_exit(type_mis(&mut self.type_var_map, expected_type, &n.typ))
+                .error_exit(self.type_mis(expected_type, &n.typ))
         });
         let inst = n.typ.get_adt_inst_args().unwrap_or(&[]);
         let expected_member_types = self.adts
@@ -919,7 +1586,9 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     }
 
     fn infer_pattern(&mut self, patt: &mut Pattern<'s>, expected_type: &Type<'s>) -> Type<'s> {
-        match *patt {
+        let outer_cause = self.set_cause(patt.pos(), "the pattern".to_string());
+        let typ = match *patt {
+            Pattern::Wild(_) => expected_type.clone(),
             Pattern::Nil(ref mut nil) => self.infer_nil(nil, expected_type),
             Pattern::NumLit(ref mut num) => self.infer_num_lit(num, expected_type).clone(),
             Pattern::StrLit(ref mut lit) => self.infer_str_lit(lit, expected_type),
@@ -938,7 +1607,7 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 let adt_inst = adt_type.get_adt_inst_args().unwrap_or(&[]);
                 let typ = self.unify(expected_type, &adt_type).unwrap_or_else(|_| {
                     dec.pos
-                        .error_exit(type_mis(&mut self.type_var_map, expected_type, &adt_type))
+                        .error_exit(self.type_mis(expected_type, &adt_type))
                 });
                 let variant_members = self.adts
                     .members_with_inst_of_variant_with_name(dec.constr.s, adt_inst)
@@ -955,7 +1624,58 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
                 }
                 typ
             }
+            Pattern::As(ref mut a) => {
+                let typ = self.infer_pattern(&mut a.patt, expected_type);
+                a.var.typ = typ.clone();
+                typ
+            }
+            Pattern::Or(ref mut o) => self.infer_or_pattern(o, expected_type),
+            Pattern::Cons(ref mut cons) => {
+                let arbitrary_cons_type = Type::new_cons(
+                    self.type_var_gen.gen_type_var(),
+                    self.type_var_gen.gen_type_var(),
+                );
+                let expected_type2 = self.unify(expected_type, &arbitrary_cons_type)
+                    .unwrap_or_else(|_| {
+                        cons.pos.error_exit(self.type_mis(expected_type, &arbitrary_cons_type))
+                    });
+                let (expected_car_type, expected_cdr_type) = expected_type2
+                    .get_cons()
+                    .expect("ICE: expected type not cons in infer_pattern");
+                let car_type = self.infer_pattern(&mut cons.car, expected_car_type);
+                let cdr_type = self.infer_pattern(&mut cons.cdr, expected_cdr_type);
+                cons.typ = Type::new_cons(car_type, cdr_type);
+                cons.typ.clone()
+            }
+        };
+        self.cause = outer_cause;
+        typ
+    }
+
+    /// Infer the types of the alternatives of an or-pattern, and unify the types of the
+    /// variables bound in each alternative with those of the first
+    fn infer_or_pattern(&mut self, o: &mut OrPattern<'s>, expected_type: &Type<'s>) -> Type<'s> {
+        let mut typ = expected_type.clone();
+        for alt in &mut o.alts {
+            typ = self.infer_pattern(alt, &typ);
+        }
+        let (first, rest) = o.alts
+            .split_first_mut()
+            .expect("ICE: No alternatives in infer_or_pattern");
+        let first_var_types = first
+            .variables()
+            .into_iter()
+            .map(|v| (v.ident.s, v.typ.clone()))
+            .collect::<BTreeMap<_, _>>();
+        for alt in rest {
+            for var in alt.variables_mut() {
+                let expected_var_type = &first_var_types[var.ident.s];
+                var.typ = self.unify(expected_var_type, &var.typ).unwrap_or_else(|_| {
+                    var.ident.pos.error_exit(self.type_mis(expected_var_type, &var.typ))
+                });
+            }
         }
+        typ
     }
 
     fn infer_case<'c>(
@@ -968,6 +1688,9 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
         for var in case.patt.variables() {
             self.push_var(var.ident.s, var.typ.clone())
         }
+        if let Some(ref mut guard) = case.guard {
+            self.infer_expr(guard, &TYPE_BOOL);
+        }
         self.infer_expr(&mut case.body, expected_body_type);
         for var in case.patt.variables() {
             self.pop_var(var.ident.s)
@@ -979,16 +1702,103 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
     fn infer_match<'m>(&mut self, m: &'m mut Match<'s>, expected_type: &Type<'s>) -> &'m Type<'s> {
         let expected_expr_type = self.type_var_gen.gen_type_var();
         let expr_typ = self.infer_expr(&mut m.expr, &expected_expr_type);
+        // A unique value is consumed by the match, and its parts are matched like those of
+        // any other value of the inner type
+        let expr_typ = subst(&expr_typ, &mut self.type_var_map);
+        let patt_typ = expr_typ.get_unique().unwrap_or(&expr_typ).clone();
         for case in &mut m.cases {
-            self.infer_case(case, &expr_typ, expected_type);
+            self.infer_case(case, &patt_typ, expected_type);
         }
         m.typ = expected_type.clone();
         &m.typ
     }
 
+    /// Infer the type of a typed hole
+    ///
+    /// A hole fits any expected type. The hole is recorded along with the variables in scope,
+    /// so that the candidates to fill it can be reported once the types are fully inferred.
+    fn infer_hole<'h>(
+        &mut self,
+        hole: &'h mut Hole<'s>,
+        expected_type: &Type<'s>,
+    ) -> &'h Type<'s> {
+        hole.typ = expected_type.clone();
+        let scope = self.var_env
+            .iter()
+            .filter_map(|(&id, ts)| ts.last().map(|t| (id, t.clone())))
+            .collect();
+        self.holes.push(TypedHole {
+            name: hole.name,
+            typ: hole.typ.clone(),
+            pos: hole.pos.clone(),
+            scope,
+        });
+        &hole.typ
+    }
+
+    /// Returns whether a variable of type `t` could fill a hole of type `hole_type`
+    ///
+    /// Unification is only tried, and any substitutions it makes are undone afterwards.
+    fn fits_hole(&mut self, hole_type: &Type<'s>, t: &Type<'s>) -> bool {
+        let old_type_var_map = self.type_var_map.clone();
+        let old_type_var_env = self.type_var_env.clone();
+        let inst = self.instantiate(t);
+        let fits = self.unify(hole_type, &inst).is_ok();
+        self.type_var_map = old_type_var_map;
+        self.type_var_env = old_type_var_env;
+        fits
+    }
+
+    /// Returns the local and global bindings whose types unify with the type `typ` of `hole`
+    fn fitting_bindings(
+        &mut self,
+        hole: &TypedHole<'s>,
+        typ: &Type<'s>,
+    ) -> Vec<(&'s str, Type<'s>)> {
+        let externs = self.externs
+            .iter()
+            .filter(|&(id, _)| !hole.scope.iter().any(|&(id2, _)| id2 == *id))
+            .map(|(&id, ext)| (id, ext.typ.clone()))
+            .collect::<Vec<_>>();
+        let mut fits = Vec::new();
+        for (id, t) in hole.scope.iter().cloned().chain(externs) {
+            if self.fits_hole(typ, &t) {
+                fits.push((id, subst(&t, &mut self.type_var_map)));
+            }
+        }
+        fits
+    }
+
+    /// Report the types of all typed holes under the current substitution, together with the
+    /// local and global bindings whose types unify with them
+    fn report_holes(&mut self) {
+        let holes = mem::replace(&mut self.holes, Vec::new());
+        for hole in holes {
+            let typ = subst(&hole.typ, &mut self.type_var_map);
+            hole.pos.error(format!(
+                "Found hole `?{}` of type `{}`",
+                hole.name.unwrap_or(""),
+                self.type_aliases.resugar(&typ)
+            ));
+            let fits = self.fitting_bindings(&hole, &typ)
+                .into_iter()
+                .map(|(id, t)| format!("  `{}`: `{}`", id, self.type_aliases.resugar(&t)))
+                .collect::<Vec<_>>();
+            if fits.is_empty() {
+                hole.pos.print_note("No bindings in scope fit the hole")
+            } else {
+                hole.pos.print_note(format!(
+                    "Bindings in scope that fit the hole:\n{}",
+                    fits.join("\n")
+                ))
+            }
+        }
+    }
+
     // The type of an expression will only be inferred once
     fn infer_expr(&mut self, expr: &mut Expr<'s>, expected_type: &Type<'s>) -> Type<'s> {
-        match *expr {
+        let outer_cause = self.set_cause(expr.pos(), describe_expr(expr));
+        let typ = match *expr {
             Expr::Nil(ref mut nil) => self.infer_nil(nil, expected_type),
             Expr::StrLit(ref mut l) => self.infer_str_lit(l, expected_type),
             Expr::Bool(ref mut b) => self.infer_bool(b, expected_type),
@@ -1005,7 +1815,10 @@ impl<'a, 's: 'a> Inferrer<'a, 's> {
             Expr::Cast(ref mut c) => self.infer_cast(c, expected_type).clone(),
             Expr::New(ref mut n) => self.infer_new(n, expected_type).clone(),
             Expr::Match(ref mut m) => self.infer_match(m, expected_type).clone(),
-        }
+            Expr::Hole(ref mut h) => self.infer_hole(h, expected_type).clone(),
+        };
+        self.cause = outer_cause;
+        typ
     }
 }
 
@@ -1018,18 +1831,400 @@ fn assert_externs_monomorphic(externs: &BTreeMap<&str, ExternDecl>) {
     }
 }
 
-pub fn infer_types(ast: &mut Ast, type_var_generator: &mut TypeVarGen) {
-    assert_externs_monomorphic(&ast.externs);
-    let mut inferrer = Inferrer::new(&mut ast.externs, &mut ast.adts, type_var_generator);
+/// Returns mutable references to all method definitions of classes and instances
+fn method_defs_mut<'a, 's>(classes: &'a mut Classes<'s>) -> Vec<&'a mut Binding<'s>> {
+    let Classes {
+        ref mut defs,
+        ref mut instances,
+        ..
+    } = *classes;
+    defs.values_mut()
+        .flat_map(|class| class.defaults.values_mut())
+        .chain(instances.values_mut().flat_map(|inst| inst.methods.values_mut()))
+        .collect()
+}
+
+/// Add bindings of the methods of all classes to `globals`
+///
+/// The definition of a method binding is only a placeholder. Monomorphizations are
+/// generated from the definition of the resolved instance.
+fn add_method_bindings<'s>(
+    globals: &mut TopologicallyOrderedDependencyGroups<'s>,
+    classes: &Classes<'s>,
+) {
+    for class in classes.defs.values() {
+        for (&name, decl) in &class.methods {
+            let binding = Binding {
+                ident: decl.ident.clone(),
+                sig: decl.sig.clone(),
+                val: Expr::Nil(Nil {
+                    pos: decl.pos.clone(),
+                }),
+                mono_insts: BTreeMap::new(),
+                pos: decl.pos.clone(),
+            };
+            globals.0.push(Group::Uncircular(name, binding))
+        }
+    }
+}
 
-    inferrer.infer_bindings(&mut ast.globals);
+/// Infer and check the types of `ast`, and monomorphize the instantiations of definitions
+///
+/// Types left ambiguous are defaulted to the default type of `ast` during monomorphization,
+/// with a warning, unless `deny_defaulting`, in which case it's an error.
+pub fn infer_types(ast: &mut Ast, type_var_generator: &mut TypeVarGen, deny_defaulting: bool) {
+    assert_externs_monomorphic(&ast.externs);
+    check_kinds(ast);
+    // The inferrer only needs the declarations of classes and the heads of instances,
+    // while the method definitions are inferred below
+    let classes = ast.classes.clone();
+    let mut inferrer = Inferrer::new(
+        &ast.externs,
+        &ast.adts,
+        &classes,
+        &ast.type_aliases,
+        &ast.coercion_modules,
+        &ast.default_type,
+        type_var_generator,
+    );
+
+    inferrer.infer_bindings(&mut ast.globals, false);
+    for binding in method_defs_mut(&mut ast.classes) {
+        inferrer.infer_method_def(binding);
+    }
+    // Fresh values never used where a unique type is expected are ordinary values
+    let fresh = inferrer.fresh.keys().map(|&tv| Type::Var(tv)).collect::<Vec<_>>();
+    for t in fresh {
+        inferrer.settle_fresh(&t)
+    }
 
     // Apply all substitutions recursively to get rid of reduntant, indirect type variables
     for binding in ast.globals.bindings_mut() {
         binding.sig.body = subst(&binding.sig.body, &mut inferrer.type_var_map);
         subst_expr(&mut binding.val, &mut inferrer.type_var_map);
     }
+    for binding in method_defs_mut(&mut ast.classes) {
+        subst_expr(&mut binding.val, &mut inferrer.type_var_map);
+    }
+
+    // Typed holes are only meant for sketching code, so stop after reporting them
+    if !inferrer.holes.is_empty() {
+        inferrer.report_holes();
+        exit()
+    }
+
+    check_matches(ast);
+
+    add_method_bindings(&mut ast.globals, &ast.classes);
 
     // Map monomorphic instantiations of variables to monomorphization of definitions
-    monomorphize_defs_of_insts(&mut ast.globals);
+    let mut defaulting = Defaulting::new(ast.default_type.clone(), deny_defaulting);
+    monomorphize_defs_of_insts(&mut ast.globals, &ast.classes, &mut defaulting);
+    check_linearity(ast);
+}
+
+#[cfg(test)]
+mod test {
+    use lib::set_of;
+    use lib::collections::AddMap;
+    use lib::front::TypeVarGen;
+    use lib::front::ast::*;
+    use lib::front::parse::parse_test_program;
+    use lib::front::substitution::subst;
+    use std::mem;
+    use super::{infer_types, Inferrer};
+
+    /// Returns the type of the global `name`, or of the let-binding `local` in its body
+    fn type_of<'s>(ast: &Ast<'s>, name: &str, local: Option<&str>) -> String {
+        let binding = ast.globals
+            .bindings()
+            .find(|b| b.ident.s == name)
+            .expect("binding not found");
+        let mut body = &binding.val;
+        while let Expr::Lambda(ref lam) = *body {
+            body = &lam.body;
+        }
+        match (local, body) {
+            (None, _) => binding.sig.body.to_string(),
+            (Some(local), &Expr::Let(ref l)) => l.bindings
+                .bindings()
+                .find(|b| b.ident.s == local)
+                .expect("local binding not found")
+                .sig
+                .body
+                .to_string(),
+            _ => panic!("value of `{}` is not a let", name),
+        }
+    }
+
+    #[test]
+    fn test_infer_fresh_uniqueness() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(data Ints Done (More Int64 (Unique Ints)))
+             (extern consume (-> (Unique Ints) Int64))
+             (define unique (let [[ns (new More 1 (new Done))]] (consume ns)))
+             (define plain (let [[ns (new Done)]] ns))
+             (define: annotated (Unique Ints) (new Done))",
+            &sources,
+            &mut tvg,
+        );
+        infer_types(&mut ast, &mut tvg, false);
+        assert_eq!(type_of(&ast, "unique", Some("ns")), "(Unique Ints)");
+        assert_eq!(type_of(&ast, "plain", Some("ns")), "Ints");
+        assert_eq!(type_of(&ast, "plain", None), "Ints");
+        assert_eq!(type_of(&ast, "annotated", None), "(Unique Ints)");
+    }
+
+    #[test]
+    fn test_hole_fitting_bindings() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(extern i Int64)
+             (extern f Float64)
+             (define: (g x) (-> Int64 Int64) ?body)",
+            &sources,
+            &mut tvg,
+        );
+        let classes = ast.classes.clone();
+        let mut inferrer = Inferrer::new(
+            &ast.externs,
+            &ast.adts,
+            &classes,
+            &ast.type_aliases,
+            &ast.coercion_modules,
+            &ast.default_type,
+            &mut tvg,
+        );
+        inferrer.infer_bindings(&mut ast.globals, false);
+        let holes = mem::replace(&mut inferrer.holes, Vec::new());
+        assert_eq!(holes.len(), 1);
+        assert_eq!(holes[0].name, Some("body"));
+        let typ = subst(&holes[0].typ, &mut inferrer.type_var_map);
+        assert_eq!(typ.to_string(), "Int64");
+        let fits = inferrer
+            .fitting_bindings(&holes[0], &typ)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(fits, vec!["x", "i"]);
+    }
+
+    #[test]
+    fn test_explain_inferred() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(extern consume (-> Int64 Nil))
+(define (f x) (consume x))",
+            &sources,
+            &mut tvg,
+        );
+        let classes = ast.classes.clone();
+        let mut inferrer = Inferrer::new(
+            &ast.externs,
+            &ast.adts,
+            &classes,
+            &ast.type_aliases,
+            &ast.coercion_modules,
+            &ast.default_type,
+            &mut tvg,
+        );
+        inferrer.infer_bindings(&mut ast.globals, false);
+        let f = ast.globals.bindings().find(|b| b.ident.s == "f").unwrap();
+        let arg_type = match f.val {
+            Expr::Lambda(box Lambda {
+                body: Expr::App(ref app),
+                ..
+            }) => app.arg.get_type().clone(),
+            _ => panic!("unexpected definition of `f`"),
+        };
+        let lines = inferrer.explain_inferred(&[&arg_type]);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("`Int64` was inferred because of the variable `x` at 2:"));
+    }
+
+    #[test]
+    fn test_scoped_type_variables() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(define: (dup x) (-> a (Cons a a))
+               (let [[y a x]] (cons x y)))
+             (define (inc (: n Int64)) n)",
+            &sources,
+            &mut tvg,
+        );
+        infer_types(&mut ast, &mut tvg, false);
+        // `a` in the signature of `y` is the `a` of `dup`, not a new type variable
+        assert_eq!(type_of(&ast, "dup", Some("y")), "a");
+        assert_eq!(type_of(&ast, "inc", None), "(-> Int64 Int64)");
+    }
+
+    #[test]
+    fn test_polymorphic_recursion() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(extern fl Float64)
+             (define: (f x) (-> a Int64) (f fl))",
+            &sources,
+            &mut tvg,
+        );
+        let classes = ast.classes.clone();
+        let mut inferrer = Inferrer::new(
+            &ast.externs,
+            &ast.adts,
+            &classes,
+            &ast.type_aliases,
+            &ast.coercion_modules,
+            &ast.default_type,
+            &mut tvg,
+        );
+        inferrer.infer_bindings(&mut ast.globals, false);
+        // The recursive use at `Float64` does not fix `a`
+        assert_eq!(type_of(&ast, "f", None), "(-> a Int64)");
+    }
+
+    #[test]
+    fn test_explicit_type_application() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(define: (k x y) (-> a b a) x)
+             (define k-float (@ k Float64))
+             (define k-float-int (@ k Float64 Int64))
+             (define: (k' x y) (-> b a b) x)
+             (define k'-float-int (@ k' Float64 Int64))",
+            &sources,
+            &mut tvg,
+        );
+        infer_types(&mut ast, &mut tvg, false);
+        // Type arguments fill the leading parameters, leaving the rest polymorphic
+        assert!(type_of(&ast, "k-float", None).starts_with("(-> Float64 (-> "));
+        assert_eq!(
+            type_of(&ast, "k-float-int", None),
+            "(-> Float64 (-> Int64 Float64))"
+        );
+        // Arguments follow the quantifier list, not the order of occurrence in the signature
+        assert_eq!(
+            type_of(&ast, "k'-float-int", None),
+            "(-> Int64 (-> Float64 Int64))"
+        );
+    }
+
+    #[test]
+    fn test_coerce_declared_param() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(allow-coercion)
+             (extern widen (-> Int64 Int64))
+             (define: small Int32 1)
+             (define x (widen small))",
+            &sources,
+            &mut tvg,
+        );
+        infer_types(&mut ast, &mut tvg, false);
+        let x = ast.globals.bindings().find(|b| b.ident.s == "x").unwrap();
+        match x.val {
+            Expr::App(box App {
+                arg: Expr::Cast(ref cast),
+                ..
+            }) => assert_eq!(cast.typ.to_string(), "Int64"),
+            _ => panic!("argument of `widen` was not coerced"),
+        }
+    }
+
+    /// Infers the function of the call, and returns whether coercing the last argument would be
+    /// ambiguous
+    fn is_ambiguous<'a, 's: 'a>(inferrer: &mut Inferrer<'a, 's>, call: Expr<'s>) -> bool {
+        let mut app = match call {
+            Expr::App(box app) => app,
+            _ => panic!("call is not an application"),
+        };
+        let expected_func_type = Type::new_func(
+            inferrer.type_var_gen.gen_type_var(),
+            inferrer.type_var_gen.gen_type_var(),
+        );
+        let func_type = inferrer.infer_expr(&mut app.func, &expected_func_type);
+        let (param_type, _) = func_type.get_func().unwrap();
+        inferrer
+            .ambiguous_coercion_cause(&app, param_type)
+            .is_some()
+    }
+
+    #[test]
+    fn test_ambiguous_coercion_cause() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(allow-coercion)
+             (define: (add x y) (-> a a a) x)
+             (extern large Int64)
+             (extern small Int32)
+             (define ambiguous (add large small))
+             (define declared ((@ add Int64) large small))",
+            &sources,
+            &mut tvg,
+        );
+        let (ambiguous, declared) = {
+            let val_of = |name| {
+                ast.globals
+                    .bindings()
+                    .find(|b| b.ident.s == name)
+                    .map(|b| b.val.clone())
+                    .unwrap()
+            };
+            (val_of("ambiguous"), val_of("declared"))
+        };
+        // Only infer `add`, and the calls step by step below
+        ast.globals
+            .0
+            .retain(|g| g.bindings().all(|b| b.ident.s == "add"));
+        let classes = ast.classes.clone();
+        let mut inferrer = Inferrer::new(
+            &ast.externs,
+            &ast.adts,
+            &classes,
+            &ast.type_aliases,
+            &ast.coercion_modules,
+            &ast.default_type,
+            &mut tvg,
+        );
+        inferrer.infer_bindings(&mut ast.globals, false);
+        // `a` is inferred to be `Int64` from `large`, so `small` could be coerced or not
+        assert!(is_ambiguous(&mut inferrer, ambiguous));
+        // `a` is given explicitly
+        assert!(!is_ambiguous(&mut inferrer, declared));
+    }
+
+    #[test]
+    fn test_unfulfilled_constraint() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = parse_test_program(
+            "(class (Describe a) (: describe (-> a Int64)))
+             (instance (Describe Int64) (define (describe x) x))",
+            &sources,
+            &mut tvg,
+        );
+        let mut inferrer = Inferrer::new(
+            &ast.externs,
+            &ast.adts,
+            &ast.classes,
+            &ast.type_aliases,
+            &ast.coercion_modules,
+            &ast.default_type,
+            &mut tvg,
+        );
+        let tv = inferrer.type_var_gen.gen_tv();
+        inferrer.type_var_env.insert(tv, set_of("Describe"));
+        let (int, float) = (Type::Const("Int64", None), Type::Const("Float64", None));
+        assert_eq!(inferrer.unfulfilled_constraint(&Type::Var(tv), &int), None);
+        let missing = inferrer.unfulfilled_constraint(&float, &Type::Var(tv));
+        assert_eq!(missing, Some(("Describe", float)));
+    }
 }
@@ -0,0 +1,555 @@
+//! Kind inference and checking
+//!
+//! The kind of a type is to the type what the type of a value is to the value. Proper types
+//! like `Int64` and `(List Int64)` are of kind `*`, while type constructors like `List` are
+//! of kind `(-> * *)`. As type variables may be of higher kind, e.g. `f` in `(f a)`, the
+//! kinds of type variables and data type parameters are inferred, and all types are checked
+//! to be well-kinded. This also catches type constructors applied to the wrong number of
+//! arguments, and names of undefined types.
+
+use lib::front::SrcPos;
+use lib::front::ast::*;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// The kind of a type
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// The kind of proper types, i.e. types of values
+    Star,
+    /// The kind of a type constructor, taking a type of the first kind and
+    /// resulting in a type of the second kind
+    Arrow(Box<Kind>, Box<Kind>),
+    /// A kind variable to be inferred
+    Var(u64),
+}
+
+impl Kind {
+    fn new_arrow(param: Kind, result: Kind) -> Self {
+        Kind::Arrow(Box::new(param), Box::new(result))
+    }
+
+    /// Returns the kind of a type constructor of `n` parameters of kind `*`
+    fn new_star_arrows(n: usize) -> Self {
+        (0..n).fold(Kind::Star, |acc, _| Kind::new_arrow(Kind::Star, acc))
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Kind::Star => write!(f, "*"),
+            Kind::Arrow(ref a, ref b) => write!(f, "(-> {} {})", a, b),
+            // Unresolved kind variables may be of any kind
+            Kind::Var(_) => write!(f, "_"),
+        }
+    }
+}
+
+/// The primitive types, all of kind `*`
+const PRIMITIVE_TYPES: &[&str] = &[
+    "Int8",
+    "Int16",
+    "Int32",
+    "Int64",
+    "IntPtr",
+    "UInt8",
+    "UInt16",
+    "UInt32",
+    "UInt64",
+    "UIntPtr",
+    "Bool",
+    "Float32",
+    "Float64",
+    "Nil",
+    "RealWorld",
+];
+
+/// An error in the kind of a type
+enum KindErr<'s> {
+    /// Mismatch between a found and an expected kind
+    Mismatch(Kind, Kind),
+    /// Type constructor applied to the wrong number of arguments. Expected some amount, found
+    /// another
+    Arity(&'s str, usize, usize),
+    /// Undefined type name, with the position of the name if known
+    Undefined(&'s str, Option<SrcPos<'s>>),
+}
+
+impl<'s> From<(Kind, Kind)> for KindErr<'s> {
+    fn from((found, expected): (Kind, Kind)) -> Self {
+        KindErr::Mismatch(found, expected)
+    }
+}
+
+struct KindInferrer<'s> {
+    /// Kinds of the defined data types
+    adt_kinds: BTreeMap<&'s str, Kind>,
+    /// Kinds of the parameters of the defined classes
+    class_kinds: BTreeMap<&'s str, Kind>,
+    /// Substitutions of kind variables
+    kind_var_map: BTreeMap<u64, Kind>,
+    kind_var_count: u64,
+}
+
+impl<'s> KindInferrer<'s> {
+    fn new() -> Self {
+        KindInferrer {
+            adt_kinds: BTreeMap::new(),
+            class_kinds: BTreeMap::new(),
+            kind_var_map: BTreeMap::new(),
+            kind_var_count: 0,
+        }
+    }
+
+    fn gen_kind_var(&mut self) -> Kind {
+        self.kind_var_count += 1;
+        Kind::Var(self.kind_var_count)
+    }
+
+    /// Apply the substitutions of kind variables to `k`
+    fn subst(&self, k: &Kind) -> Kind {
+        match *k {
+            Kind::Star => Kind::Star,
+            Kind::Arrow(ref a, ref b) => Kind::new_arrow(self.subst(a), self.subst(b)),
+            Kind::Var(v) => match self.kind_var_map.get(&v) {
+                Some(k2) => self.subst(k2),
+                None => k.clone(),
+            },
+        }
+    }
+
+    /// Apply the substitutions of kind variables to `k`, and default unresolved
+    /// variables to `*`
+    fn subst_default(&self, k: &Kind) -> Kind {
+        match self.subst(k) {
+            Kind::Arrow(a, b) => Kind::new_arrow(self.subst_default(&a), self.subst_default(&b)),
+            _ => Kind::Star,
+        }
+    }
+
+    fn occurs_in(&self, v: u64, k: &Kind) -> bool {
+        match self.subst(k) {
+            Kind::Star => false,
+            Kind::Arrow(a, b) => self.occurs_in(v, &a) || self.occurs_in(v, &b),
+            Kind::Var(w) => v == w,
+        }
+    }
+
+    /// Unify two kinds. On failure, returns the kinds after substitution
+    fn unify(&mut self, k1: &Kind, k2: &Kind) -> Result<(), (Kind, Kind)> {
+        match (self.subst(k1), self.subst(k2)) {
+            (Kind::Star, Kind::Star) => Ok(()),
+            (Kind::Var(v), Kind::Var(w)) if v == w => Ok(()),
+            (Kind::Var(v), k) | (k, Kind::Var(v)) => if self.occurs_in(v, &k) {
+                Err((Kind::Var(v), k))
+            } else {
+                self.kind_var_map.insert(v, k);
+                Ok(())
+            },
+            (Kind::Arrow(a1, b1), Kind::Arrow(a2, b2)) => {
+                self.unify(&a1, &a2)?;
+                self.unify(&b1, &b2)
+            }
+            (k1, k2) => Err((k1, k2)),
+        }
+    }
+
+    fn kind_of_const(&self, c: &'s str, pos: &Option<SrcPos<'s>>) -> Result<Kind, KindErr<'s>> {
+        match c {
+            "->" | "Cons" => Ok(Kind::new_star_arrows(2)),
+            "Ptr" | "Unique" => Ok(Kind::new_star_arrows(1)),
+            _ if PRIMITIVE_TYPES.contains(&c) => Ok(Kind::Star),
+            _ => self.adt_kinds
+                .get(c)
+                .cloned()
+                .ok_or_else(|| KindErr::Undefined(c, pos.clone())),
+        }
+    }
+
+    /// Returns the number of type arguments the type constructor `c` takes to make a proper
+    /// type
+    fn arity_of_const(&self, c: &'s str) -> usize {
+        fn arity(k: Kind) -> usize {
+            match k {
+                Kind::Arrow(_, b) => 1 + arity(*b),
+                _ => 0,
+            }
+        }
+        self.kind_of_const(c, &None)
+            .map(|k| arity(self.subst(&k)))
+            .unwrap_or(0)
+    }
+
+    /// If `t` is a type constructor applied to too few arguments, like `(Cons Int64)`, returns
+    /// the corresponding arity error
+    fn under_applied(&self, t: &Type<'s>) -> Option<KindErr<'s>> {
+        let (c, n_args) = match *t {
+            Type::Const(c, _) => (c, 0),
+            Type::App(box TypeFunc::Const(c), ref args) => (c, args.len()),
+            Type::Poly(ref p) => return self.under_applied(&p.body),
+            _ => return None,
+        };
+        let arity = self.arity_of_const(c);
+        if n_args < arity {
+            Some(KindErr::Arity(c, arity, n_args))
+        } else {
+            None
+        }
+    }
+
+    fn kind_of_type_var(&mut self, tv: TVar<'s>, env: &mut BTreeMap<TVar<'s>, Kind>) -> Kind {
+        if let Some(k) = env.get(&tv) {
+            return k.clone();
+        }
+        let k = self.gen_kind_var();
+        env.insert(tv, k.clone());
+        k
+    }
+
+    fn kind_of_poly(
+        &mut self,
+        p: &Poly<'s>,
+        env: &mut BTreeMap<TVar<'s>, Kind>,
+    ) -> Result<Kind, KindErr<'s>> {
+        let mut shadoweds = Vec::new();
+        for (&tv, constrs) in &p.params {
+            let k = self.gen_kind_var();
+            for class in constrs {
+                let class_kind = self.class_kinds.get(class).cloned().unwrap_or(Kind::Star);
+                self.unify(&k, &class_kind)?;
+            }
+            shadoweds.push((tv, env.insert(tv, k)));
+        }
+        let k = self.kind_of(&p.body, env);
+        for (tv, shadowed) in shadoweds {
+            match shadowed {
+                Some(k2) => env.insert(tv, k2),
+                None => env.remove(&tv),
+            };
+        }
+        k
+    }
+
+    /// Infer the kind of type `t`, with the kinds of type variables in `env`
+    fn kind_of(
+        &mut self,
+        t: &Type<'s>,
+        env: &mut BTreeMap<TVar<'s>, Kind>,
+    ) -> Result<Kind, KindErr<'s>> {
+        match *t {
+            Type::Var(tv) => Ok(self.kind_of_type_var(tv, env)),
+            Type::Const(c, ref pos) => self.kind_of_const(c, pos),
+            Type::App(ref f, ref args) => {
+                let mut f_kind = match **f {
+                    TypeFunc::Const(c) => self.kind_of_const(c, &None)?,
+                    TypeFunc::Var(tv) => self.kind_of_type_var(tv, env),
+                    TypeFunc::Poly(ref p) => return self.kind_of_poly(p, env),
+                };
+                for arg in args {
+                    let arg_kind = self.kind_of(arg, env)?;
+                    let result_kind = self.gen_kind_var();
+                    let res = self.unify(&f_kind, &Kind::new_arrow(arg_kind, result_kind.clone()));
+                    if let Err(e) = res {
+                        // Describe the common mistakes of applying a type constructor to too
+                        // many arguments, or passing one applied to too few
+                        return Err(match **f {
+                            TypeFunc::Const(c) if args.len() > self.arity_of_const(c) => {
+                                KindErr::Arity(c, self.arity_of_const(c), args.len())
+                            }
+                            _ => self.under_applied(arg).unwrap_or(e.into()),
+                        });
+                    }
+                    f_kind = result_kind;
+                }
+                Ok(f_kind)
+            }
+            Type::Poly(ref p) => self.kind_of_poly(p, env),
+        }
+    }
+
+    /// Exit with an error describing the kind error `e` in type `t`
+    fn report(&self, e: KindErr<'s>, t: &Type<'s>, pos: &SrcPos) -> ! {
+        match e {
+            KindErr::Mismatch(found, expected) => pos.error_exit(format!(
+                "Kind mismatch in type `{}`. Expected kind `{}`, found `{}`",
+                t,
+                self.subst(&expected),
+                self.subst(&found)
+            )),
+            KindErr::Arity(c, expected, found) => pos.error_exit(format!(
+                "Type constructor `{}` in type `{}` expects {} type argument{}, found {}",
+                c,
+                t,
+                expected,
+                if expected == 1 { "" } else { "s" },
+                found
+            )),
+            KindErr::Undefined(c, Some(ref c_pos)) => {
+                c_pos.error_exit(format!("Type `{}` not found in this scope", c))
+            }
+            KindErr::Undefined(c, None) => pos.error_exit(format!(
+                "Type `{}` not found in this scope, in type `{}`",
+                c, t
+            )),
+        }
+    }
+
+    /// Check that `t` is a well-formed type of any kind
+    fn check_type(&mut self, t: &Type<'s>, env: &mut BTreeMap<TVar<'s>, Kind>, pos: &SrcPos) {
+        if let Err(e) = self.kind_of(t, env) {
+            self.report(e, t, pos)
+        }
+    }
+
+    /// Check that `t` is a proper type, i.e. of kind `*`
+    fn check_proper_type(
+        &mut self,
+        t: &Type<'s>,
+        env: &mut BTreeMap<TVar<'s>, Kind>,
+        pos: &SrcPos,
+    ) {
+        let res = self.kind_of(t, env).and_then(|k| {
+            self.unify(&k, &Kind::Star)
+                .map_err(|e| self.under_applied(t).unwrap_or(e.into()))
+        });
+        if let Err(e) = res {
+            self.report(e, t, pos)
+        }
+    }
+
+    fn check_poly(&mut self, p: &Poly<'s>, pos: &SrcPos) {
+        self.check_proper_type(&Type::Poly(Box::new(p.clone())), &mut BTreeMap::new(), pos)
+    }
+
+    /// Infer the kinds of all data types
+    ///
+    /// As data types may be mutually recursive, they are inferred together. Parameters
+    /// of unconstrained kind default to `*`.
+    fn infer_adt_kinds(&mut self, adts: &Adts<'s>) {
+        let mut params_kinds = BTreeMap::new();
+        for (&name, adt) in &adts.defs {
+            let param_kinds = adt.params
+                .iter()
+                .map(|&p| (TVar::Explicit(p), self.gen_kind_var()))
+                .collect::<BTreeMap<_, _>>();
+            let kind = adt.params
+                .iter()
+                .rev()
+                .fold(Kind::Star, |acc, &p| {
+                    Kind::new_arrow(param_kinds[&TVar::Explicit(p)].clone(), acc)
+                });
+            self.adt_kinds.insert(name, kind);
+            params_kinds.insert(name, param_kinds);
+        }
+        for (name, adt) in &adts.defs {
+            let env = params_kinds.get_mut(name).unwrap();
+            for variant in &adt.variants {
+                for member in &variant.members {
+                    self.check_proper_type(member, env, &adt.pos)
+                }
+            }
+        }
+        let defaulted = self.adt_kinds
+            .iter()
+            .map(|(&name, k)| (name, self.subst_default(k)))
+            .collect();
+        self.adt_kinds = defaulted;
+    }
+
+    /// Infer the kinds of the parameters of all classes, from the method declarations
+    fn infer_class_kinds(&mut self, classes: &Classes<'s>) {
+        for (&name, class) in &classes.defs {
+            let k = self.gen_kind_var();
+            self.class_kinds.insert(name, k);
+        }
+        for class in classes.defs.values() {
+            for method in class.methods.values() {
+                self.check_poly(&method.sig, &method.pos)
+            }
+        }
+        let defaulted = self.class_kinds
+            .iter()
+            .map(|(&name, k)| (name, self.subst_default(k)))
+            .collect();
+        self.class_kinds = defaulted;
+    }
+
+    fn check_binding(&mut self, binding: &Binding<'s>) {
+        self.check_poly(&binding.sig, &binding.pos);
+        self.check_expr(&binding.val)
+    }
+
+    fn check_expr(&mut self, e: &Expr<'s>) {
+        match *e {
+            Expr::App(ref app) => {
+                self.check_expr(&app.func);
+                self.check_expr(&app.arg);
+            }
+            Expr::If(ref cond) => {
+                self.check_expr(&cond.predicate);
+                self.check_expr(&cond.consequent);
+                self.check_expr(&cond.alternative);
+            }
+            Expr::Lambda(ref lam) => {
+                self.check_proper_type(&lam.typ, &mut BTreeMap::new(), &lam.pos);
+                self.check_expr(&lam.body)
+            }
+            Expr::Let(ref l) => {
+                for binding in l.bindings.bindings() {
+                    self.check_binding(binding)
+                }
+                self.check_expr(&l.body)
+            }
+            Expr::TypeAscript(ref a) => {
+                self.check_proper_type(&a.typ, &mut BTreeMap::new(), &a.pos);
+                self.check_expr(&a.expr)
+            }
+            Expr::Cons(ref c) => {
+                self.check_expr(&c.car);
+                self.check_expr(&c.cdr);
+            }
+            Expr::Car(ref c) => self.check_expr(&c.expr),
+            Expr::Cdr(ref c) => self.check_expr(&c.expr),
+            Expr::Cast(ref c) => {
+                self.check_proper_type(&c.typ, &mut BTreeMap::new(), &c.pos);
+                self.check_expr(&c.expr)
+            }
+            Expr::New(ref n) => for member in &n.members {
+                self.check_expr(member)
+            },
+            Expr::Match(ref m) => {
+                self.check_expr(&m.expr);
+                for case in &m.cases {
+                    if let Some(ref guard) = case.guard {
+                        self.check_expr(guard)
+                    }
+                    self.check_expr(&case.body)
+                }
+            }
+            Expr::Variable(ref var) => for t in &var.type_args {
+                self.check_type(t, &mut BTreeMap::new(), &var.ident.pos)
+            },
+            Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) | Expr::Hole(_) => (),
+        }
+    }
+
+    /// Check that the head of `inst` is of the kind of the parameter of the class
+    fn check_instance(&mut self, inst: &InstanceDef<'s>) {
+        let class_kind = self.class_kinds
+            .get(inst.class.s)
+            .cloned()
+            .unwrap_or(Kind::Star);
+        let res = self.kind_of(&inst.head, &mut BTreeMap::new())
+            .and_then(|k| self.unify(&k, &class_kind).map_err(KindErr::from));
+        match res {
+            Ok(()) => (),
+            Err(KindErr::Mismatch(..)) => inst.pos.error_exit(format!(
+                "Kind mismatch in instance head `{}`. Class `{}` expects a type of kind `{}`",
+                inst.head, inst.class, class_kind
+            )),
+            Err(e) => self.report(e, &inst.head, &inst.pos),
+        }
+        for method in inst.methods.values() {
+            self.check_binding(method)
+        }
+    }
+}
+
+/// Infer the kinds of all type variables, data types, and classes, and check that all
+/// types in the program are well-kinded
+pub fn check_kinds<'s>(ast: &Ast<'s>) {
+    let mut inferrer = KindInferrer::new();
+    inferrer.infer_adt_kinds(&ast.adts);
+    inferrer.infer_class_kinds(&ast.classes);
+    for decl in ast.externs.values() {
+        inferrer.check_proper_type(&decl.typ, &mut BTreeMap::new(), &decl.pos)
+    }
+    for binding in ast.globals.bindings() {
+        inferrer.check_binding(binding)
+    }
+    for class in ast.classes.defs.values() {
+        for binding in class.defaults.values() {
+            inferrer.check_binding(binding)
+        }
+    }
+    for inst in ast.classes.instances.values() {
+        inferrer.check_instance(inst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lib::collections::AddMap;
+    use lib::front::TypeVarGen;
+    use lib::front::parse::parse_test_program;
+    use std::collections::BTreeMap;
+    use super::{Kind, KindErr, KindInferrer};
+
+    #[test]
+    fn test_infer_adt_kinds() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = parse_test_program(
+            "(data (Fix f) (In (f (Fix f))))
+             (data (Pair a b) (Pair a b))
+             (data (Phantom a) Phantom)",
+            &sources,
+            &mut tvg,
+        );
+        let mut inferrer = KindInferrer::new();
+        inferrer.infer_adt_kinds(&ast.adts);
+        assert_eq!(
+            inferrer.adt_kinds["Fix"],
+            Kind::new_arrow(Kind::new_star_arrows(1), Kind::Star)
+        );
+        assert_eq!(inferrer.adt_kinds["Pair"], Kind::new_star_arrows(2));
+        // Unconstrained parameters default to `*`
+        assert_eq!(inferrer.adt_kinds["Phantom"], Kind::new_star_arrows(1));
+    }
+
+    #[test]
+    fn test_infer_class_kinds() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = parse_test_program(
+            "(class (Functor f)
+               (: fmap (-> (-> a b) (f a) (f b))))",
+            &sources,
+            &mut tvg,
+        );
+        let mut inferrer = KindInferrer::new();
+        inferrer.infer_adt_kinds(&ast.adts);
+        inferrer.infer_class_kinds(&ast.classes);
+        assert_eq!(inferrer.class_kinds["Functor"], Kind::new_star_arrows(1));
+    }
+
+    #[test]
+    fn test_arity_and_undefined_errors() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = parse_test_program(
+            "(data (Pair a b) (Pair a b))
+             (extern too-many (Pair Int64 Int64 Int64))
+             (extern too-few (Ptr (Pair Int64)))
+             (extern undefined Foo)",
+            &sources,
+            &mut tvg,
+        );
+        let mut inferrer = KindInferrer::new();
+        inferrer.infer_adt_kinds(&ast.adts);
+        let mut kind_of = |name| inferrer.kind_of(&ast.externs[name].typ, &mut BTreeMap::new());
+        match kind_of("too-many") {
+            Err(KindErr::Arity("Pair", 2, 3)) => (),
+            _ => panic!("expected too many type arguments to `Pair`"),
+        }
+        match kind_of("too-few") {
+            Err(KindErr::Arity("Pair", 2, 1)) => (),
+            _ => panic!("expected too few type arguments to `Pair`"),
+        }
+        match kind_of("undefined") {
+            Err(KindErr::Undefined("Foo", Some(_))) => (),
+            _ => panic!("expected undefined type `Foo`"),
+        }
+    }
+}
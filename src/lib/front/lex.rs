@@ -299,7 +299,7 @@ fn tokens_to_trees_until<'s>(
 }
 
 /// Lex the source code as a Concrete Syntax Tree
-fn lex_src<'s>(filename: &'s Path, src: &'s str) -> Vec<Cst<'s>> {
+pub fn lex_src<'s>(filename: &'s Path, src: &'s str) -> Vec<Cst<'s>> {
     tokens_to_trees_until(
         &mut Tokens {
             filename,
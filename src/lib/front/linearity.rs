@@ -0,0 +1,498 @@
+//! Linearity checking of values containing a `RealWorld`, and affinity checking of values of
+//! unique types
+//!
+//! IO is modeled by threading a `RealWorld` through every impure operation. For this to keep
+//! the program referentially transparent, a `RealWorld` must be consumed exactly once. Using
+//! one twice would allow observing the world both before and after an effect, and dropping one
+//! would allow effects to be reordered or discarded.
+//!
+//! A value is *linear* if its type contains a `RealWorld`. Every linear variable must be used
+//! exactly once along every control-flow path of its scope. As an exception, projecting a
+//! non-linear component out of a linear pair variable with `car` or `cdr` does not consume it,
+//! so that the result of an IO action can be inspected before the world is passed on.
+//!
+//! A value is *affine* if its type contains a `(Unique T)`. Every affine variable may be used
+//! at most once, so that a unique value is never referenced from more than one place, and may
+//! be updated in place when consumed.
+
+use lib::front::ast::*;
+use lib::front::{exit, SrcPos};
+use std::cmp::max;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::once;
+
+/// The number of times a value may be used
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Usage {
+    /// Any number of times
+    Unrestricted,
+    /// At most once
+    Affine,
+    /// Exactly once
+    Linear,
+}
+
+/// The restricted variables consumed by an expression, where they were consumed, and how
+/// they are restricted
+type Uses<'s> = BTreeMap<&'s str, (SrcPos<'s>, Usage)>;
+
+struct Checker<'a, 's: 'a> {
+    adts: &'a Adts<'s>,
+}
+
+impl<'a, 's: 'a> Checker<'a, 's> {
+    /// Returns how many times values of type `t` may be used
+    ///
+    /// Functions are unrestricted, as a closure may be called any number of times. Type
+    /// variables only remain in the generic versions of polymorphic definitions, and are
+    /// treated as unrestricted. The monomorphic instantiations are checked separately.
+    fn usage(&self, t: &Type<'s>) -> Usage {
+        self.usage_in(t, &mut BTreeSet::new())
+    }
+
+    fn is_linear(&self, t: &Type<'s>) -> bool {
+        self.usage(t) == Usage::Linear
+    }
+
+    fn usage_in(&self, t: &Type<'s>, visiting: &mut BTreeSet<&'s str>) -> Usage {
+        match *t {
+            Type::Const("RealWorld", _) => Usage::Linear,
+            Type::Const(name, _) => self.adt_usage(name, &[], visiting),
+            Type::App(box TypeFunc::Const("->"), _) => Usage::Unrestricted,
+            Type::App(box TypeFunc::Const("Unique"), ref args) => {
+                max(Usage::Affine, self.usage_in(&args[0], visiting))
+            }
+            Type::App(box TypeFunc::Const(name), ref args) => {
+                if self.adts.defs.contains_key(name) {
+                    self.adt_usage(name, args, visiting)
+                } else {
+                    args.iter()
+                        .map(|arg| self.usage_in(arg, visiting))
+                        .max()
+                        .unwrap_or(Usage::Unrestricted)
+                }
+            }
+            _ => Usage::Unrestricted,
+        }
+    }
+
+    fn adt_usage(
+        &self,
+        name: &'s str,
+        inst: &[Type<'s>],
+        visiting: &mut BTreeSet<&'s str>,
+    ) -> Usage {
+        let adt = match self.adts.defs.get(name) {
+            Some(adt) => adt,
+            None => return Usage::Unrestricted,
+        };
+        // A recursive occurence can't contribute anything not already found in the
+        // outer occurence
+        if !visiting.insert(name) {
+            return Usage::Unrestricted;
+        }
+        let mut usage = Usage::Unrestricted;
+        for variant in &adt.variants {
+            let members = self.adts
+                .members_with_inst_of_variant(variant, inst)
+                .expect("ICE: members_with_inst_of_variant failed in adt_usage");
+            for member in &members {
+                usage = max(usage, self.usage_in(member, visiting))
+            }
+        }
+        visiting.remove(name);
+        usage
+    }
+
+    /// Combine the uses of two expressions evaluated in sequence
+    fn seq(&self, mut first: Uses<'s>, second: Uses<'s>) -> Uses<'s> {
+        for (var, (pos, usage)) in second {
+            if let Some(&(ref prev, _)) = first.get(var) {
+                pos.error(format!(
+                    "{} variable `{}` is used more than once",
+                    usage_name(usage),
+                    var
+                ));
+                prev.print_note("First used here");
+                exit()
+            }
+            first.insert(var, (pos, usage));
+        }
+        first
+    }
+
+    /// Combine the uses of alternative branches, of which exactly one will be evaluated
+    ///
+    /// All branches must consume the same linear variables. An affine variable is considered
+    /// consumed if any branch consumes it.
+    fn branches(&self, arms: Vec<(Uses<'s>, &SrcPos<'s>)>) -> Uses<'s> {
+        let mut arms = arms.into_iter();
+        let (mut merged, merged_pos) = match arms.next() {
+            Some(arm) => arm,
+            None => return Uses::new(),
+        };
+        for (uses, pos) in arms {
+            let missing_from_other = |a: &Uses<'s>, b: &Uses<'s>, b_pos: &SrcPos<'s>| {
+                let missing = a.iter()
+                    .find(|&(var, &(_, usage))| usage == Usage::Linear && !b.contains_key(var));
+                if let Some((var, &(ref use_pos, _))) = missing {
+                    b_pos.error(format!(
+                        "Linear variable `{}` is not consumed in this branch, \
+                         but is in another",
+                        var
+                    ));
+                    use_pos.print_note("Consumed here");
+                    exit()
+                }
+            };
+            missing_from_other(&merged, &uses, pos);
+            missing_from_other(&uses, &merged, merged_pos);
+            for (var, use_) in uses {
+                merged.entry(var).or_insert(use_);
+            }
+        }
+        merged
+    }
+
+    /// Check that a linear variable bound by `ident` is consumed in the scope of `uses`, and
+    /// remove the variable from the uses of the enclosing scope
+    fn bind(&self, uses: &mut Uses<'s>, ident: &Ident<'s>, typ: &Type<'s>) {
+        if uses.remove(ident.s).is_none() && self.is_linear(typ) {
+            ident.pos.error_exit(format!(
+                "Linear variable `{}` is never used. A value of type `{}` contains a \
+                 `RealWorld`, and must be consumed exactly once",
+                ident.s, typ
+            ))
+        }
+    }
+
+    /// Check a projection of `pair`. If `pair` is a variable and the projected component is
+    /// unrestricted, this is merely a peek and does not consume the variable
+    fn check_projection(&self, pair: &Expr<'s>, is_car: bool, pos: &SrcPos<'s>) -> Uses<'s> {
+        if let Some((car_typ, cdr_typ)) = pair.get_type().get_cons() {
+            let (proj_typ, rest_typ) = if is_car {
+                (car_typ, cdr_typ)
+            } else {
+                (cdr_typ, car_typ)
+            };
+            match *pair {
+                Expr::Variable(_) if self.usage(proj_typ) == Usage::Unrestricted => {
+                    return Uses::new()
+                }
+                _ if self.is_linear(rest_typ) => pos.error_exit(format!(
+                    "Projection drops the {} of the pair, which is of type `{}` and \
+                     contains a `RealWorld`",
+                    if is_car { "cdr" } else { "car" },
+                    rest_typ
+                )),
+                _ => (),
+            }
+        }
+        self.check_expr(pair)
+    }
+
+    /// Check that no linear part of a value matched by `patt` is dropped by the pattern
+    fn check_pattern(&self, patt: &Pattern<'s>, typ: &Type<'s>) {
+        match *patt {
+            Pattern::Wild(ref pos) => if self.is_linear(typ) {
+                pos.error_exit(format!(
+                    "Wildcard pattern drops a value of type `{}`, which contains a `RealWorld`",
+                    typ
+                ))
+            },
+            Pattern::Nil(_) | Pattern::NumLit(_) | Pattern::StrLit(_) | Pattern::Variable(_) => (),
+            Pattern::Deconstr(ref d) => {
+                let inst = typ.get_adt_inst_args().unwrap_or(&[]);
+                let members = self.adts
+                    .members_with_inst_of_variant_with_name(d.constr.s, inst)
+                    .expect("ICE: members_with_inst_of_variant_with_name failed in check_pattern");
+                for (subpatt, member) in d.subpatts.iter().zip(&members) {
+                    self.check_pattern(subpatt, member)
+                }
+            }
+            Pattern::As(ref a) => if self.usage(typ) != Usage::Unrestricted {
+                let restricted = a.patt
+                    .variables()
+                    .into_iter()
+                    .find(|v| self.usage(&v.typ) != Usage::Unrestricted);
+                if let Some(var) = restricted {
+                    a.pos.error_exit(format!(
+                        "As-pattern binds a value of type `{}` both as a whole and by its \
+                         part `{}`, but it may only be used once",
+                        typ, var.ident.s
+                    ))
+                }
+            } else {
+                self.check_pattern(&a.patt, typ)
+            },
+            Pattern::Or(ref o) => for alt in &o.alts {
+                self.check_pattern(alt, typ)
+            },
+            Pattern::Cons(ref c) => if let Some((car_typ, cdr_typ)) = typ.get_cons() {
+                self.check_pattern(&c.car, car_typ);
+                self.check_pattern(&c.cdr, cdr_typ);
+            },
+        }
+    }
+
+    fn check_match(&self, m: &Match<'s>) -> Uses<'s> {
+        let matchee = self.check_expr(&m.expr);
+        let arms = m.cases
+            .iter()
+            .map(|case| {
+                self.check_pattern(&case.patt, &case.patt_typ);
+                // A guard is evaluated before we know whether the arm is taken, so it must
+                // not consume anything
+                if let Some(ref guard) = case.guard {
+                    if let Some((var, (pos, usage))) = self.check_expr(guard).into_iter().next() {
+                        pos.error_exit(format!(
+                            "{} variable `{}` is consumed in a guard. The guard may fail, \
+                             and the variable then be consumed again by another arm",
+                            usage_name(usage),
+                            var
+                        ))
+                    }
+                }
+                let mut uses = self.check_expr(&case.body);
+                for var in case.patt.variables() {
+                    self.bind(&mut uses, &var.ident, &var.typ)
+                }
+                (uses, &case.pos)
+            })
+            .collect();
+        let arms_uses = self.branches(arms);
+        self.seq(matchee, arms_uses)
+    }
+
+    fn check_lambda(&self, lam: &Lambda<'s>) -> Uses<'s> {
+        let mut uses = self.check_expr(&lam.body);
+        if let Some((param_typ, _)) = lam.typ.get_func() {
+            self.bind(&mut uses, &lam.param_ident, param_typ)
+        }
+        if let Some((var, (pos, usage))) = uses.into_iter().next() {
+            pos.error(format!(
+                "{} variable `{}` is captured by a closure",
+                usage_name(usage),
+                var
+            ));
+            lam.pos.print_note(
+                "A closure may be called any number of times, \
+                 so it cannot capture a value that may only be used once",
+            );
+            exit()
+        }
+        Uses::new()
+    }
+
+    /// Check the value of a binding and its monomorphic instantiations
+    ///
+    /// Returns the uses of the generic value, as the instantiations only differ in types.
+    fn check_binding(&self, binding: &Binding<'s>) -> Uses<'s> {
+        for inst in binding.mono_insts.values() {
+            self.check_expr(inst);
+        }
+        self.check_expr(&binding.val)
+    }
+
+    fn check_let(&self, l: &Let<'s>) -> Uses<'s> {
+        let mut uses = Uses::new();
+        for binding in l.bindings.bindings() {
+            let binding_uses = self.check_binding(binding);
+            uses = self.seq(uses, binding_uses);
+        }
+        let body_uses = self.check_expr(&l.body);
+        uses = self.seq(uses, body_uses);
+        for binding in l.bindings.bindings() {
+            self.bind(&mut uses, &binding.ident, &binding.sig.body)
+        }
+        uses
+    }
+
+    fn check_exprs<'e, I>(&self, es: I) -> Uses<'s>
+    where
+        I: IntoIterator<Item = &'e Expr<'s>>,
+        's: 'e,
+    {
+        es.into_iter()
+            .fold(Uses::new(), |uses, e| {
+                let e_uses = self.check_expr(e);
+                self.seq(uses, e_uses)
+            })
+    }
+
+    /// Returns the linear variables consumed by `e`
+    fn check_expr(&self, e: &Expr<'s>) -> Uses<'s> {
+        match *e {
+            Expr::Variable(ref var) => match self.usage(&var.typ) {
+                Usage::Unrestricted => Uses::new(),
+                usage => once((var.ident.s, (var.ident.pos.clone(), usage))).collect(),
+            },
+            Expr::App(ref app) => self.check_exprs(vec![&app.func, &app.arg]),
+            Expr::If(ref cond) => {
+                let pred_uses = self.check_expr(&cond.predicate);
+                let arms = vec![
+                    (self.check_expr(&cond.consequent), cond.consequent.pos()),
+                    (self.check_expr(&cond.alternative), cond.alternative.pos()),
+                ];
+                let arms_uses = self.branches(arms);
+                self.seq(pred_uses, arms_uses)
+            }
+            Expr::Lambda(ref lam) => self.check_lambda(lam),
+            Expr::Let(ref l) => self.check_let(l),
+            Expr::TypeAscript(ref a) => self.check_expr(&a.expr),
+            Expr::Cons(ref c) => self.check_exprs(vec![&c.car, &c.cdr]),
+            Expr::Car(ref c) => self.check_projection(&c.expr, true, &c.pos),
+            Expr::Cdr(ref c) => self.check_projection(&c.expr, false, &c.pos),
+            Expr::Cast(ref c) => self.check_expr(&c.expr),
+            Expr::New(ref n) => self.check_exprs(&n.members),
+            Expr::Match(ref m) => self.check_match(m),
+            Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) | Expr::Hole(_) => {
+                Uses::new()
+            }
+        }
+    }
+}
+
+fn usage_name(usage: Usage) -> &'static str {
+    match usage {
+        Usage::Unrestricted => "Unrestricted",
+        Usage::Affine => "Unique",
+        Usage::Linear => "Linear",
+    }
+}
+
+/// Check that every value containing a `RealWorld` is consumed exactly once, and every value
+/// of a unique type at most once, along every control-flow path
+///
+/// Must be done after monomorphization, as whether a value is linear may depend on the
+/// instantiation of a polymorphic definition.
+pub fn check_linearity<'s>(ast: &Ast<'s>) {
+    let checker = Checker { adts: &ast.adts };
+    for binding in ast.globals.bindings() {
+        checker.check_binding(binding);
+    }
+    for class in ast.classes.defs.values() {
+        for binding in class.defaults.values() {
+            checker.check_binding(binding);
+        }
+    }
+    for inst in ast.classes.instances.values() {
+        for binding in inst.methods.values() {
+            checker.check_binding(binding);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lib::collections::AddMap;
+    use lib::front::TypeVarGen;
+    use lib::front::ast::*;
+    use lib::front::inference::infer_types;
+    use lib::front::parse::parse_test_program;
+    use super::{Checker, Usage};
+
+    /// Returns the body of the innermost lambda of the global function `name`
+    fn body_of<'a, 's>(ast: &'a Ast<'s>, name: &str) -> &'a Expr<'s> {
+        let binding = ast.globals
+            .bindings()
+            .find(|b| b.ident.s == name)
+            .expect("binding not found");
+        let mut e = &binding.val;
+        while let Expr::Lambda(ref lam) = *e {
+            e = &lam.body;
+        }
+        e
+    }
+
+    #[test]
+    fn test_is_linear() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = parse_test_program(
+            "(data World (World RealWorld Int64))
+             (data (Box a) (Box a))
+             (extern world World)
+             (extern boxed-world (Box RealWorld))
+             (extern boxed-int (Box Int64))
+             (extern pair (Cons Int64 RealWorld))
+             (extern action (-> RealWorld RealWorld))",
+            &sources,
+            &mut tvg,
+        );
+        let checker = Checker { adts: &ast.adts };
+        let is_linear = |name: &str| checker.is_linear(&ast.externs[name].typ);
+        assert!(is_linear("world"));
+        assert!(is_linear("boxed-world"));
+        assert!(!is_linear("boxed-int"));
+        assert!(is_linear("pair"));
+        // A closure may be called any number of times, even if it threads a `RealWorld`
+        assert!(!is_linear("action"));
+    }
+
+    #[test]
+    fn test_consumed_variables() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(extern print_int64 (-> (Cons Int64 RealWorld) (Cons Nil RealWorld)))
+             (define (print n w) (print_int64 (cons n w)))
+             (define: (peek p) (-> (Cons Int64 RealWorld) (Cons Int64 RealWorld))
+               (cons (car p) (cdr p)))",
+            &sources,
+            &mut tvg,
+        );
+        infer_types(&mut ast, &mut tvg, false);
+        let checker = Checker { adts: &ast.adts };
+        let consumed = |name: &str| {
+            checker
+                .check_expr(body_of(&ast, name))
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(consumed("print"), vec!["w"]);
+        // Projecting out the `Int64` only peeks at `p`, which is then consumed by the `cdr`
+        assert_eq!(consumed("peek"), vec!["p"]);
+    }
+
+    #[test]
+    fn test_usage() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = parse_test_program(
+            "(data Ints Done (More Int64 (Unique Ints)))
+             (extern unique-int (Unique Int64))
+             (extern ints Ints)
+             (extern int Int64)
+             (extern pair (Cons (Unique Int64) RealWorld))",
+            &sources,
+            &mut tvg,
+        );
+        let checker = Checker { adts: &ast.adts };
+        let usage = |name: &str| checker.usage(&ast.externs[name].typ);
+        assert_eq!(usage("unique-int"), Usage::Affine);
+        assert_eq!(usage("ints"), Usage::Affine);
+        assert_eq!(usage("int"), Usage::Unrestricted);
+        // The most restrictive usage of the parts is the usage of the whole
+        assert_eq!(usage("pair"), Usage::Linear);
+    }
+
+    #[test]
+    fn test_branches_merge_affine_uses() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut ast = parse_test_program(
+            "(define: (pick b u v) (-> Bool (Unique Int64) (Unique Int64) (Unique Int64))
+               (if b u v))",
+            &sources,
+            &mut tvg,
+        );
+        infer_types(&mut ast, &mut tvg, false);
+        let checker = Checker { adts: &ast.adts };
+        // Unlike linear variables, affine variables may be dropped in a branch
+        let uses = checker.check_expr(body_of(&ast, "pick"));
+        assert_eq!(uses.keys().cloned().collect::<Vec<_>>(), vec!["u", "v"]);
+        assert!(uses.values().all(|&(_, usage)| usage == Usage::Affine));
+    }
+}
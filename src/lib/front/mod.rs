@@ -18,8 +18,12 @@ pub mod cst;
 pub mod lex;
 pub mod dependency_graph;
 pub mod ast;
+pub mod import_graph;
 pub mod parse;
 pub mod inference;
+pub mod kinds;
+pub mod exhaustiveness;
+pub mod linearity;
 pub mod monomorphization;
 pub mod substitution;
 mod macros;
@@ -125,6 +129,11 @@ impl<'src> SrcPos<'src> {
         }
     }
 
+    /// The path of the source file of the position
+    pub fn filename(&self) -> &'src Path {
+        self.filename
+    }
+
     /// [0 .. 10].after([0 .. 3]) = [4 .. 10]
     /// [0 .. 10].after([2 .. 4]) = [5 .. 10]
     fn after(&self, child: &Self) -> Self {
@@ -1,38 +1,244 @@
-use itertools::zip;
-use std::collections::BTreeMap;
+use itertools::{zip, Itertools};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path;
+use lib::set_of;
 use lib::collections::*;
 use lib::front::*;
 use lib::front::ast::*;
 use lib::front::substitution::*;
 
+/// How ambiguous types, left polymorphic in monomorphic code after inference, are defaulted
+pub struct Defaulting<'src> {
+    /// The type to default to. `Int64` unless declared with `(default T)`
+    pub typ: Type<'src>,
+    /// Whether defaulting is an error, as with `--deny-defaulting`
+    pub deny: bool,
+    /// The uses that have already been warned about
+    warned: BTreeSet<SrcPos<'src>>,
+}
+
+impl<'src> Defaulting<'src> {
+    pub fn new(typ: Type<'src>, deny: bool) -> Self {
+        Defaulting {
+            typ,
+            deny,
+            warned: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the default type for an ambiguous type in the use of `var`, that must fulfill
+    /// the class constraints `constrs`
+    ///
+    /// The declared default type is used if it fulfills the constraints, otherwise `Int64` or
+    /// `Float64`. E.g. with `(default Float64)`, an ambiguous type constrained by `Integral`
+    /// is defaulted to `Int64`. Warns about the defaulting at the use site, or exits if
+    /// defaulting is denied or no default type fulfills the constraints.
+    fn default_for(
+        &mut self,
+        var: &Ident<'src>,
+        constrs: &BTreeSet<&'src str>,
+        classes: &Classes<'src>,
+    ) -> Type<'src> {
+        let candidates = [
+            self.typ.clone(),
+            Type::Const("Int64", None),
+            Type::Const("Float64", None),
+        ];
+        let typ = match candidates
+            .iter()
+            .find(|t| fulfills_constraints(t, constrs, classes))
+        {
+            Some(t) => t.clone(),
+            None => {
+                var.pos.error(format!(
+                    "Ambiguous type in the use of `{}`, constrained by {}, can not be defaulted",
+                    var.s,
+                    constrs.iter().map(|c| format!("`{}`", c)).join(", ")
+                ));
+                var.pos.print_help("Add a type annotation to choose the type");
+                exit()
+            }
+        };
+        let msg = format!(
+            "Ambiguous type in the use of `{}` defaulted to `{}`",
+            var.s, typ
+        );
+        let help = "Add a type annotation to choose the type, \
+                    or declare another default type like `(default Float64)`";
+        if self.deny {
+            var.pos.error(msg);
+            var.pos.print_help(help);
+            exit()
+        } else if self.warned.insert(var.pos.clone()) {
+            var.pos.print_warn(msg);
+            var.pos.print_help(help);
+        }
+        typ
+    }
+}
+
+/// Returns whether the monomorphic type `t` fulfills the class constraints `constrs`
+fn fulfills_constraints<'src>(
+    t: &Type<'src>,
+    constrs: &BTreeSet<&'src str>,
+    classes: &Classes<'src>,
+) -> bool {
+    constrs.iter().all(|&c| {
+        if is_builtin_class(c) && t.fulfills_constraints(&set_of(c)) {
+            true
+        } else {
+            classes.instance_for(c, t).is_some()
+        }
+    })
+}
+
+/// The maximum number of nested monomorphizations of the same variable
+///
+/// Polymorphic recursion where each recursive use is at a larger type, like over
+/// `(data (Nested a) (N a (Nested (List a))))`, would otherwise monomorphize forever.
+const MAX_INST_DEPTH: usize = 64;
+
+/// The chain of monomorphizations in progress, each generated from within the previous one
+///
+/// Each is of the identifier of the binding definition, the variable it's used through, and
+/// the instantiation.
+type InstChain<'src> = Vec<(Ident<'src>, Ident<'src>, Vec<Type<'src>>)>;
+
+/// Push the monomorphization of the binding defined as `def`, used through `var`, for the
+/// instantiation `ts` to the `chain`
+///
+/// Instantiations are counted per binding rather than per name, as unrelated bindings may
+/// shadow each other. Exits with an error if it makes for an unbounded chain of
+/// instantiations of the binding.
+fn push_inst<'src>(
+    chain: &mut InstChain<'src>,
+    def: &Ident<'src>,
+    var: &Ident<'src>,
+    ts: &[Type<'src>],
+) {
+    chain.push((def.clone(), var.clone(), ts.to_vec()));
+    let insts = chain
+        .iter()
+        .filter(|&&(ref d, _, _)| d == def)
+        .collect::<Vec<_>>();
+    if insts.len() > MAX_INST_DEPTH {
+        let show_inst = |&&(_, ref v, ref ts): &&(Ident<'src>, Ident<'src>, Vec<Type<'src>>)| {
+            format!("`{}` at `{}`", v.s, ts.iter().join(" "))
+        };
+        var.pos.error(format!(
+            "Unbounded chain of instantiations of `{}`, which is used at ever larger types",
+            var.s
+        ));
+        insts[0].1.pos.print_note(format!(
+            "Instantiating {} requires {}, which requires {}, and so on",
+            show_inst(&insts[0]),
+            show_inst(&insts[1]),
+            show_inst(&insts[2])
+        ));
+        var.pos.print_help(
+            "Polymorphic recursion can only be compiled if it is used at finitely many types",
+        );
+        exit()
+    }
+}
+
+/// Returns the definition of the class method `var`, from the instance of the class
+/// for the instantiation `ts` of the method signature `p`
+///
+/// If the instance does not define the method, the default definition of the class is used.
+fn resolve_method_def<'src>(
+    var: &Variable<'src>,
+    p: &Poly<'src>,
+    ts: &[Type<'src>],
+    classes: &Classes<'src>,
+) -> Expr<'src> {
+    let class = classes
+        .class_of_method(var.ident.s)
+        .expect("ICE: No class of method in resolve_method_def");
+    let mut s = zip(&p.params, ts)
+        .map(|((&param_v, _), t)| (param_v, t.clone()))
+        .collect::<BTreeMap<_, _>>();
+    let t = s[&TVar::Explicit(class.param)].clone();
+    let (inst, inst_s) = classes.instance_for(class.name.s, &t).unwrap_or_else(|| {
+        var.ident.pos.error_exit(format!(
+            "No instance of class `{}` for type `{}`",
+            class.name, t
+        ))
+    });
+    s.extend(inst_s);
+    let mut def = inst.methods
+        .get(var.ident.s)
+        .or_else(|| class.defaults.get(var.ident.s))
+        .expect("ICE: Method neither defined in instance nor by default")
+        .val
+        .clone();
+    subst_expr(&mut def, &mut s);
+    def
+}
+
+/// If `var` is the core operation of the builtin class `class`, instantiated for a type
+/// with an instance of the class, returns the definition of the operation from the instance
+fn resolve_builtin_method_def<'src>(
+    var: &Variable<'src>,
+    class: &str,
+    ts: &[Type<'src>],
+    classes: &Classes<'src>,
+) -> Option<Expr<'src>> {
+    let (inst, mut s) = classes.instance_for(class, ts.first()?)?;
+    let mut def = inst.methods[var.ident.s].val.clone();
+    subst_expr(&mut def, &mut s);
+    Some(def)
+}
+
 /// If `var` is an instantiation of a polymorphic value and monomorphization
 /// does not already exist for this instantiation type, generate a
 /// monomorphization and return the monomorphisized definition
 fn monomorphize_def_of_inst<'src>(
     var: &mut Variable<'src>,
     env: &mut ScopeStack<&str, Binding<'src>>,
+    classes: &Classes<'src>,
+    defaulting: &mut Defaulting<'src>,
 ) -> Option<(Vec<Type<'src>>, Expr<'src>)> {
     if let Type::App(ref f, ref mut ts) = var.typ {
         // In application of poly function to poly args, any type can be used
         // for the args during codegen, with the constraint that a Num arg must
         // be numeric. As such, default args of (application of poly function to poly args)
-        // to Int64
-        *ts = ts.iter()
-            .map(|t| {
-                if !t.is_monomorphic() {
-                    Type::Const("Int64", None)
-                } else {
-                    t.clone()
-                }
-            })
-            .collect::<Vec<_>>();
+        // to the default type, which is numeric
+        if ts.iter().any(|t| !t.is_monomorphic()) {
+            // The constraints on each type argument, as given by the parameters of the polytype
+            let constrs: Vec<BTreeSet<&str>> = match **f {
+                TypeFunc::Poly(ref p) => p.params.values().cloned().collect(),
+                _ => Vec::new(),
+            };
+            let no_constrs = BTreeSet::new();
+            *ts = ts.iter()
+                .enumerate()
+                .map(|(i, t)| {
+                    if !t.is_monomorphic() {
+                        let cs = constrs.get(i).unwrap_or(&no_constrs);
+                        defaulting.default_for(&var.ident, cs, classes)
+                    } else {
+                        t.clone()
+                    }
+                })
+                .collect::<Vec<_>>();
+        }
         if let TypeFunc::Poly(ref p) = **f {
             // An application of a polytype =>
             //   it's an instantiation to generate monomorphization for
             let b = env.get(var.ident.s).unwrap();
             if !b.mono_insts.contains_key(&*ts) {
                 // The monomorphization does not already exist
+                if env.get_height(var.ident.s) == Some(0) {
+                    if classes.class_of_method(var.ident.s).is_some() {
+                        return Some((ts.clone(), resolve_method_def(var, p, &*ts, classes)));
+                    }
+                    let builtin_def = builtin_class_of_method(var.ident.s)
+                        .and_then(|class| resolve_builtin_method_def(var, class, &*ts, classes));
+                    if let Some(def) = builtin_def {
+                        return Some((ts.clone(), def));
+                    }
+                }
                 let mut s = zip(&p.params, &*ts)
                     .map(|((&param_v, _), t)| (param_v, t.clone()))
                     .collect();
@@ -43,8 +249,8 @@ fn monomorphize_def_of_inst<'src>(
         }
     } else if !var.typ.is_monomorphic() {
         // If, in a monomorphic instantiation, the variable can be of any type:
-        // consistently default to generating it as Int64
-        var.typ = Type::Const("Int64", None)
+        // consistently default to generating it as the default type
+        var.typ = defaulting.default_for(&var.ident, &BTreeSet::new(), classes)
     }
     // Either definition is already monomorphic to begin with,
     // or it is polymorphic, but monomorphization has already been generated
@@ -54,13 +260,19 @@ fn monomorphize_def_of_inst<'src>(
 fn monomorphize_defs_of_insts_in_match<'src>(
     m: &mut Match<'src>,
     env: &mut ScopeStack<&'src str, Binding<'src>>,
+    classes: &Classes<'src>,
+    defaulting: &mut Defaulting<'src>,
+    chain: &mut InstChain<'src>,
 ) {
-    monomorphize_defs_of_insts_in_expr(&mut m.expr, env);
+    monomorphize_defs_of_insts_in_expr(&mut m.expr, env, classes, defaulting, chain);
     // TODO: This is probably wrong. I have no idea what to put here.
     // I kinda forgot how exactly this whole process worked, and I'm
     // tired and confused.
     for case in &mut m.cases {
-        monomorphize_defs_of_insts_in_expr(&mut case.body, env)
+        if let Some(ref mut guard) = case.guard {
+            monomorphize_defs_of_insts_in_expr(guard, env, classes, defaulting, chain)
+        }
+        monomorphize_defs_of_insts_in_expr(&mut case.body, env, classes, defaulting, chain)
     }
 }
 
@@ -68,6 +280,9 @@ fn monomorphize_defs_of_insts_in_match<'src>(
 fn monomorphize_defs_of_insts_in_expr<'src>(
     e: &mut Expr<'src>,
     env: &mut ScopeStack<&'src str, Binding<'src>>,
+    classes: &Classes<'src>,
+    defaulting: &mut Defaulting<'src>,
+    chain: &mut InstChain<'src>,
 ) {
     let canon = e.get_type().canonicalize();
     if !canon.is_monomorphic() {
@@ -80,63 +295,92 @@ fn monomorphize_defs_of_insts_in_expr<'src>(
     }
     match *e {
         Expr::Variable(ref mut var) => {
-            if let Some((arg_ts, mut def_mono)) = monomorphize_def_of_inst(var, env) {
+            let maybe_def_mono = monomorphize_def_of_inst(var, env, classes, defaulting);
+            if let Some((arg_ts, mut def_mono)) = maybe_def_mono {
                 // Insert dummy monomorphization as a tag to show that monomorphization
                 // already has been done, but we still need `def_mono` to continue
                 // our recursive monomorphization
-                {
+                let def = {
                     let dummy_expr = Expr::Nil(Nil {
                         pos: SrcPos::new_pos(path::Path::new(""), "", 0),
                     });
                     let b = env.get_mut(var.ident.s).unwrap();
                     b.mono_insts.insert(arg_ts.clone(), dummy_expr);
-                }
+                    b.ident.clone()
+                };
 
                 // Recursively generate monomorphizations for now-monomorphic
                 // instantiations in `def_mono`
+                push_inst(chain, &def, &var.ident, &arg_ts);
                 let h = env.get_height(var.ident.s).unwrap();
                 let above = env.split_off(h + 1);
-                monomorphize_defs_of_insts_in_expr(&mut def_mono, env);
+                monomorphize_defs_of_insts_in_expr(&mut def_mono, env, classes, defaulting, chain);
                 env.extend(above);
+                chain.pop();
 
                 let b = env.get_mut(var.ident.s).unwrap();
                 *b.mono_insts.get_mut(&arg_ts).unwrap() = def_mono;
             }
         }
         Expr::App(ref mut app) => {
-            monomorphize_defs_of_insts_in_expr(&mut app.func, env);
-            monomorphize_defs_of_insts_in_expr(&mut app.arg, env);
+            monomorphize_defs_of_insts_in_expr(&mut app.func, env, classes, defaulting, chain);
+            monomorphize_defs_of_insts_in_expr(&mut app.arg, env, classes, defaulting, chain);
         }
         Expr::If(ref mut cond) => {
-            monomorphize_defs_of_insts_in_expr(&mut cond.predicate, env);
-            monomorphize_defs_of_insts_in_expr(&mut cond.consequent, env);
-            monomorphize_defs_of_insts_in_expr(&mut cond.alternative, env);
+            monomorphize_defs_of_insts_in_expr(
+                &mut cond.predicate,
+                env,
+                classes,
+                defaulting,
+                chain,
+            );
+            monomorphize_defs_of_insts_in_expr(
+                &mut cond.consequent,
+                env,
+                classes,
+                defaulting,
+                chain,
+            );
+            monomorphize_defs_of_insts_in_expr(
+                &mut cond.alternative,
+                env,
+                classes,
+                defaulting,
+                chain,
+            );
         }
         Expr::Lambda(ref mut lam) => {
-            monomorphize_defs_of_insts_in_expr(&mut lam.body, env);
-        }
-        Expr::Let(box ref mut l) => {
-            monomorphize_defs_of_insts_in_let(&mut l.bindings, &mut l.body, env)
+            monomorphize_defs_of_insts_in_expr(&mut lam.body, env, classes, defaulting, chain);
         }
+        Expr::Let(box ref mut l) => monomorphize_defs_of_insts_in_let(
+            &mut l.bindings,
+            &mut l.body,
+            env,
+            classes,
+            defaulting,
+            chain,
+        ),
         Expr::TypeAscript(_) => unreachable!(),
         Expr::Cons(ref mut cons) => {
-            monomorphize_defs_of_insts_in_expr(&mut cons.car, env);
-            monomorphize_defs_of_insts_in_expr(&mut cons.cdr, env);
+            monomorphize_defs_of_insts_in_expr(&mut cons.car, env, classes, defaulting, chain);
+            monomorphize_defs_of_insts_in_expr(&mut cons.cdr, env, classes, defaulting, chain);
         }
         Expr::Car(ref mut c) => {
-            monomorphize_defs_of_insts_in_expr(&mut c.expr, env);
+            monomorphize_defs_of_insts_in_expr(&mut c.expr, env, classes, defaulting, chain);
         }
         Expr::Cdr(ref mut c) => {
-            monomorphize_defs_of_insts_in_expr(&mut c.expr, env);
+            monomorphize_defs_of_insts_in_expr(&mut c.expr, env, classes, defaulting, chain);
         }
         Expr::Cast(ref mut c) => {
-            monomorphize_defs_of_insts_in_expr(&mut c.expr, env);
+            monomorphize_defs_of_insts_in_expr(&mut c.expr, env, classes, defaulting, chain);
         }
         Expr::New(ref mut n) => for member in &mut n.members {
-            monomorphize_defs_of_insts_in_expr(member, env)
+            monomorphize_defs_of_insts_in_expr(member, env, classes, defaulting, chain)
         },
-        Expr::Match(ref mut m) => monomorphize_defs_of_insts_in_match(m, env),
-        Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) => (),
+        Expr::Match(ref mut m) => {
+            monomorphize_defs_of_insts_in_match(m, env, classes, defaulting, chain)
+        }
+        Expr::Nil(_) | Expr::NumLit(_) | Expr::StrLit(_) | Expr::Bool(_) | Expr::Hole(_) => (),
     }
 }
 
@@ -145,6 +389,9 @@ fn monomorphize_defs_of_insts_in_let<'src>(
     bindings: &mut TopologicallyOrderedDependencyGroups<'src>,
     body: &mut Expr<'src>,
     env: &mut ScopeStack<&'src str, Binding<'src>>,
+    classes: &Classes<'src>,
+    defaulting: &mut Defaulting<'src>,
+    chain: &mut InstChain<'src>,
 ) {
     let mut monos = BTreeMap::new();
     let mut bindings_flat_map = BTreeMap::new();
@@ -157,9 +404,9 @@ fn monomorphize_defs_of_insts_in_let<'src>(
     env.push(bindings_flat_map);
 
     for (_, mut def) in &mut monos {
-        monomorphize_defs_of_insts_in_expr(&mut def, env);
+        monomorphize_defs_of_insts_in_expr(&mut def, env, classes, defaulting, chain);
     }
-    monomorphize_defs_of_insts_in_expr(body, env);
+    monomorphize_defs_of_insts_in_expr(body, env, classes, defaulting, chain);
 
     for b in bindings.bindings_mut() {
         if let Some(upd_def) = monos.remove(b.ident.s) {
@@ -172,9 +419,62 @@ fn monomorphize_defs_of_insts_in_let<'src>(
 }
 
 /// Monomorphize definitions for monomorphic instantiations of variables in `bindings`
-pub fn monomorphize_defs_of_insts<'src>(globals: &mut TopologicallyOrderedDependencyGroups<'src>) {
+pub fn monomorphize_defs_of_insts<'src>(
+    globals: &mut TopologicallyOrderedDependencyGroups<'src>,
+    classes: &Classes<'src>,
+    defaulting: &mut Defaulting<'src>,
+) {
     let mut dummy_body = Expr::Nil(Nil {
         pos: SrcPos::new_pos(path::Path::new(""), "", 0),
     });
-    monomorphize_defs_of_insts_in_let(globals, &mut dummy_body, &mut ScopeStack::new());
+    monomorphize_defs_of_insts_in_let(
+        globals,
+        &mut dummy_body,
+        &mut ScopeStack::new(),
+        classes,
+        defaulting,
+        &mut Vec::new(),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use lib::collections::AddMap;
+    use lib::front::{SrcPos, TypeVarGen};
+    use lib::front::ast::*;
+    use lib::front::parse::parse_test_program;
+    use std::path::Path;
+    use super::{push_inst, Defaulting, MAX_INST_DEPTH};
+
+    #[test]
+    fn test_default_for() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = parse_test_program("(default Float64)", &sources, &mut tvg);
+        let mut defaulting = Defaulting::new(ast.default_type.clone(), false);
+        let x = Ident::new("x", SrcPos::new_dummy());
+        let num = ["Num"].iter().cloned().collect();
+        assert_eq!(defaulting.default_for(&x, &num, &ast.classes).to_string(), "Float64");
+        // Each use is only warned about once
+        assert_eq!(defaulting.default_for(&x, &num, &ast.classes).to_string(), "Float64");
+        assert_eq!(defaulting.warned.len(), 1);
+        // The declared default does not fulfill `Integral`, so the fallback is used
+        let y = Ident::new("y", SrcPos::new_dummy());
+        let integral = ["Integral"].iter().cloned().collect();
+        assert_eq!(defaulting.default_for(&y, &integral, &ast.classes).to_string(), "Int64");
+    }
+
+    #[test]
+    fn test_push_inst_per_binding() {
+        let src = "(define (f x) x) (define (g f) (f 1))";
+        let def_at = |start| Ident::new("f", SrcPos::new_pos(Path::new("test.kvs"), src, start));
+        let (global_f, param_f) = (def_at(9), def_at(27));
+        let mut chain = Vec::new();
+        // Two bindings of the same name each stay within the limit, though together they exceed it
+        for _ in 0..MAX_INST_DEPTH {
+            push_inst(&mut chain, &global_f, &global_f, &[]);
+            push_inst(&mut chain, &param_f, &param_f, &[]);
+        }
+        assert_eq!(chain.len(), 2 * MAX_INST_DEPTH);
+    }
 }
@@ -3,12 +3,18 @@ use super::*;
 use super::ast::*;
 use super::cst::Cst;
 use super::dependency_graph::*;
+use super::import_graph::{Import, ImportGraph};
 use super::macros;
-use lib::CanonPathBuf;
+use super::substitution::subst;
+use itertools::zip;
+use lib::{map_of, set_of, CanonPathBuf};
 use lib::collections::AddMap;
 use lib::front::lex::lex_file;
-use std::collections::BTreeMap;
-use std::mem;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::once;
+use std::{fmt, mem};
+use std::path::Path;
 
 /// Constructors for common parse errors to prevent repetition and spelling mistakes
 #[derive(PartialEq, Eq)]
@@ -69,6 +75,126 @@ enum PErr<'s> {
         name: &'s str,
         prev_pos: SrcPos<'s>,
     },
+    /// A module transitively imports itself. The chain of imports, ending in the one closing
+    /// the cycle
+    ImportCycle(Vec<Import<'s>>),
+    /// Duplicate definition of type class
+    ClassDuplDef {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        prev_pos: SrcPos<'s>,
+    },
+    /// Item in class or instance body is neither a method declaration nor a definition
+    InvalidClassItem(SrcPos<'s>),
+    /// The type of a method does not mention the parameter of the class
+    AmbiguousMethod {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        class: &'s str,
+    },
+    /// Definition of a method that is not declared in the class
+    UndefMethod {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        class: &'s str,
+    },
+    /// A method is neither defined in the instance nor has a default definition
+    MissingMethod {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        class: &'s str,
+    },
+    /// Instance head is not a type constant or type constructor applied to distinct variables
+    InvalidInstanceHead(SrcPos<'s>),
+    /// Duplicate instance of class for type constructor
+    InstanceDuplDef {
+        pos: SrcPos<'s>,
+        class: &'s str,
+        prev_pos: SrcPos<'s>,
+    },
+    /// Instance of class, but not of a superclass, for type constructor
+    MissingSuperInstance {
+        pos: SrcPos<'s>,
+        class: &'s str,
+        superclass: &'s str,
+    },
+    /// Type variable of instance head is also used in the type of a method
+    InstanceTVarClash {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        method: &'s str,
+    },
+    /// Definition of a class with the name of a builtin constraint class
+    BuiltinClassRedef(SrcPos<'s>, &'s str),
+    /// Instances of the class can't be automatically derived
+    InvalidDeriving(SrcPos<'s>, &'s str),
+    /// Deriving an instance in a program that does not import std
+    DerivingWithoutStd(SrcPos<'s>, &'s str),
+    /// Invalid type alias identifier
+    InvalidTypeAliasIdent(SrcPos<'s>, &'s str),
+    /// Duplicate definition of type alias, or type alias with the name of a data type
+    TypeAliasDuplDef {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        prev_pos: SrcPos<'s>,
+    },
+    /// A type alias transitively refers to itself. The chain of references to aliases being
+    /// expanded, ending in the one closing the cycle
+    TypeAliasCycle(Vec<Ident<'s>>),
+    /// Type variable in the body of a type alias that is not a parameter of the alias
+    UnboundTypeAliasTVar {
+        pos: SrcPos<'s>,
+        name: TVar<'s>,
+        alias: &'s str,
+    },
+    /// Newtype that doesn't have exactly one variant of exactly one member
+    InvalidNewtype(SrcPos<'s>),
+    /// Newtype that directly or indirectly contains itself
+    RecursiveNewtype(SrcPos<'s>, &'s str),
+    /// Field declaration of record is not of the form `(name type)`
+    InvalidRecordField(SrcPos<'s>),
+    /// Field is not a field of the record, or of any record if `record` is `None`
+    UndefField {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        record: Option<&'s str>,
+    },
+    /// Field occurs more than once in a record declaration, update, or pattern
+    DuplField {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        prev_pos: SrcPos<'s>,
+    },
+    /// Alternatives of an or-pattern that don't bind the same variables
+    OrPattVarsDiffer {
+        pos: SrcPos<'s>,
+        first_pos: SrcPos<'s>,
+    },
+    /// Pattern that may fail to match, where only irrefutable patterns are allowed
+    RefutablePatt(SrcPos<'s>),
+    /// `RealWorld` in a type outside of an extern declaration
+    HiddenRealWorld(SrcPos<'s>),
+    /// The type of a `(default T)` declaration is not a primitive numeric type
+    InvalidDefaultType(SrcPos<'s>),
+    /// Declarations of different default types
+    ConflictingDefault {
+        pos: SrcPos<'s>,
+        prev_pos: SrcPos<'s>,
+    },
+    /// Duplicate stand-alone type signature for a variable
+    SigDuplDef {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        prev_pos: SrcPos<'s>,
+    },
+    /// Stand-alone type signature without a binding of the same name
+    OrphanSig(SrcPos<'s>, &'s str),
+    /// Stand-alone type signature for a binding that already has a signature
+    ConflictingSig {
+        pos: SrcPos<'s>,
+        name: &'s str,
+        binding_pos: SrcPos<'s>,
+    },
 }
 
 impl<'s> PErr<'s> {
@@ -100,6 +226,36 @@ impl<'s> PErr<'s> {
             UndefDataConstr { .. } => e(19),
             InvalidDataBind(_) => e(20),
             DataDuplParam { .. } => e(21),
+            ImportCycle(_) => e(22),
+            ClassDuplDef { .. } => e(23),
+            InvalidClassItem(_) => e(24),
+            AmbiguousMethod { .. } => e(25),
+            UndefMethod { .. } => e(26),
+            MissingMethod { .. } => e(27),
+            InvalidInstanceHead(_) => e(28),
+            InstanceDuplDef { .. } => e(29),
+            MissingSuperInstance { .. } => e(30),
+            InstanceTVarClash { .. } => e(31),
+            BuiltinClassRedef(..) => e(32),
+            InvalidDeriving(..) => e(33),
+            InvalidTypeAliasIdent(..) => e(34),
+            TypeAliasDuplDef { .. } => e(35),
+            TypeAliasCycle(_) => e(36),
+            UnboundTypeAliasTVar { .. } => e(37),
+            InvalidNewtype(_) => e(38),
+            RecursiveNewtype(..) => e(39),
+            InvalidRecordField(_) => e(40),
+            UndefField { .. } => e(41),
+            DuplField { .. } => e(42),
+            OrPattVarsDiffer { .. } => e(43),
+            RefutablePatt(..) => e(44),
+            HiddenRealWorld(..) => e(45),
+            InvalidDefaultType(..) => e(46),
+            ConflictingDefault { .. } => e(47),
+            SigDuplDef { .. } => e(48),
+            OrphanSig(..) => e(49),
+            ConflictingSig { .. } => e(50),
+            DerivingWithoutStd(..) => e(51),
         }
     }
 
@@ -216,6 +372,281 @@ impl<'s> PErr<'s> {
                 );
                 prev_pos.write_note(w, "The previous declaration of the type parameter is here:")
             }
+            ImportCycle(ref chain) => {
+                let (closing, rest) = chain
+                    .split_last()
+                    .expect("ICE: Empty import chain in ImportCycle");
+                closing.pos.write_error(
+                    w,
+                    code,
+                    format!("Cyclic import of module `{}`", closing.name),
+                );
+                for import in rest {
+                    import
+                        .pos
+                        .write_note(w, format!("Module `{}` is imported here", import.name));
+                }
+            }
+            ClassDuplDef {
+                ref pos,
+                name,
+                ref prev_pos,
+            } => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("Class `{}` has already been defined in this scope", name),
+                );
+                prev_pos.write_note(w, "The first definition of the class is here:")
+            }
+            InvalidClassItem(ref pos) => pos.write_error(
+                w,
+                code,
+                "Invalid item. Expected method declaration `(: name type)` \
+                 or definition `(define ...)`",
+            ),
+            AmbiguousMethod {
+                ref pos,
+                name,
+                class,
+            } => pos.write_error(
+                w,
+                code,
+                format!(
+                    "Type of method `{}` does not mention the type parameter of class `{}`",
+                    name, class
+                ),
+            ),
+            UndefMethod {
+                ref pos,
+                name,
+                class,
+            } => pos.write_error(
+                w,
+                code,
+                format!("`{}` is not a method of class `{}`", name, class),
+            ),
+            MissingMethod {
+                ref pos,
+                name,
+                class,
+            } => pos.write_error(
+                w,
+                code,
+                format!(
+                    "Instance of `{}` is missing a definition of method `{}`, \
+                     which has no default",
+                    class, name
+                ),
+            ),
+            InvalidInstanceHead(ref pos) => pos.write_error(
+                w,
+                code,
+                "Invalid instance head. Expected a type constant, \
+                 or a type constructor applied to distinct type variables",
+            ),
+            InstanceDuplDef {
+                ref pos,
+                class,
+                ref prev_pos,
+            } => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("Conflicting instance of class `{}`", class),
+                );
+                prev_pos.write_note(w, "The previous instance is here:")
+            }
+            MissingSuperInstance {
+                ref pos,
+                class,
+                superclass,
+            } => pos.write_error(
+                w,
+                code,
+                format!(
+                    "Instance of `{}` requires an instance of superclass `{}` for the same type",
+                    class, superclass
+                ),
+            ),
+            InstanceTVarClash {
+                ref pos,
+                name,
+                method,
+            } => pos.write_error(
+                w,
+                code,
+                format!(
+                    "Type variable `{}` of instance head is also used in the type of method `{}`",
+                    name, method
+                ),
+            ),
+            BuiltinClassRedef(ref pos, name) => pos.write_error(
+                w,
+                code,
+                format!("Class `{}` is a builtin constraint class, and can't be redefined", name),
+            ),
+            InvalidDeriving(ref pos, class) => {
+                pos.write_error(w, code, format!("Can't derive instance of class `{}`", class));
+                pos.write_note(w, "Only `Eq`, `Ord`, and `Show` can be derived")
+            }
+            InvalidTypeAliasIdent(ref pos, name) => pos.write_error(
+                w,
+                code,
+                format!("Invalid type alias name `{}`", name),
+            ),
+            TypeAliasDuplDef {
+                ref pos,
+                name,
+                ref prev_pos,
+            } => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("Type `{}` has already been defined in this scope", name),
+                );
+                prev_pos.write_note(w, "The first definition of the type is here:")
+            }
+            TypeAliasCycle(ref chain) => {
+                let (closing, rest) = chain
+                    .split_last()
+                    .expect("ICE: Empty alias chain in TypeAliasCycle");
+                closing.pos.write_error(
+                    w,
+                    code,
+                    format!("Cyclic expansion of type alias `{}`", closing.s),
+                );
+                for alias in rest {
+                    alias
+                        .pos
+                        .write_note(w, format!("Type alias `{}` is expanded here", alias.s));
+                }
+            }
+            UnboundTypeAliasTVar {
+                ref pos,
+                name,
+                alias,
+            } => pos.write_error(
+                w,
+                code,
+                format!(
+                    "Type variable `{}` is not a parameter of type alias `{}`",
+                    name, alias
+                ),
+            ),
+            InvalidNewtype(ref pos) => pos.write_error(
+                w,
+                code,
+                "Invalid newtype. A newtype must have exactly one variant of exactly one member",
+            ),
+            RecursiveNewtype(ref pos, name) => {
+                pos.write_error(w, code, format!("Newtype `{}` is recursive", name));
+                pos.write_note(
+                    w,
+                    "A newtype is represented as its member. Use `data` for recursive types",
+                )
+            }
+            InvalidRecordField(ref pos) => pos.write_error(
+                w,
+                code,
+                "Invalid record field. Expected field declaration `(name type)`",
+            ),
+            UndefField {
+                ref pos,
+                name,
+                record,
+            } => {
+                let msg = match record {
+                    Some(record) => format!("Record `{}` has no field `{}`", record, name),
+                    None => format!("`{}` is not a field of any record", name),
+                };
+                pos.write_error(w, code, msg)
+            }
+            DuplField {
+                ref pos,
+                name,
+                ref prev_pos,
+            } => {
+                pos.write_error(w, code, format!("Field `{}` is given more than once", name));
+                prev_pos.write_note(w, "The field is first given here:")
+            }
+            OrPattVarsDiffer {
+                ref pos,
+                ref first_pos,
+            } => {
+                pos.write_error(
+                    w,
+                    code,
+                    "Alternative of or-pattern does not bind the same variables as the first",
+                );
+                first_pos.write_note(w, "The first alternative is here:")
+            }
+            RefutablePatt(ref pos) => pos.write_error(
+                w,
+                code,
+                "Refutable pattern in binding. Only patterns that match any value may be used here",
+            ),
+            HiddenRealWorld(ref pos) => {
+                pos.write_error(
+                    w,
+                    code,
+                    "`RealWorld` may only be used in the types of extern declarations",
+                );
+                pos.write_help(w, "Use the abstract type `(IO a)` for IO actions")
+            }
+            InvalidDefaultType(ref pos) => pos.write_error(
+                w,
+                code,
+                "Invalid default type. Expected a primitive numeric type, like `Float64`",
+            ),
+            ConflictingDefault {
+                ref pos,
+                ref prev_pos,
+            } => {
+                pos.write_error(w, code, "Conflicting declaration of default type");
+                prev_pos.write_note(w, "Previous declaration here");
+            }
+            SigDuplDef {
+                ref pos,
+                name,
+                ref prev_pos,
+            } => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("Duplicate type signature for variable `{}`", name),
+                );
+                prev_pos.write_note(w, "Previous signature here");
+            }
+            OrphanSig(ref pos, name) => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("The type signature for `{}` lacks an accompanying binding", name),
+                );
+                pos.write_help(w, "Define the variable in the same scope as its signature")
+            }
+            ConflictingSig {
+                ref pos,
+                name,
+                ref binding_pos,
+            } => {
+                pos.write_error(
+                    w,
+                    code,
+                    format!("Type signature for `{}`, which already has a signature", name),
+                );
+                binding_pos.write_note(w, "The binding with a signature is here");
+                binding_pos.write_help(w, "Use either a `define:` or a separate signature")
+            }
+            DerivingWithoutStd(ref pos, class) => {
+                pos.write_error(w, code, format!("Can't derive instance of class `{}`", class));
+                pos.write_note(
+                    w,
+                    "Derived instances are defined in terms of std, which is not imported",
+                );
+                pos.write_help(w, "Import std with `(import std)`")
+            }
         }
     }
 
@@ -224,6 +655,14 @@ impl<'s> PErr<'s> {
     }
 }
 
+impl<'s> fmt::Debug for PErr<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut v = Vec::new();
+        self.write(&mut v);
+        write!(f, "{}", String::from_utf8_lossy(&v))
+    }
+}
+
 type PRes<'s, T> = Result<T, PErr<'s>>;
 
 /// A binding pattern
@@ -232,8 +671,9 @@ type PRes<'s, T> = Result<T, PErr<'s>>;
 enum BindPattern<'s> {
     /// Just an identifier
     Var(Ident<'s>),
-    /// A function-binding pattern. E.g. `(inc x)`
-    Func(Ident<'s>, (Vec<Ident<'s>>, SrcPos<'s>)),
+    /// A function-binding pattern. E.g. `(inc x)`. The parameters may destructure the
+    /// arguments, like `(swap (cons a b))`
+    Func(Ident<'s>, (Vec<Cst<'s>>, SrcPos<'s>)),
 }
 
 // Parser combinators
@@ -316,15 +756,52 @@ fn constant<'s, T: Eq>(x: T, y: T, err: PErr<'s>) -> PRes<'s, ()> {
     }
 }
 
+/// The forms that build and take apart IO actions, available only to std and modules that
+/// declare externs
+const PRIMITIVE_FORMS: &[&str] = &["primitive-io", "primitive-bind"];
+
 fn is_special_operator(op: &Cst) -> bool {
     let special_operators = [
-        "if", "lambda", "let", ":", "cons", "car", "cdr", "cast", "cond", "new", "match"
+        "if",
+        "lambda",
+        "let",
+        ":",
+        "cons",
+        "car",
+        "cdr",
+        "cast",
+        "cond",
+        "new",
+        "match",
+        "with",
+        "@",
     ];
     ident_s(op)
         .map(|s| special_operators.contains(&s))
         .unwrap_or(false)
 }
 
+/// The form an algebraic data type is declared with
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AdtForm {
+    Data,
+    Newtype,
+    Record,
+}
+
+/// The token trees of the top level items of a program, separated by kind of item
+struct TopLevelCsts<'s> {
+    externs: Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+    globals: Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
+    /// Data type, newtype, and record definitions
+    adts: Vec<(AdtForm, Vec<Cst<'s>>, SrcPos<'s>)>,
+    classes: Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+    instances: Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+    type_aliases: Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+    /// Stand-alone type signatures of globals, like `(: f (-> Int64 Int64))`
+    sigs: Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+}
+
 struct Parser<'tvg, 's> {
     /// An additive-only map of module file paths to source code strings
     sources: &'s AddMap<CanonPathBuf, String>,
@@ -332,7 +809,39 @@ struct Parser<'tvg, 's> {
     type_var_gen: &'tvg mut TypeVarGen,
     /// Algebraic data type definitions
     adts: Adts<'s>,
+    /// The unparsed definitions of all type aliases. Aliases are parsed on demand when first
+    /// expanded, so that they may refer to each other regardless of order
+    type_alias_csts: BTreeMap<&'s str, (Vec<Cst<'s>>, SrcPos<'s>)>,
+    /// Type alias definitions parsed so far
+    type_aliases: TypeAliases<'s>,
+    /// The chain of references to the type aliases currently being parsed. The last is the
+    /// innermost
+    alias_chain: Vec<Ident<'s>>,
+    /// Names of all defined type classes. Collected before the classes are parsed,
+    /// so that classes may be referred to in constraints before their definition
+    class_names: BTreeSet<&'s str>,
+    /// Type class definitions and instances
+    classes: Classes<'s>,
     macros: BTreeMap<&'s str, macros::Macro<'s>>,
+    /// The graph of imports between the modules parsed so far
+    import_graph: ImportGraph<'s>,
+    /// The modules currently being parsed. The last is the innermost
+    module_stack: Vec<CanonPathBuf>,
+    /// The chain of imports leading to the innermost module being parsed
+    import_chain: Vec<Import<'s>>,
+    /// Counter for generation of unique names of destructured values
+    n_destructured: usize,
+    /// Counter for generation of unique names in desugared IO actions
+    n_io: usize,
+    /// Whether the type of an extern declaration is being parsed, where `RealWorld` is
+    /// allowed so that foreign functions may thread the world token
+    in_extern: bool,
+    /// The type declared with `(default T)`, that ambiguous types are defaulted to
+    default_type: Option<(Type<'s>, SrcPos<'s>)>,
+    /// The modules that have declared `(allow-coercion)`
+    coercion_modules: BTreeSet<&'s Path>,
+    /// The modules that declare externs, and may use the primitive IO forms
+    extern_modules: BTreeSet<&'s Path>,
 }
 
 impl<'tvg, 's> Parser<'tvg, 's> {
@@ -340,8 +849,22 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         Parser {
             sources,
             type_var_gen,
-            adts: Adts::new(),
+            adts: Adts::with_builtins(),
+            type_alias_csts: BTreeMap::new(),
+            type_aliases: TypeAliases::new(),
+            alias_chain: Vec::new(),
+            class_names: BTreeSet::new(),
+            classes: Classes::new(),
             macros: BTreeMap::new(),
+            import_graph: ImportGraph::new(),
+            module_stack: Vec::new(),
+            import_chain: Vec::new(),
+            n_destructured: 0,
+            n_io: 0,
+            in_extern: false,
+            default_type: None,
+            coercion_modules: BTreeSet::new(),
+            extern_modules: BTreeSet::new(),
         }
     }
 
@@ -354,16 +877,71 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     }
 
     /// Parse a list of `Cst`s as a module import
-    fn parse_import(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, &'s str> {
-        ident_s(one(csts, pos)?)
+    fn parse_import(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Import<'s>> {
+        let name = ident_s(one(csts, pos)?)?;
+        let path = CanonPathBuf::new(&format!("{}.kvs", name))
+            .expect("ICE: Failed to canonicalize module path");
+        Ok(Import {
+            name,
+            path,
+            pos: pos.clone(),
+        })
+    }
+
+    /// Returns the module currently being parsed
+    fn current_module(&self) -> &CanonPathBuf {
+        self.module_stack
+            .last()
+            .expect("ICE: No current module in current_module")
+    }
+
+    /// Returns the import of std by any module of the program, if there is one
+    fn std_import(&self) -> Option<&Import<'s>> {
+        self.import_graph
+            .modules
+            .values()
+            .flat_map(|m| m.imports.iter())
+            .find(|import| import.name == "std")
+    }
+
+    /// Record `import` in the import graph, and follow it unless the module has already been
+    /// visited
+    ///
+    /// If the imported module is still being parsed, the import closes a cycle,
+    /// which is an error.
+    fn follow_import(&mut self, import: Import<'s>, items: &mut TopLevelCsts<'s>) -> PRes<'s, ()> {
+        let current = self.current_module().clone();
+        self.import_graph
+            .get_mut(&current)
+            .imports
+            .push(import.clone());
+        if let Some(i) = self.module_stack.iter().position(|m| *m == import.path) {
+            let mut chain = self.import_chain[i..].to_vec();
+            chain.push(import);
+            return Err(ImportCycle(chain));
+        }
+        if self.import_graph.contains(&import.path) {
+            return Ok(());
+        }
+        self.import_graph.add_module(import.path.clone());
+        self.module_stack.push(import.path.clone());
+        self.import_chain.push(import.clone());
+        let import_csts = lex_file(import.path, &self.sources);
+        self._get_top_level_csts(&import_csts, items)?;
+        self.module_stack.pop();
+        self.import_chain.pop();
+        Ok(())
     }
 
     /// Parse a list of `Cst`s as an external variable declaration
     fn parse_extern(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, ExternDecl<'s>> {
         let (a, b) = two(csts, pos)?;
+        self.in_extern = true;
+        let typ = self.parse_type(b);
+        self.in_extern = false;
         Ok(ExternDecl {
             ident: ident(a)?,
-            typ: self.parse_type(b)?,
+            typ: typ?,
             pos: pos.clone(),
         })
     }
@@ -398,7 +976,8 @@ impl<'tvg, 's> Parser<'tvg, 's> {
 
     fn parse_constraint_class(&mut self, cst: &Cst<'s>) -> PRes<'s, &'s str> {
         match *cst {
-            Cst::Ident("Num", _) => Ok("Num"),
+            Cst::Ident(s, _) if is_builtin_class(s) => Ok(s),
+            Cst::Ident(s, _) if self.class_names.contains(s) => Ok(s),
             Cst::Ident(s, ref pos) => Err(UndefConstr(pos.clone(), s)),
             _ => Err(InvalidConstr(cst.pos().clone())),
         }
@@ -471,18 +1050,30 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         Ok(Type::new_cons(car, cdr))
     }
 
-    fn parse_type_app(&mut self, name: &'s str, args_csts: &[Cst<'s>]) -> PRes<'s, Type<'s>> {
+    fn parse_type_app(
+        &mut self,
+        name: &'s str,
+        args_csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, Type<'s>> {
         let args = args_csts
             .iter()
             .map(|c| self.parse_type(c))
             .collect::<Result<_, _>>()?;
-        Ok(Type::App(box TypeFunc::Const(name), args))
+        match self.type_alias(name, pos)? {
+            Some(alias) => expand_type_alias(&alias, args, pos),
+            None => Ok(Type::App(box TypeFunc::Const(name), args)),
+        }
     }
 
     fn parse_ptr_type(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Type<'s>> {
         self.parse_type(one(csts, pos)?).map(Type::new_ptr)
     }
 
+    fn parse_unique_type(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Type<'s>> {
+        self.parse_type(one(csts, pos)?).map(Type::new_unique)
+    }
+
     fn parse_type_sexpr(&mut self, app: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Type<'s>> {
         let (first, rest) = split_first(app, pos)?;
         let id = ident_s(first)?;
@@ -490,7 +1081,16 @@ impl<'tvg, 's> Parser<'tvg, 's> {
             "->" => self.parse_func_type(rest, pos),
             "Cons" => self.parse_cons_type(rest, pos),
             "Ptr" => self.parse_ptr_type(rest, pos),
-            _ => self.parse_type_app(id, rest),
+            "Unique" => self.parse_unique_type(rest, pos),
+            // Application of a higher kinded type variable, like `(f a)`
+            s if s.starts_with(char::is_lowercase) => {
+                first(rest, pos)?;
+                let args = rest.iter()
+                    .map(|c| self.parse_type(c))
+                    .collect::<Result<_, _>>()?;
+                Ok(Type::App(box TypeFunc::Var(TVar::Explicit(s)), args))
+            }
+            _ => self.parse_type_app(id, rest, first.pos()),
         }
     }
 
@@ -498,10 +1098,14 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         match id {
             "_" => Ok(self.gen_type_var()),
             "Nil" => Ok(TYPE_NIL.clone()),
+            "RealWorld" if !self.in_extern => Err(HiddenRealWorld(pos.clone())),
             // The type identifier starts with a lowercase letter => Is a type variable
             s if s.starts_with(char::is_lowercase) => Ok(Type::Var(TVar::Explicit(s))),
-            // Doesn't start with lowercase => Is a type constant e.g. Int32
-            s => Ok(Type::Const(s, Some(pos.clone()))),
+            // Doesn't start with lowercase => Is a type constant e.g. Int32, or a type alias
+            s => match self.type_alias(s, pos)? {
+                Some(alias) => expand_type_alias(&alias, Vec::new(), pos),
+                None => Ok(Type::Const(s, Some(pos.clone()))),
+            },
         }
     }
 
@@ -525,8 +1129,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         let first_param = first(rest, pos)?;
         let last_param = last(rest, pos)?;
         let params_pos = first_param.pos().to(last_param.pos());
-        let params_ids = rest.iter().map(|a| ident(a)).collect::<PRes<Vec<_>>>()?;
-        Ok(BindPattern::Func(f_id, (params_ids, params_pos)))
+        Ok(BindPattern::Func(f_id, (rest.to_vec(), params_pos)))
     }
 
     /// Parse a syntax tree as a BindPattern
@@ -641,7 +1244,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         let innermost = Lambda {
             param_ident: last.0.clone(),
             body: body,
-            typ: self.gen_type_var(),
+            typ: Type::new_func(last.1.clone(), self.gen_type_var()),
             pos: pos.clone(),
         };
         Ok(init.iter()
@@ -650,12 +1253,14 @@ impl<'tvg, 's> Parser<'tvg, 's> {
             .fold(innermost, |inner, param| Lambda {
                 param_ident: param.0,
                 body: Expr::Lambda(Box::new(inner)),
-                typ: self.gen_type_var(),
+                typ: Type::new_func(param.1, self.gen_type_var()),
                 pos: pos.clone(),
             }))
     }
 
     /// Parse a list of `Cst`s as the parts of a `Lambda`
+    ///
+    /// Parameters may be annotated with their types, like `(lambda ((: x Int64) y) ...)`
     fn parse_lambda(
         &mut self,
         csts: &[Cst<'s>],
@@ -665,20 +1270,139 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         let (a, b) = two(csts, args_pos)?;
         let params_csts = sexpr(a)?;
         let params_pos = a.pos();
-        let params = params_csts
-            .iter()
-            .map(|p| Ok((ident(p)?, self.gen_type_var())))
-            .collect::<PRes<Vec<_>>>()?;
-        let body = self.parse_expr(b)?;
+        let (params, body_cst) = self.desugar_destructuring_params(params_csts, b)?;
+        let body = self.parse_expr(&body_cst)?;
         self.new_multary_lambda(&params, params_pos, body, pos)
     }
 
-    fn parse_binding(
+    /// Returns whether `cst`, where a variable is bound, is a pattern destructuring the
+    /// value, like `(Pair a b)`, rather than an identifier or function-binding pattern
+    fn is_destructuring_pattern(&self, cst: &Cst<'s>) -> bool {
+        match *cst {
+            Cst::Ident(s, _) => self.adts.variant_exists(s),
+            Cst::Sexpr(ref cs, _) => match cs.first() {
+                Some(&Cst::Ident(s, _)) => {
+                    ["cons", "@", "or"].contains(&s) || self.adts.variant_exists(s)
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns whether `patt` matches any value of its type
+    fn is_irrefutable(&self, patt: &Pattern<'s>) -> bool {
+        match *patt {
+            Pattern::Wild(_) | Pattern::Nil(_) | Pattern::Variable(_) => true,
+            Pattern::NumLit(_) | Pattern::StrLit(_) => false,
+            Pattern::Deconstr(ref dec) => {
+                let n_variants = self.adts
+                    .parent_adt_of_variant(dec.constr.s)
+                    .map(|adt| adt.variants.len());
+                n_variants == Some(1) && dec.subpatts.iter().all(|p| self.is_irrefutable(p))
+            }
+            Pattern::As(ref a) => self.is_irrefutable(&a.patt),
+            Pattern::Or(ref o) => o.alts.iter().any(|p| self.is_irrefutable(p)),
+            Pattern::Cons(ref c) => self.is_irrefutable(&c.car) && self.is_irrefutable(&c.cdr),
+        }
+    }
+
+    /// Parse a pattern that must match any value, as where a variable is bound
+    fn parse_irrefutable_pattern(&mut self, cst: &Cst<'s>) -> PRes<'s, Pattern<'s>> {
+        let patt = self.parse_pattern(cst)?;
+        if self.is_irrefutable(&patt) {
+            Ok(patt)
+        } else {
+            Err(RefutablePatt(cst.pos().clone()))
+        }
+    }
+
+    /// Generate a unique name for a destructured value
+    fn gen_destructured_ident(&mut self) -> &'static str {
+        self.n_destructured += 1;
+        gen_ident("destructured", self.n_destructured - 1)
+    }
+
+    /// Replace each parameter that destructures its argument with a pattern, like
+    /// `(Pair a b)`, with a fresh variable, and wrap `body` in a match on it
+    ///
+    /// Returns the parameters along with their types, which are fresh type variables unless
+    /// annotated, like `(: x Int64)`.
+    fn desugar_destructuring_params(
         &mut self,
-        patt: &Cst<'s>,
-        maybe_sig: Option<&Cst<'s>>,
-        val: &Cst<'s>,
-        pos: &SrcPos<'s>,
+        params_csts: &[Cst<'s>],
+        body: &Cst<'s>,
+    ) -> PRes<'s, (Vec<(Ident<'s>, Type<'s>)>, Cst<'s>)> {
+        let mut params = Vec::new();
+        let mut destructured = Vec::new();
+        for p in params_csts {
+            let (p, typ) = match *p {
+                Cst::Sexpr(ref xs, ref pos)
+                    if xs.first().and_then(|x| ident_s(x).ok()) == Some(":") =>
+                {
+                    let (inner, t) = two(&xs[1..], &pos.after(xs[0].pos()))?;
+                    (inner, self.parse_type(t)?)
+                }
+                _ => (p, self.gen_type_var()),
+            };
+            let param = if self.is_destructuring_pattern(p) {
+                self.parse_irrefutable_pattern(p)?;
+                let param = Ident::new(self.gen_destructured_ident(), p.pos().clone());
+                destructured.push((param.clone(), p));
+                param
+            } else {
+                ident(p)?
+            };
+            params.push((param, typ))
+        }
+        let body = destructured
+            .into_iter()
+            .rev()
+            .fold(body.clone(), |inner, (param, patt)| {
+                let pos = patt.pos();
+                Cst::Sexpr(
+                    vec![
+                        Cst::Ident("match", pos.clone()),
+                        Cst::Ident(param.s, pos.clone()),
+                        Cst::Sexpr(vec![patt.clone(), inner], pos.clone()),
+                    ],
+                    pos.clone(),
+                )
+            });
+        Ok((params, body))
+    }
+
+    /// Desugar a binding that destructures its value with the pattern `patt`, like
+    /// `[(Pair a b) p]`, to a binding of the value to a fresh variable, and a binding of
+    /// each variable of the pattern to a match on it
+    fn desugar_destructuring_binding(
+        &mut self,
+        patt: &Cst<'s>,
+        val: &Cst<'s>,
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, Vec<(Vec<Cst<'s>>, SrcPos<'s>)>> {
+        let vars = self.parse_irrefutable_pattern(patt)?.variable_names();
+        let destructured = self.gen_destructured_ident();
+        let id = |s: &'s str| Cst::Ident(s, pos.clone());
+        let sx = |xs: Vec<Cst<'s>>| Cst::Sexpr(xs, pos.clone());
+        let mut bindings = vec![(vec![id(destructured), val.clone()], pos.clone())];
+        for var in vars {
+            let match_c = sx(vec![
+                id("match"),
+                id(destructured),
+                sx(vec![patt.clone(), id(var)]),
+            ]);
+            bindings.push((vec![id(var), match_c], pos.clone()))
+        }
+        Ok(bindings)
+    }
+
+    fn parse_binding(
+        &mut self,
+        patt: &Cst<'s>,
+        maybe_sig: Option<&Cst<'s>>,
+        val: &Cst<'s>,
+        pos: &SrcPos<'s>,
     ) -> PRes<'s, Binding<'s>> {
         let sig = maybe_sig
             .map(|c| self.parse_def_type_sig(c))
@@ -696,12 +1420,9 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                 mono_insts: BTreeMap::new(),
                 pos: pos.clone(),
             },
-            BindPattern::Func(f_id, (params_ids, params_pos)) => {
-                let params = params_ids
-                    .into_iter()
-                    .map(|id| (id, self.gen_type_var()))
-                    .collect::<Vec<_>>();
-                let body = self.parse_expr(val)?;
+            BindPattern::Func(f_id, (params_csts, params_pos)) => {
+                let (params, body_cst) = self.desugar_destructuring_params(&params_csts, val)?;
+                let body = self.parse_expr(&body_cst)?;
                 Binding {
                     ident: f_id,
                     sig: sig,
@@ -780,15 +1501,40 @@ impl<'tvg, 's> Parser<'tvg, 's> {
             .map(flat_bindings_to_topologically_ordered)
     }
 
+    /// Parse the bindings of a `let`
+    ///
+    /// A binding may be given a type signature, like a `define:`, as in `[x Int64 1]`, or by a
+    /// separate signature among the bindings, as in `[(: x Int64) (x 1)]`.
+    /// Explicit type variables of the signature of the enclosing definition are in scope.
     fn parse_let_bindings(
         &mut self,
         csts: &[Cst<'s>],
     ) -> PRes<'s, TopologicallyOrderedDependencyGroups<'s>> {
-        let mut bindings_csts = Vec::new();
+        let mut binding_pairs = Vec::new();
+        let mut sigs = Vec::new();
         for cst in csts {
             let binding_pair = sexpr(cst)?;
-            bindings_csts.push((false, binding_pair.clone(), cst.pos().clone()))
+            match binding_pair.first() {
+                Some(head) if ident_s(head).ok() == Some(":") => {
+                    sigs.push((binding_pair[1..].to_vec(), cst.pos().clone()))
+                }
+                Some(patt) if self.is_destructuring_pattern(patt) => {
+                    let (_, val) = two(binding_pair, cst.pos())?;
+                    let desugared = self.desugar_destructuring_binding(patt, val, cst.pos())?;
+                    binding_pairs.extend(desugared.into_iter().map(|(c, p)| (false, c, p)))
+                }
+                _ => binding_pairs.push((
+                    binding_pair.len() == 3,
+                    binding_pair.to_vec(),
+                    cst.pos().clone(),
+                )),
+            }
         }
+        attach_signatures(&mut binding_pairs, &sigs)?;
+        let bindings_csts = binding_pairs
+            .iter()
+            .map(|&(is_typed, ref pair, ref pos)| (is_typed, &pair[..], pos.clone()))
+            .collect::<Vec<_>>();
         self.parse_bindings(&bindings_csts)
     }
 
@@ -809,6 +1555,26 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         })
     }
 
+    /// Parse an explicit type application, like `(@ read-num Int64)`, as a variable with
+    /// explicit type arguments
+    fn parse_explicit_type_application(
+        &mut self,
+        csts: &[Cst<'s>],
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        let (f, ts) = split_first(csts, args_pos)?;
+        if ts.is_empty() {
+            return Err(ArityMisTooFew(args_pos.clone(), 2));
+        }
+        Ok(Expr::Variable(Variable {
+            ident: ident(f)?,
+            typ: self.gen_type_var(),
+            type_args: ts.iter()
+                .map(|t| self.parse_type(t))
+                .collect::<PRes<_>>()?,
+        }))
+    }
+
     /// Parse a list of `Cst`s as a `TypeAscript`
     fn parse_type_ascript(
         &mut self,
@@ -923,9 +1689,17 @@ impl<'tvg, 's> Parser<'tvg, 's> {
     ) -> PRes<'s, Deconstr<'s>> {
         let (head, tail) = split_first(csts, pos)?;
         let constr = self.parse_variant(head)?;
-        let subpatts = tail.iter()
-            .map(|c| self.parse_pattern(c))
-            .collect::<PRes<_>>()?;
+        let fields = self.adts
+            .parent_adt_of_variant(constr.s)
+            .and_then(|adt| adt.fields.clone());
+        let subpatts = match fields {
+            Some(ref fields) if tail.first().map(|c| is_field_pattern(c, fields)) == Some(true) => {
+                self.parse_field_patterns(tail, constr.s, fields, pos)?
+            }
+            _ => tail.iter()
+                .map(|c| self.parse_pattern(c))
+                .collect::<PRes<_>>()?,
+        };
         Ok(Deconstr {
             constr,
             subpatts,
@@ -933,10 +1707,137 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         })
     }
 
+    /// Parse the subpatterns of a pattern on the record `record` by field name, like
+    /// `(Point [y py])`, in the order of the fields. Fields not given are ignored
+    fn parse_field_patterns(
+        &mut self,
+        csts: &[Cst<'s>],
+        record: &'s str,
+        fields: &[Ident<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, Vec<Pattern<'s>>> {
+        let mut named: Vec<(Ident<'s>, Pattern<'s>)> = Vec::new();
+        for c in csts {
+            let (field_c, patt_c) = pair(c)?;
+            let field = ident(field_c)?;
+            if !fields.iter().any(|f| f.s == field.s) {
+                return Err(UndefField {
+                    pos: field.pos,
+                    name: field.s,
+                    record: Some(record),
+                });
+            }
+            if let Some(&(ref prev, _)) = named.iter().find(|&&(ref prev, _)| prev.s == field.s) {
+                return Err(DuplField {
+                    pos: field.pos.clone(),
+                    name: field.s,
+                    prev_pos: prev.pos.clone(),
+                });
+            }
+            let patt = self.parse_pattern(patt_c)?;
+            named.push((field, patt));
+        }
+        let mut subpatts = Vec::new();
+        for f in fields {
+            let subpatt = match named.iter().position(|&(ref g, _)| g.s == f.s) {
+                Some(i) => named.remove(i).1,
+                None => Pattern::Wild(pos.clone()),
+            };
+            subpatts.push(subpatt)
+        }
+        Ok(subpatts)
+    }
+
+    /// Parse an as-pattern, like `(@ xs (List x _))`
+    fn parse_as_pattern(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, AsPattern<'s>> {
+        let (var_c, patt_c) = two(csts, args_pos)?;
+        let var = match self.parse_pattern(var_c)? {
+            Pattern::Variable(var) => var,
+            _ => return Err(Expected(var_c.pos().clone(), "variable to bind")),
+        };
+        Ok(AsPattern {
+            var,
+            patt: self.parse_pattern(patt_c)?,
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse an or-pattern, like `(or CmpLt CmpEq)`
+    fn parse_or_pattern(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, OrPattern<'s>> {
+        let (first_c, rest_c) = split_first(csts, args_pos)?;
+        let first = self.parse_pattern(first_c)?;
+        let first_vars = first.variable_names();
+        let mut alts = vec![first];
+        for c in rest_c {
+            let alt = self.parse_pattern(c)?;
+            if alt.variable_names() != first_vars {
+                return Err(OrPattVarsDiffer {
+                    pos: c.pos().clone(),
+                    first_pos: first_c.pos().clone(),
+                });
+            }
+            alts.push(alt)
+        }
+        Ok(OrPattern {
+            alts,
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a pattern on a `cons` pair, like `(cons x y)`
+    ///
+    /// More than two subpatterns are nested to the right, so that tuples can be matched on
+    /// like `(cons x y z)`, instead of `(cons x (cons y z))`.
+    fn parse_cons_pattern(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Pattern<'s>> {
+        if csts.len() < 2 {
+            return Err(ArityMisTooFew(args_pos.clone(), csts.len()));
+        }
+        let (last_c, init_c) = split_last(csts, args_pos)?;
+        let mut patt = self.parse_pattern(last_c)?;
+        for c in init_c.iter().rev() {
+            patt = Pattern::Cons(box ConsPattern {
+                car: self.parse_pattern(c)?,
+                cdr: patt,
+                typ: self.gen_type_var(),
+                pos: pos.clone(),
+            })
+        }
+        Ok(patt)
+    }
+
+    fn parse_sexpr_pattern(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, Pattern<'s>> {
+        let (head, tail) = split_first(csts, pos)?;
+        let tail_pos = pos.after(head.pos());
+        match *head {
+            Cst::Ident("@", _) => self.parse_as_pattern(tail, pos, &tail_pos)
+                .map(|a| Pattern::As(box a)),
+            Cst::Ident("or", _) => self.parse_or_pattern(tail, pos, &tail_pos)
+                .map(|o| Pattern::Or(box o)),
+            Cst::Ident("cons", _) => self.parse_cons_pattern(tail, pos, &tail_pos),
+            _ => self.parse_deconstr_pattern(csts, pos)
+                .map(|d| Pattern::Deconstr(box d)),
+        }
+    }
+
     fn parse_pattern(&mut self, cst: &Cst<'s>) -> PRes<'s, Pattern<'s>> {
         match *cst {
-            Cst::Sexpr(ref sexpr, ref pos) => self.parse_deconstr_pattern(sexpr, pos)
-                .map(|d| Pattern::Deconstr(box d)),
+            Cst::Sexpr(ref sexpr, ref pos) => self.parse_sexpr_pattern(sexpr, pos),
+            Cst::Ident("_", ref pos) => Ok(Pattern::Wild(pos.clone())),
             Cst::Ident("nil", ref pos) => Ok(Pattern::Nil(Nil { pos: pos.clone() })),
             Cst::Ident(ident, ref pos) if self.adts.variant_exists(ident) => {
                 Ok(Pattern::Deconstr(box Deconstr {
@@ -948,6 +1849,7 @@ impl<'tvg, 's> Parser<'tvg, 's> {
             Cst::Ident(ident, ref pos) => Ok(Pattern::Variable(Variable {
                 ident: Ident::new(ident, pos.clone()),
                 typ: self.gen_type_var(),
+                type_args: Vec::new(),
             })),
             Cst::Num(num, ref pos) => Ok(Pattern::NumLit(NumLit {
                 lit: num,
@@ -961,11 +1863,32 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         }
     }
 
+    /// Parse a guard of a case, like `(when (> x 0))`
+    fn parse_guard(&mut self, cst: &Cst<'s>) -> PRes<'s, Expr<'s>> {
+        let (when, guard) = pair(cst)?;
+        match *when {
+            Cst::Ident("when", _) => self.parse_expr(guard),
+            _ => Err(Expected(when.pos().clone(), "guard `(when ...)`")),
+        }
+    }
+
+    /// Parse a case of a match, like `[(Some x) x]`, or with a guard,
+    /// like `[(Some x) (when (> x 0)) x]`
     fn parse_case(&mut self, cst: &Cst<'s>) -> PRes<'s, Case<'s>> {
-        let (patt_cst, body_cst) = pair(cst)?;
+        let csts = sexpr(cst)?;
+        let (patt_cst, guard_cst, body_cst) = if csts.len() == 3 {
+            (&csts[0], Some(&csts[1]), &csts[2])
+        } else {
+            let (patt_cst, body_cst) = two(csts, cst.pos())?;
+            (patt_cst, None, body_cst)
+        };
         Ok(Case {
             patt: self.parse_pattern(patt_cst)?,
             patt_typ: self.gen_type_var(),
+            guard: match guard_cst {
+                Some(c) => Some(self.parse_guard(c)?),
+                None => None,
+            },
             body: self.parse_expr(body_cst)?,
             pos: cst.pos().clone(),
         })
@@ -990,6 +1913,176 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         })
     }
 
+    /// Parse a functional record update, like `(with p [x 1.0])`
+    ///
+    /// Desugars to a match that deconstructs the record and constructs a new one of the same
+    /// record type, with the given fields replaced and the rest copied.
+    fn parse_with(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        let (record_c, updates_c) = split_first(csts, args_pos)?;
+        let mut updates: Vec<(Ident<'s>, &Cst<'s>)> = Vec::new();
+        for c in updates_c {
+            let (field_c, val_c) = pair(c)?;
+            let field = ident(field_c)?;
+            if let Some(&(ref prev, _)) = updates.iter().find(|&&(ref prev, _)| prev.s == field.s) {
+                return Err(DuplField {
+                    pos: field.pos.clone(),
+                    name: field.s,
+                    prev_pos: prev.pos.clone(),
+                });
+            }
+            updates.push((field, val_c));
+        }
+        let adt = {
+            let &(ref first_field, _) = updates
+                .first()
+                .ok_or(ArityMisTooFew(args_pos.clone(), 1))?;
+            self.adts
+                .record_of_field(first_field.s)
+                .ok_or(UndefField {
+                    pos: first_field.pos.clone(),
+                    name: first_field.s,
+                    record: None,
+                })?
+                .clone()
+        };
+        let fields = adt.fields.as_ref().expect("ICE: record_of_field returned non-record");
+        for &(ref field, _) in &updates {
+            if !fields.iter().any(|f| f.s == field.s) {
+                return Err(UndefField {
+                    pos: field.pos.clone(),
+                    name: field.s,
+                    record: Some(adt.name.s),
+                });
+            }
+        }
+        let id = |s: &'s str| Cst::Ident(s, pos.clone());
+        let sx = |xs: Vec<Cst<'s>>| Cst::Sexpr(xs, pos.clone());
+        let mut binders = Vec::new();
+        let mut vals = Vec::new();
+        for (i, f) in fields.iter().enumerate() {
+            match updates.iter().find(|&&(ref g, _)| g.s == f.s) {
+                Some(&(_, val_c)) => {
+                    binders.push(id("_"));
+                    vals.push(val_c.clone());
+                }
+                None => {
+                    let var = id(gen_ident("with-field", i));
+                    binders.push(var.clone());
+                    vals.push(var);
+                }
+            }
+        }
+        let patt = sx(once(id(adt.name.s)).chain(binders).collect());
+        let new = sx(vec![id("new"), id(adt.name.s)].into_iter().chain(vals).collect());
+        let match_c = sx(vec![id("match"), record_c.clone(), sx(vec![patt, new])]);
+        self.parse_expr(&match_c)
+    }
+
+    fn gen_io_ident(&mut self, prefix: &str) -> &'static str {
+        self.n_io += 1;
+        gen_ident(prefix, self.n_io)
+    }
+
+    /// Returns the token tree of an IO action that runs the function `run`, which threads the
+    /// world token through its effects
+    fn io_action_csts(&self, run: Cst<'s>, pos: &SrcPos<'s>) -> Cst<'s> {
+        let id = |s: &'s str| Cst::Ident(s, pos.clone());
+        Cst::Sexpr(vec![id("new"), id(IO_CONSTR), run], pos.clone())
+    }
+
+    /// Returns the token tree of the IO action that performs `m`, applies `f` to the result,
+    /// and performs the resulting action
+    fn bind_csts(&mut self, m: Cst<'s>, f: Cst<'s>, pos: &SrcPos<'s>) -> Cst<'s> {
+        let world = Cst::Ident(self.gen_io_ident("io-world"), pos.clone());
+        let world2 = Cst::Ident(self.gen_io_ident("io-world"), pos.clone());
+        let run_m = Cst::Ident(self.gen_io_ident("io-run"), pos.clone());
+        let run_f = Cst::Ident(self.gen_io_ident("io-run"), pos.clone());
+        let x = Cst::Ident(self.gen_io_ident("io-result"), pos.clone());
+        let id = |s: &'s str| Cst::Ident(s, pos.clone());
+        let sx = |xs: Vec<Cst<'s>>| Cst::Sexpr(xs, pos.clone());
+        let run_rest = sx(vec![
+            id("match"),
+            sx(vec![f, x.clone()]),
+            sx(vec![
+                sx(vec![id(IO_CONSTR), run_f.clone()]),
+                sx(vec![run_f, world2.clone()]),
+            ]),
+        ]);
+        let run_all = sx(vec![
+            id("match"),
+            sx(vec![run_m.clone(), world.clone()]),
+            sx(vec![sx(vec![id("cons"), x, world2]), run_rest]),
+        ]);
+        let unwrap_m = sx(vec![
+            id("match"),
+            m,
+            sx(vec![sx(vec![id(IO_CONSTR), run_m]), run_all]),
+        ]);
+        self.io_action_csts(sx(vec![id("lambda"), sx(vec![world]), unwrap_m]), pos)
+    }
+
+    /// Parse `(primitive-io f)`, the IO action of the function `f`, which threads the world
+    /// token through its effects
+    ///
+    /// Used to wrap extern functions that take and return a `RealWorld`.
+    fn parse_primitive_io(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        let run = one(csts, args_pos)?;
+        let action = self.io_action_csts(run.clone(), pos);
+        self.parse_expr(&action)
+    }
+
+    /// Parse `(primitive-bind m f)`, the IO action that performs `m`, applies `f` to the
+    /// result, and performs the resulting action
+    fn parse_primitive_bind(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        args_pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        let (m, f) = two(csts, args_pos)?;
+        let action = self.bind_csts(m.clone(), f.clone(), pos);
+        self.parse_expr(&action)
+    }
+
+    /// Returns whether `op` is a primitive IO form in the module of `pos`
+    ///
+    /// The primitive forms take IO actions apart, so they are only available to std and to
+    /// modules that declare externs. Anywhere else they are ordinary identifiers.
+    fn is_primitive_form(&self, op: &Cst<'s>, pos: &SrcPos<'s>) -> bool {
+        let is_primitive = ident_s(op)
+            .map(|s| PRIMITIVE_FORMS.contains(&s))
+            .unwrap_or(false);
+        let in_std = self.std_import()
+            .map(|import| import.path.path() == pos.filename())
+            .unwrap_or(false);
+        is_primitive && (in_std || self.extern_modules.contains(pos.filename()))
+    }
+
+    fn parse_primitive_form(
+        &mut self,
+        head: &Cst<'s>,
+        tail: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, Expr<'s>> {
+        let form = ident(head)?;
+        let tail_pos = pos.after(&form.pos);
+        match form.s {
+            "primitive-io" => self.parse_primitive_io(tail, pos, &tail_pos),
+            "primitive-bind" => self.parse_primitive_bind(tail, pos, &tail_pos),
+            _ => Err(NotASpecForm(form.pos.clone(), form.s)),
+        }
+    }
+
     fn parse_special_form(
         &mut self,
         head: &Cst<'s>,
@@ -1021,6 +2114,8 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                 pos,
                 &tail_pos,
             )?))),
+            "with" => self.parse_with(tail, pos, &tail_pos),
+            "@" => self.parse_explicit_type_application(tail, &tail_pos),
 
             // "Macros"
             "cond" => self.parse_cond(tail, &tail_pos),
@@ -1033,6 +2128,8 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         if let Some((head, tail)) = cs.split_first() {
             if is_special_operator(head) {
                 self.parse_special_form(head, tail, pos)
+            } else if self.is_primitive_form(head, pos) {
+                self.parse_primitive_form(head, tail, pos)
             } else {
                 self.parse_app(head, tail, pos).map(|a| Expr::App(box a))
             }
@@ -1054,9 +2151,15 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                 val: false,
                 pos: pos.clone(),
             })),
+            Cst::Ident(ident, ref pos) if ident.starts_with('?') => Ok(Expr::Hole(Hole {
+                name: if ident.len() > 1 { Some(&ident[1..]) } else { None },
+                typ: self.gen_type_var(),
+                pos: pos.clone(),
+            })),
             Cst::Ident(ident, ref pos) => Ok(Expr::Variable(Variable {
                 ident: Ident::new(ident, pos.clone()),
                 typ: self.gen_type_var(),
+                type_args: Vec::new(),
             })),
             Cst::Num(num, ref pos) => Ok(Expr::NumLit(NumLit {
                 lit: num,
@@ -1070,6 +2173,93 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         }
     }
 
+    /// Parse a type alias definition, like `(type (IO a) (-> RealWorld (Cons a RealWorld)))`
+    fn parse_type_alias_def(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, TypeAlias<'s>> {
+        let (head_c, body_c) = two(csts, pos)?;
+        let (name, params) = self.parse_data_binding(head_c)?;
+        if !name.s.starts_with(char::is_uppercase) {
+            return Err(InvalidTypeAliasIdent(name.pos.clone(), name.s));
+        }
+        let body = self.parse_type(body_c)?;
+        let params = params.into_iter().map(TVar::Explicit).collect::<Vec<_>>();
+        if let Some(&tv) = body.explicit_type_vars()
+            .iter()
+            .find(|tv| !params.contains(*tv))
+        {
+            return Err(UnboundTypeAliasTVar {
+                pos: body_c.pos().clone(),
+                name: tv,
+                alias: name.s,
+            });
+        }
+        let fresh_params = params.iter().map(|_| self.gen_tvar()).collect::<Vec<_>>();
+        let mut s = zip(params, fresh_params.iter().map(|&tv| Type::Var(tv))).collect();
+        Ok(TypeAlias {
+            name,
+            params: fresh_params,
+            body: subst(&body, &mut s),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Returns the type alias of name `name`, if any, parsing its definition if not
+    /// already parsed
+    ///
+    /// `pos` is the position of the reference to the alias, and is used to report cycles.
+    fn type_alias(&mut self, name: &'s str, pos: &SrcPos<'s>) -> PRes<'s, Option<TypeAlias<'s>>> {
+        if let Some(alias) = self.type_aliases.defs.get(name) {
+            return Ok(Some(alias.clone()));
+        }
+        let (csts, def_pos) = match self.type_alias_csts.get(name) {
+            Some(&(ref csts, ref def_pos)) => (csts.clone(), def_pos.clone()),
+            None => return Ok(None),
+        };
+        let reference = Ident {
+            s: name,
+            pos: pos.clone(),
+        };
+        if let Some(i) = self.alias_chain.iter().position(|a| a.s == name) {
+            let mut cycle = self.alias_chain[i..].to_vec();
+            cycle.push(reference);
+            return Err(TypeAliasCycle(cycle));
+        }
+        self.alias_chain.push(reference);
+        let alias = self.parse_type_alias_def(&csts, &def_pos)?;
+        self.alias_chain.pop();
+        self.type_aliases.defs.insert(name, alias.clone());
+        Ok(Some(alias))
+    }
+
+    /// Parse all type alias definitions
+    ///
+    /// Must be done before anything else that contains types is parsed, so that the aliases
+    /// can be expanded.
+    fn parse_type_aliases(&mut self, defs_csts: &[(Vec<Cst<'s>>, SrcPos<'s>)]) -> PRes<'s, ()> {
+        let mut names = Vec::new();
+        for &(ref def_csts, ref pos) in defs_csts {
+            let (head_c, _) = split_first(def_csts, pos)?;
+            let (name, _) = self.parse_data_binding(head_c)?;
+            if let Some((_, prev_pos)) = self.type_alias_csts
+                .insert(name.s, (def_csts.clone(), pos.clone()))
+            {
+                return Err(TypeAliasDuplDef {
+                    pos: pos.clone(),
+                    name: name.s,
+                    prev_pos,
+                });
+            }
+            names.push(name);
+        }
+        for name in names {
+            self.type_alias(name.s, &name.pos)?;
+        }
+        Ok(())
+    }
+
     fn parse_data_binding(&mut self, cst: &Cst<'s>) -> PRes<'s, (Ident<'s>, Vec<&'s str>)> {
         match *cst {
             Cst::Ident(s, ref pos) => Ok((
@@ -1145,6 +2335,38 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         cs.iter().map(|c| self.parse_data_type_variant(c)).collect()
     }
 
+    /// Parse the classes of a `deriving` clause, like `(deriving Eq Ord)`
+    fn parse_derivings(&mut self, csts: &[Cst<'s>]) -> PRes<'s, Vec<Ident<'s>>> {
+        let mut classes: Vec<Ident> = Vec::new();
+        for c in csts {
+            let class = ident(c)?;
+            if let Some(prev) = classes.iter().find(|prev| prev.s == class.s) {
+                return Err(InstanceDuplDef {
+                    pos: class.pos.clone(),
+                    class: class.s,
+                    prev_pos: prev.pos.clone(),
+                });
+            }
+            classes.push(class)
+        }
+        Ok(classes)
+    }
+
+    /// Split off and parse the optional trailing `deriving` clause of a data type definition
+    fn parse_trailing_derivings<'c>(
+        &mut self,
+        csts: &'c [Cst<'s>],
+    ) -> PRes<'s, (&'c [Cst<'s>], Vec<Ident<'s>>)> {
+        match csts.split_last() {
+            Some((&Cst::Sexpr(ref xs, _), init))
+                if xs.first().and_then(|x| ident_s(x).ok()) == Some("deriving") =>
+            {
+                Ok((init, self.parse_derivings(&xs[1..])?))
+            }
+            _ => Ok((csts, Vec::new())),
+        }
+    }
+
     /// Parse a data type definition
     fn parse_data_type_def(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, AdtDef<'s>> {
         let (bnd_c, variants_c) = split_first(csts, pos)?;
@@ -1152,17 +2374,101 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         if !name.s.starts_with(char::is_uppercase) {
             return Err(InvalidAdtIdent(name.pos.clone(), name.s));
         }
+        let (variants_c, derivings) = self.parse_trailing_derivings(variants_c)?;
         Ok(AdtDef {
             name,
             params,
             variants: self.parse_data_type_variants(variants_c)?,
+            derivings,
+            is_newtype: false,
+            fields: None,
             pos: pos.clone(),
         })
     }
 
-    fn parse_data_type_defs(&mut self, defs_csts: &[(Vec<Cst<'s>>, SrcPos<'s>)]) -> PRes<'s, ()> {
-        for &(ref def_csts, ref pos) in defs_csts {
-            let def = self.parse_data_type_def(def_csts, pos)?;
+    /// Parse the declaration of a field of a record, like `(x Float64)`
+    fn parse_record_field(&mut self, cst: &Cst<'s>) -> PRes<'s, (Ident<'s>, Type<'s>)> {
+        match *cst {
+            Cst::Sexpr(ref xs, _) if xs.len() == 2 => {
+                Ok((ident(&xs[0])?, self.parse_type(&xs[1])?))
+            }
+            _ => Err(InvalidRecordField(cst.pos().clone())),
+        }
+    }
+
+    /// Parse a record type definition, like `(record (Pair a b) (fst a) (snd b))`
+    ///
+    /// A record is a data type with a single variant of the same name as the type, of which
+    /// the members are named.
+    fn parse_record_def(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, AdtDef<'s>> {
+        let (bnd_c, fields_c) = split_first(csts, pos)?;
+        let (name, params) = self.parse_data_binding(bnd_c)?;
+        if !name.s.starts_with(char::is_uppercase) {
+            return Err(InvalidAdtIdent(name.pos.clone(), name.s));
+        }
+        let (fields_c, derivings) = self.parse_trailing_derivings(fields_c)?;
+        let mut fields: Vec<Ident> = Vec::new();
+        let mut members = Vec::new();
+        for field_c in fields_c {
+            let (field, typ) = self.parse_record_field(field_c)?;
+            if let Some(prev) = fields.iter().find(|prev| prev.s == field.s) {
+                return Err(DuplField {
+                    pos: field.pos.clone(),
+                    name: field.s,
+                    prev_pos: prev.pos.clone(),
+                });
+            }
+            fields.push(field);
+            members.push(typ);
+        }
+        Ok(AdtDef {
+            name: name.clone(),
+            params,
+            variants: vec![
+                AdtVariant {
+                    name,
+                    members,
+                    pos: pos.clone(),
+                },
+            ],
+            derivings,
+            is_newtype: false,
+            fields: Some(fields),
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a newtype definition
+    ///
+    /// Same syntax as a data type definition, but must have a single variant of a single member.
+    fn parse_newtype_def(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, AdtDef<'s>> {
+        let def = self.parse_data_type_def(csts, pos)?;
+        if def.variants.len() != 1 || def.variants[0].members.len() != 1 {
+            return Err(InvalidNewtype(pos.clone()));
+        }
+        Ok(AdtDef {
+            is_newtype: true,
+            ..def
+        })
+    }
+
+    fn parse_data_type_defs(
+        &mut self,
+        defs_csts: &[(AdtForm, Vec<Cst<'s>>, SrcPos<'s>)],
+    ) -> PRes<'s, ()> {
+        for &(form, ref def_csts, ref pos) in defs_csts {
+            let def = match form {
+                AdtForm::Data => self.parse_data_type_def(def_csts, pos)?,
+                AdtForm::Newtype => self.parse_newtype_def(def_csts, pos)?,
+                AdtForm::Record => self.parse_record_def(def_csts, pos)?,
+            };
+            if let Some(alias) = self.type_aliases.defs.get(def.name.s) {
+                return Err(TypeAliasDuplDef {
+                    pos: def.pos,
+                    name: def.name.s,
+                    prev_pos: alias.pos.clone(),
+                });
+            }
             if let Some(prev_def) = self.adts.defs.insert(def.name.s, def.clone()) {
                 return Err(DataTypeDuplDef {
                     pos: def.pos,
@@ -1186,6 +2492,346 @@ impl<'tvg, 's> Parser<'tvg, 's> {
                 }
             }
         }
+        for def in self.adts.defs.values() {
+            if def.is_newtype && self.adts.adt_is_recursive(def) {
+                return Err(RecursiveNewtype(def.pos.clone(), def.name.s));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the head of a class or instance declaration, with an optional context of
+    /// constraints
+    ///
+    /// E.g. `(Eq a)`, or `(constrain [(Eq a)] (Eq (List a)))`.
+    /// Returns the constraints of the context, the class, and the type argument of the class
+    fn parse_class_head<'c>(
+        &mut self,
+        cst: &'c Cst<'s>,
+    ) -> PRes<'s, (BTreeMap<TVar<'s>, BTreeSet<&'s str>>, Ident<'s>, &'c Cst<'s>)> {
+        let csts = sexpr(cst)?;
+        let (context, head) = if first(csts, cst.pos())
+            .and_then(ident_s)
+            .map(|s| s == "constrain")
+            .unwrap_or(false)
+        {
+            let (a, b) = two(&csts[1..], cst.pos())?;
+            (self.parse_constraints(sexpr(a)?)?, b)
+        } else {
+            (BTreeMap::new(), cst)
+        };
+        let (class_c, arg) = pair(head)?;
+        Ok((context, ident(class_c)?, arg))
+    }
+
+    /// Returns the name of the class declared by a class definition, without parsing the rest
+    fn parse_class_name(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, &'s str> {
+        let head_c = first(csts, pos)?;
+        let head = sexpr(head_c)?;
+        let head = if first(head, head_c.pos())
+            .and_then(ident_s)
+            .map(|s| s == "constrain")
+            .unwrap_or(false)
+        {
+            sexpr(last(head, head_c.pos())?)?
+        } else {
+            head
+        };
+        ident_s(first(head, head_c.pos())?)
+    }
+
+    /// Parse a method declaration of the form `(: name type)` in the class `class` with
+    /// parameter `param`
+    fn parse_method_decl(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+        class: &'s str,
+        param: TVar<'s>,
+    ) -> PRes<'s, MethodDecl<'s>> {
+        let (name_c, typ_c) = two(csts, pos)?;
+        let ident = ident(name_c)?;
+        let mut sig = self.parse_def_type_sig(typ_c)?;
+        let tvs = sig.body.explicit_type_vars();
+        if !tvs.contains(&param) {
+            return Err(AmbiguousMethod {
+                pos: pos.clone(),
+                name: ident.s,
+                class,
+            });
+        }
+        for tv in tvs {
+            sig.params.entry(tv).or_insert(BTreeSet::new());
+        }
+        sig.params
+            .get_mut(&param)
+            .expect("ICE: class param not in method sig")
+            .insert(class);
+        Ok(MethodDecl {
+            ident,
+            sig,
+            pos: pos.clone(),
+        })
+    }
+
+    /// Parse a type class definition
+    fn parse_class_def(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, ClassDef<'s>> {
+        let (head_c, items) = split_first(csts, pos)?;
+        let (context, name, param_c) = self.parse_class_head(head_c)?;
+        let param = self.parse_type_var_ident(param_c)?;
+        let param_tv = TVar::Explicit(param.s);
+        let mut superclasses = BTreeSet::new();
+        for (tv, constrs) in context {
+            if tv != param_tv {
+                return Err(InvalidConstr(head_c.pos().clone()));
+            }
+            superclasses.extend(constrs);
+        }
+        let mut methods = BTreeMap::new();
+        let mut defaults_csts = Vec::new();
+        for item in items {
+            let item_pos = item.pos();
+            let (first, rest) = split_first(sexpr(item)?, item_pos)?;
+            match ident_s(first)? {
+                ":" => {
+                    let decl = self.parse_method_decl(rest, item_pos, name.s, param_tv)?;
+                    let (method_name, method_pos) = (decl.ident.s, decl.pos.clone());
+                    if let Some(prev) = methods.insert(method_name, decl) {
+                        return Err(VarDuplDef {
+                            pos: method_pos,
+                            name: method_name,
+                            prev_pos: prev.pos,
+                        });
+                    }
+                }
+                "define" => defaults_csts.push((rest, item_pos)),
+                _ => return Err(InvalidClassItem(item_pos.clone())),
+            }
+        }
+        let mut defaults = BTreeMap::new();
+        for (rest, item_pos) in defaults_csts {
+            let mut binding = self.parse_untyped_binding(rest, item_pos)?;
+            binding.sig = methods
+                .get(binding.ident.s)
+                .map(|decl: &MethodDecl<'s>| decl.sig.clone())
+                .ok_or(UndefMethod {
+                    pos: binding.pos.clone(),
+                    name: binding.ident.s,
+                    class: name.s,
+                })?;
+            let (method_name, method_pos) = (binding.ident.s, binding.pos.clone());
+            if let Some(prev) = defaults.insert(method_name, binding) {
+                return Err(VarDuplDef {
+                    pos: method_pos,
+                    name: method_name,
+                    prev_pos: prev.pos,
+                });
+            }
+        }
+        Ok(ClassDef {
+            name,
+            param: param.s,
+            superclasses,
+            methods,
+            defaults,
+            pos: pos.clone(),
+        })
+    }
+
+    fn parse_class_defs(&mut self, defs_csts: &[(Vec<Cst<'s>>, SrcPos<'s>)]) -> PRes<'s, ()> {
+        for &(ref def_csts, ref pos) in defs_csts {
+            let name = self.parse_class_name(def_csts, pos)?;
+            if is_builtin_class(name) {
+                return Err(BuiltinClassRedef(pos.clone(), name));
+            }
+            self.class_names.insert(name);
+        }
+        for &(ref def_csts, ref pos) in defs_csts {
+            let def = self.parse_class_def(def_csts, pos)?;
+            for (&method_name, method) in &def.methods {
+                if let Some(prev_method) = self.classes.method_decl(method_name) {
+                    return Err(VarDuplDef {
+                        pos: method.pos.clone(),
+                        name: method_name,
+                        prev_pos: prev_method.pos.clone(),
+                    });
+                }
+            }
+            if let Some(prev_def) = self.classes.defs.get(def.name.s) {
+                return Err(ClassDuplDef {
+                    pos: def.pos.clone(),
+                    name: def.name.s,
+                    prev_pos: prev_def.pos.clone(),
+                });
+            }
+            for &method_name in def.methods.keys() {
+                self.classes.methods.insert(method_name, def.name.s);
+            }
+            self.classes.defs.insert(def.name.s, def);
+        }
+        Ok(())
+    }
+
+    /// Parse the head type of an instance, and return it along with its type variables
+    fn parse_instance_head_type(&mut self, cst: &Cst<'s>) -> PRes<'s, (Type<'s>, Vec<TVar<'s>>)> {
+        let typ = self.parse_type(cst)?;
+        let tvs = match typ {
+            Type::Const(..) => Vec::new(),
+            Type::App(box TypeFunc::Const(_), ref args) => {
+                let mut tvs = Vec::new();
+                for arg in args {
+                    match *arg {
+                        Type::Var(tv @ TVar::Explicit(_)) if !tvs.contains(&tv) => tvs.push(tv),
+                        _ => return Err(InvalidInstanceHead(cst.pos().clone())),
+                    }
+                }
+                tvs
+            }
+            _ => return Err(InvalidInstanceHead(cst.pos().clone())),
+        };
+        Ok((typ, tvs))
+    }
+
+    /// Parse an instance of a type class
+    fn parse_instance_def(
+        &mut self,
+        csts: &[Cst<'s>],
+        pos: &SrcPos<'s>,
+    ) -> PRes<'s, InstanceDef<'s>> {
+        let (head_c, items) = split_first(csts, pos)?;
+        let (mut params, class, head_type_c) = self.parse_class_head(head_c)?;
+        let class_def = self.classes
+            .defs
+            .get(class.s)
+            .cloned()
+            .or_else(|| builtin_class_def(class.s))
+            .ok_or(UndefConstr(class.pos.clone(), class.s))?;
+        let (head, head_tvs) = self.parse_instance_head_type(head_type_c)?;
+        if params.keys().any(|tv| !head_tvs.contains(tv)) {
+            return Err(InvalidConstr(head_c.pos().clone()));
+        }
+        for tv in head_tvs {
+            params.entry(tv).or_insert(BTreeSet::new());
+        }
+        let mut methods = BTreeMap::new();
+        for item in items {
+            let item_pos = item.pos();
+            let (first, rest) = split_first(sexpr(item)?, item_pos)?;
+            if ident_s(first)? != "define" {
+                return Err(InvalidClassItem(item_pos.clone()));
+            }
+            let mut binding = self.parse_untyped_binding(rest, item_pos)?;
+            let decl = class_def
+                .methods
+                .get(binding.ident.s)
+                .ok_or(UndefMethod {
+                    pos: binding.pos.clone(),
+                    name: binding.ident.s,
+                    class: class.s,
+                })?;
+            // The type of the method, with the class parameter instantiated to the head type
+            let mut method_params = decl.sig.params.clone();
+            method_params.remove(&TVar::Explicit(class_def.param));
+            for (&tv, constrs) in &params {
+                if method_params.insert(tv, constrs.clone()).is_some() {
+                    return Err(InstanceTVarClash {
+                        pos: head_type_c.pos().clone(),
+                        name: match tv {
+                            TVar::Explicit(s) => s,
+                            TVar::Implicit(_) => "_",
+                        },
+                        method: binding.ident.s,
+                    });
+                }
+            }
+            let mut s = map_of(TVar::Explicit(class_def.param), head.clone());
+            binding.sig = Poly {
+                params: method_params,
+                body: subst(&decl.sig.body, &mut s),
+            };
+            let (method_name, method_pos) = (binding.ident.s, binding.pos.clone());
+            if let Some(prev) = methods.insert(method_name, binding) {
+                return Err(VarDuplDef {
+                    pos: method_pos,
+                    name: method_name,
+                    prev_pos: prev.pos,
+                });
+            }
+        }
+        for &method_name in class_def.methods.keys() {
+            if !methods.contains_key(method_name) && !class_def.defaults.contains_key(method_name) {
+                return Err(MissingMethod {
+                    pos: pos.clone(),
+                    name: method_name,
+                    class: class.s,
+                });
+            }
+        }
+        Ok(InstanceDef {
+            class,
+            params,
+            head,
+            methods,
+            pos: pos.clone(),
+        })
+    }
+
+    fn add_instance_def(&mut self, def: InstanceDef<'s>) -> PRes<'s, ()> {
+        let con = head_type_constructor(&def.head)
+            .expect("ICE: instance head has no type constructor");
+        if let Some(prev_def) = self.classes.instances.get(&(def.class.s, con)) {
+            return Err(InstanceDuplDef {
+                pos: def.pos.clone(),
+                class: def.class.s,
+                prev_pos: prev_def.pos.clone(),
+            });
+        }
+        self.classes.instances.insert((def.class.s, con), def);
+        Ok(())
+    }
+
+    fn parse_instance_defs(&mut self, defs_csts: &[(Vec<Cst<'s>>, SrcPos<'s>)]) -> PRes<'s, ()> {
+        for &(ref def_csts, ref pos) in defs_csts {
+            let def = self.parse_instance_def(def_csts, pos)?;
+            self.add_instance_def(def)?;
+        }
+        let has_std = self.std_import().is_some();
+        let mut derived_csts = Vec::new();
+        for adt in self.adts.defs.values() {
+            for class in &adt.derivings {
+                let def_csts = derive_instance(adt, class)?;
+                if !has_std {
+                    return Err(DerivingWithoutStd(class.pos.clone(), class.s));
+                }
+                derived_csts.push((def_csts, class.pos.clone()));
+            }
+        }
+        for (def_csts, pos) in derived_csts {
+            let def = self.parse_instance_def(&def_csts, &pos)?;
+            self.add_instance_def(def)?;
+        }
+        // Every instance of a class must be accompanied by instances of the superclasses
+        for (&(class, con), inst) in &self.classes.instances {
+            let superclasses = match self.classes.defs.get(class) {
+                Some(def) => def.superclasses.clone(),
+                None => builtin_superclasses(class)
+                    .unwrap_or(&[])
+                    .iter()
+                    .cloned()
+                    .collect(),
+            };
+            for superclass in superclasses {
+                let is_builtin_inst = is_builtin_class(superclass)
+                    && inst.head.fulfills_constraints(&set_of(superclass));
+                if !self.classes.instances.contains_key(&(superclass, con)) && !is_builtin_inst {
+                    return Err(MissingSuperInstance {
+                        pos: inst.pos.clone(),
+                        class,
+                        superclass,
+                    });
+                }
+            }
+        }
         Ok(())
     }
 
@@ -1231,34 +2877,81 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         Ok(())
     }
 
+    /// Parse a declaration of the type that ambiguous types default to, like `(default Float64)`
+    ///
+    /// The declaration applies to the whole program, so declarations in different modules must
+    /// agree.
+    fn parse_default_decl(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, ()> {
+        let typ_c = one(csts, pos)?;
+        let typ = match *typ_c {
+            Cst::Ident(s, ref typ_pos) => Type::Const(s, Some(typ_pos.clone())),
+            _ => return Err(InvalidDefaultType(typ_c.pos().clone())),
+        };
+        if !typ.is_numeric() {
+            return Err(InvalidDefaultType(typ_c.pos().clone()));
+        }
+        match self.default_type {
+            Some((ref prev, ref prev_pos)) if *prev != typ => Err(ConflictingDefault {
+                pos: pos.clone(),
+                prev_pos: prev_pos.clone(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.default_type = Some((typ, pos.clone()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Parse an `(allow-coercion)` declaration, that enables implicit widening coercions
+    /// between numeric types for applications in the current module
+    fn parse_allow_coercion(&mut self, csts: &[Cst<'s>], pos: &SrcPos<'s>) -> PRes<'s, ()> {
+        n(0, csts, pos)?;
+        self.coercion_modules.insert(pos.filename());
+        Ok(())
+    }
+
     fn _get_top_level_csts<'c>(
         &mut self,
         csts: &'c [Cst<'s>],
-        externs: &mut Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
-        globals: &mut Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
-        adts: &mut Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
+        items: &mut TopLevelCsts<'s>,
     ) -> PRes<'s, ()> {
         for cst in csts {
+            if !is_import(cst) {
+                let current = self.current_module().clone();
+                cst_idents(cst, &mut self.import_graph.get_mut(&current).refs);
+            }
             let csts_ = macros::expand_macros(cst, &self.macros);
             for cst_ in csts_ {
                 let pos = cst_.pos().clone();
                 let (first, rest) = split_first(sexpr(&cst_)?, &pos)?;
                 let first_s = ident_s(first)?;
+                if first_s != "import" {
+                    let current = self.current_module().clone();
+                    top_level_item_defs(
+                        first_s,
+                        rest,
+                        &mut self.import_graph.get_mut(&current).defs,
+                    );
+                }
                 match first_s {
                     "import" => {
-                        let module_name = self.parse_import(rest, &pos)?;
-                        let module_path = CanonPathBuf::new(&format!("{}.kvs", module_name))
-                            .expect("ICE: Failed to canonicalize module path");
-                        if !self.sources.contains_key(&module_path) {
-                            let import_csts = lex_file(module_path, &self.sources);
-                            self._get_top_level_csts(&import_csts, externs, globals, adts)?
-                        }
+                        let import = self.parse_import(rest, &pos)?;
+                        self.follow_import(import, items)?
                     }
-                    "extern" => externs.push((rest.to_vec(), pos)),
-                    "define" => globals.push((false, rest.to_vec(), pos)),
-                    "define:" => globals.push((true, rest.to_vec(), pos)),
-                    "data" => adts.push((rest.to_vec(), pos.clone())),
+                    "extern" => items.externs.push((rest.to_vec(), pos)),
+                    "define" => items.globals.push((false, rest.to_vec(), pos)),
+                    "define:" => items.globals.push((true, rest.to_vec(), pos)),
+                    ":" => items.sigs.push((rest.to_vec(), pos)),
+                    "data" => items.adts.push((AdtForm::Data, rest.to_vec(), pos.clone())),
+                    "newtype" => items.adts.push((AdtForm::Newtype, rest.to_vec(), pos.clone())),
+                    "record" => items.adts.push((AdtForm::Record, rest.to_vec(), pos.clone())),
+                    "class" => items.classes.push((rest.to_vec(), pos)),
+                    "instance" => items.instances.push((rest.to_vec(), pos)),
+                    "type" => items.type_aliases.push((rest.to_vec(), pos)),
                     "macro" => self.parse_add_macro(rest, &pos)?,
+                    "default" => self.parse_default_decl(rest, &pos)?,
+                    "allow-coercion" => self.parse_allow_coercion(rest, &pos)?,
                     _ => return Err(InvalidTopLevelItem(pos)),
                 }
             }
@@ -1266,45 +2959,476 @@ impl<'tvg, 's> Parser<'tvg, 's> {
         Ok(())
     }
 
-    /// Separate `csts` into token trees for externs, and globals
+    /// Separate `csts` into token trees for externs, globals, data types, classes,
+    /// instances, and type aliases
     ///
     /// Recursively follow imports and get top level csts from there as well
-    fn get_top_level_csts<'c>(
-        &mut self,
-        csts: &'c [Cst<'s>],
-    ) -> PRes<
-        's,
-        (
-            Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
-            Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)>,
-            Vec<(Vec<Cst<'s>>, SrcPos<'s>)>,
-        ),
-    > {
-        let (mut externs, mut globals, mut adts) = (Vec::new(), Vec::new(), Vec::new());
-        self._get_top_level_csts(csts, &mut externs, &mut globals, &mut adts)?;
-        Ok((externs, globals, adts))
+    fn get_top_level_csts<'c>(&mut self, csts: &'c [Cst<'s>]) -> PRes<'s, TopLevelCsts<'s>> {
+        let mut items = TopLevelCsts {
+            externs: Vec::new(),
+            globals: Vec::new(),
+            adts: Vec::new(),
+            classes: Vec::new(),
+            instances: Vec::new(),
+            type_aliases: Vec::new(),
+            sigs: Vec::new(),
+        };
+        self._get_top_level_csts(csts, &mut items)?;
+        Ok(items)
     }
 
     fn parse_ast(&mut self, csts: &[Cst<'s>]) -> PRes<'s, Ast<'s>> {
-        let (externs_csts, globals_csts, adts_csts) = self.get_top_level_csts(csts)?;
-        let globals_csts_slc = globals_csts
+        let mut items = self.get_top_level_csts(csts)?;
+        self.parse_type_aliases(&items.type_aliases)?;
+        self.parse_data_type_defs(&items.adts)?;
+        for adt in self.adts.defs.values() {
+            if let Some(ref fields) = adt.fields {
+                items.globals.extend(record_defs(adt, fields));
+            }
+        }
+        if let Some(pos) = self.std_import().map(|import| import.pos.clone()) {
+            items.globals.extend(std_alias_defs(&pos));
+        }
+        attach_signatures(&mut items.globals, &items.sigs)?;
+        self.extern_modules
+            .extend(items.externs.iter().map(|&(_, ref pos)| pos.filename()));
+        let globals_csts_slc = items
+            .globals
             .iter()
             .map(|&(is_typed, ref v, ref p)| (is_typed, v.as_slice(), p.clone()))
             .collect::<Vec<_>>();
-        self.parse_data_type_defs(&adts_csts)?;
-        let externs = self.parse_externs(&externs_csts)?;
+        self.parse_class_defs(&items.classes)?;
+        self.parse_instance_defs(&items.instances)?;
+        let externs = self.parse_externs(&items.externs)?;
         let globals = self.parse_bindings(&globals_csts_slc)?;
+        for binding in globals.bindings() {
+            if let Some(method) = self.classes.method_decl(binding.ident.s) {
+                return Err(VarDuplDef {
+                    pos: binding.pos.clone(),
+                    name: binding.ident.s,
+                    prev_pos: method.pos.clone(),
+                });
+            }
+        }
         Ok(Ast {
             externs,
             globals,
             adts: mem::replace(&mut self.adts, Adts::new()),
+            classes: mem::replace(&mut self.classes, Classes::new()),
+            type_aliases: mem::replace(&mut self.type_aliases, TypeAliases::new()),
+            default_type: self.default_type
+                .as_ref()
+                .map(|&(ref t, _)| t.clone())
+                .unwrap_or(Type::Const("Int64", None)),
+            coercion_modules: mem::replace(&mut self.coercion_modules, BTreeSet::new()),
         })
     }
 
     /// Parse the file `filename`, and recursively parse imports as well
     fn parse_file(&mut self, filename: CanonPathBuf) -> PRes<'s, Ast<'s>> {
+        self.import_graph.add_module(filename.clone());
+        self.module_stack.push(filename.clone());
         let csts = lex_file(filename, &self.sources);
-        self.parse_ast(&csts)
+        let ast = self.parse_ast(&csts)?;
+        self.module_stack.pop();
+        Ok(ast)
+    }
+
+    /// Parse the source `src` as the module `filename`, like `parse_file`
+    #[cfg(test)]
+    fn parse_src(&mut self, filename: CanonPathBuf, src: &str) -> PRes<'s, Ast<'s>> {
+        self.import_graph.add_module(filename.clone());
+        self.module_stack.push(filename.clone());
+        let (filename_ref, src_ref) = self.sources.add(filename, src.to_string());
+        let csts = super::lex::lex_src(filename_ref.path(), src_ref);
+        let ast = self.parse_ast(&csts)?;
+        self.module_stack.pop();
+        Ok(ast)
+    }
+}
+
+/// Expand an application of the type alias `alias` to `args`
+fn expand_type_alias<'s>(
+    alias: &TypeAlias<'s>,
+    args: Vec<Type<'s>>,
+    pos: &SrcPos<'s>,
+) -> PRes<'s, Type<'s>> {
+    if args.len() != alias.params.len() {
+        return Err(ArityMis(pos.clone(), alias.params.len(), args.len()));
+    }
+    let mut s = zip(alias.params.iter().cloned(), args).collect();
+    Ok(subst(&alias.body, &mut s))
+}
+
+/// Returns a fresh identifier of the form `{prefix n}`
+///
+/// The braces make the identifier impossible to write in source, like `IO_CONSTR`, so that
+/// user code can not capture or shadow it.
+///
+/// Identifiers must live as long as the sources. Generated ones are few, so we just leak them.
+fn gen_ident(prefix: &str, n: usize) -> &'static str {
+    Box::leak(format!("{{{} {}}}", prefix, n).into_boxed_str())
+}
+
+/// The definitions in std that derived instances refer to, and their unwritable aliases
+///
+/// Like generated identifiers, the aliases can not be captured or shadowed by user code, so
+/// derived instances always refer to the definitions of std.
+const STD_ALIASES: &[(&str, &str)] = &[
+    ("eq", "{std eq}"),
+    ("lt", "{std lt}"),
+    ("show", "{std show}"),
+    ("append-s", "{std append-s}"),
+];
+
+/// Returns the unwritable alias of the definition `name` in std
+fn std_alias(name: &str) -> &'static str {
+    STD_ALIASES
+        .iter()
+        .find(|&&(n, _)| n == name)
+        .map(|&(_, alias)| alias)
+        .unwrap_or_else(|| panic!("ICE: `{}` has no std alias", name))
+}
+
+/// Generate the source of the global definitions of the aliases in `STD_ALIASES`, like
+/// `(define ({std eq} x) (eq x))`
+fn std_alias_defs<'s>(pos: &SrcPos<'s>) -> Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)> {
+    let id = |s: &'s str| Cst::Ident(s, pos.clone());
+    let sx = |xs: Vec<Cst<'s>>| Cst::Sexpr(xs, pos.clone());
+    STD_ALIASES
+        .iter()
+        .map(|&(name, alias)| {
+            let def = vec![sx(vec![id(alias), id("x")]), sx(vec![id(name), id("x")])];
+            (false, def, pos.clone())
+        })
+        .collect()
+}
+
+/// Returns a pattern deconstructing `variant`, binding the members to variables named
+/// after `prefix`, along with the bound variables
+fn derived_variant_pattern<'s>(
+    variant: &AdtVariant<'s>,
+    prefix: &str,
+    pos: &SrcPos<'s>,
+) -> (Cst<'s>, Vec<Cst<'s>>) {
+    let constr = Cst::Ident(variant.name.s, pos.clone());
+    if variant.members.is_empty() {
+        (constr, Vec::new())
+    } else {
+        let vars = (0..variant.members.len())
+            .map(|i| Cst::Ident(gen_ident(prefix, i), pos.clone()))
+            .collect::<Vec<_>>();
+        let patt = Cst::Sexpr(once(constr).chain(vars.clone()).collect(), pos.clone());
+        (patt, vars)
+    }
+}
+
+/// Generate the source of the method of a derived instance of `class` for the data type
+/// `adt`
+///
+/// `Eq` is structural equality, `Ord` orders lexicographically by variant index and then by
+/// members, and `Show` renders the constructor names and members as in an expression.
+///
+/// Definitions of std are referred to by their aliases in `STD_ALIASES`, and `cons`, `car`,
+/// `cdr`, `true`, and `false` are syntax, so the generated source can't be affected by
+/// definitions in user code.
+fn derived_method<'s>(adt: &AdtDef<'s>, class: &Ident<'s>) -> PRes<'s, Cst<'s>> {
+    let pos = &class.pos;
+    let id = |s: &'s str| Cst::Ident(s, pos.clone());
+    let sx = |xs: Vec<Cst<'s>>| Cst::Sexpr(xs, pos.clone());
+    let str_lit = |s: String| Cst::Str(Cow::Owned(s), pos.clone());
+    let bin_op = |op, a: &Cst<'s>, b: &Cst<'s>| {
+        sx(vec![id(std_alias(op)), sx(vec![id("cons"), a.clone(), b.clone()])])
+    };
+    let n_variants = adt.variants.len();
+    let (method, param, body) = match class.s {
+        "Eq" => {
+            let cases = adt.variants.iter().map(|variant| {
+                let (x_patt, xs) = derived_variant_pattern(variant, "x", pos);
+                let (y_patt, ys) = derived_variant_pattern(variant, "y", pos);
+                let all_eq = zip(&xs, &ys).rev().fold(id("true"), |acc, (x, y)| {
+                    sx(vec![id("if"), bin_op("eq", x, y), acc, id("false")])
+                });
+                let mut y_cases = vec![sx(vec![y_patt, all_eq])];
+                if n_variants > 1 {
+                    y_cases.push(sx(vec![id("_"), id("false")]));
+                }
+                let y_match = once(id("match"))
+                    .chain(once(sx(vec![id("cdr"), id("p")])))
+                    .chain(y_cases)
+                    .collect();
+                sx(vec![x_patt, sx(y_match)])
+            });
+            let body = once(id("match"))
+                .chain(once(sx(vec![id("car"), id("p")])))
+                .chain(cases)
+                .collect();
+            ("eq", "p", sx(body))
+        }
+        "Ord" => {
+            let cases = adt.variants.iter().enumerate().map(|(i, variant)| {
+                let (x_patt, xs) = derived_variant_pattern(variant, "x", pos);
+                let (y_patt, ys) = derived_variant_pattern(variant, "y", pos);
+                let lex_lt = zip(&xs, &ys).rev().fold(id("false"), |acc, (x, y)| {
+                    sx(vec![
+                        id("if"),
+                        bin_op("lt", x, y),
+                        id("true"),
+                        sx(vec![id("if"), bin_op("eq", x, y), acc, id("false")]),
+                    ])
+                });
+                // Variants of lower index are lesser, and of higher index greater
+                let mut y_cases = adt.variants[..i]
+                    .iter()
+                    .map(|v| sx(vec![derived_variant_pattern(v, "y", pos).0, id("false")]))
+                    .collect::<Vec<_>>();
+                y_cases.push(sx(vec![y_patt, lex_lt]));
+                if i + 1 < n_variants {
+                    y_cases.push(sx(vec![id("_"), id("true")]));
+                }
+                let y_match = once(id("match"))
+                    .chain(once(sx(vec![id("cdr"), id("p")])))
+                    .chain(y_cases)
+                    .collect();
+                sx(vec![x_patt, sx(y_match)])
+            });
+            let body = once(id("match"))
+                .chain(once(sx(vec![id("car"), id("p")])))
+                .chain(cases)
+                .collect();
+            ("lt", "p", sx(body))
+        }
+        "Show" => {
+            let cases = adt.variants.iter().map(|variant| {
+                let (x_patt, xs) = derived_variant_pattern(variant, "x", pos);
+                let shown = if xs.is_empty() {
+                    str_lit(variant.name.s.to_string())
+                } else {
+                    let mut parts = vec![str_lit(format!("({}", variant.name.s))];
+                    for x in xs {
+                        parts.push(str_lit(" ".to_string()));
+                        parts.push(sx(vec![id(std_alias("show")), x]));
+                    }
+                    let close = str_lit(")".to_string());
+                    parts
+                        .into_iter()
+                        .rev()
+                        .fold(close, |acc, part| sx(vec![id(std_alias("append-s")), part, acc]))
+                };
+                sx(vec![x_patt, shown])
+            });
+            let body = once(id("match")).chain(once(id("x"))).chain(cases).collect();
+            ("show", "x", sx(body))
+        }
+        _ => return Err(InvalidDeriving(class.pos.clone(), class.s)),
+    };
+    Ok(sx(vec![
+        id("define"),
+        sx(vec![id(method), id(param)]),
+        body,
+    ]))
+}
+
+/// Generate the source of a derived instance of `class` for the data type `adt`, like
+/// `((constrain [(Eq a)] (Eq (List a))) (define (eq p) ...))`
+fn derive_instance<'s>(adt: &AdtDef<'s>, class: &Ident<'s>) -> PRes<'s, Vec<Cst<'s>>> {
+    let pos = &class.pos;
+    let id = |s: &'s str| Cst::Ident(s, pos.clone());
+    let sx = |xs: Vec<Cst<'s>>| Cst::Sexpr(xs, pos.clone());
+    let method = derived_method(adt, class)?;
+    let head = if adt.params.is_empty() {
+        sx(vec![id(class.s), id(adt.name.s)])
+    } else {
+        let head_type = sx(once(id(adt.name.s))
+            .chain(adt.params.iter().map(|&p| id(p)))
+            .collect());
+        let context = adt.params
+            .iter()
+            .map(|&p| sx(vec![id(class.s), id(p)]))
+            .collect();
+        sx(vec![
+            id("constrain"),
+            sx(context),
+            sx(vec![id(class.s), head_type]),
+        ])
+    };
+    Ok(vec![head, method])
+}
+
+/// Generate the source of the constructor function and the field accessors of the record
+/// `adt` with the fields `fields`, as global definitions
+///
+/// E.g. `(define (Point x y) (new Point x y))`, and `(define (x r) (match r [(Point x _) x]))`.
+fn record_defs<'s>(
+    adt: &AdtDef<'s>,
+    fields: &[Ident<'s>],
+) -> Vec<(bool, Vec<Cst<'s>>, SrcPos<'s>)> {
+    let name = adt.name.s;
+    let id = |s: &'s str, pos: &SrcPos<'s>| Cst::Ident(s, pos.clone());
+    let sx = |xs: Vec<Cst<'s>>, pos: &SrcPos<'s>| Cst::Sexpr(xs, pos.clone());
+    let pos = &adt.pos;
+    let field_ids = fields.iter().map(|f| id(f.s, &f.pos)).collect::<Vec<_>>();
+    let constructor_patt = if fields.is_empty() {
+        id(name, pos)
+    } else {
+        sx(once(id(name, pos)).chain(field_ids.clone()).collect(), pos)
+    };
+    let constructor_body = sx(
+        vec![id("new", pos), id(name, pos)]
+            .into_iter()
+            .chain(field_ids)
+            .collect(),
+        pos,
+    );
+    let mut defs = vec![(false, vec![constructor_patt, constructor_body], pos.clone())];
+    for (i, field) in fields.iter().enumerate() {
+        let pos = &field.pos;
+        let patt = once(id(name, pos))
+            .chain((0..fields.len()).map(|j| id(if i == j { field.s } else { "_" }, pos)))
+            .collect();
+        let accessor = vec![
+            sx(vec![id(field.s, pos), id("record", pos)], pos),
+            sx(
+                vec![
+                    id("match", pos),
+                    id("record", pos),
+                    sx(vec![sx(patt, pos), id(field.s, pos)], pos),
+                ],
+                pos,
+            ),
+        ];
+        defs.push((false, accessor, pos.clone()))
+    }
+    defs
+}
+
+/// Attach the stand-alone type signatures `sigs`, like `(: f (-> Int64 Int64))`, to the
+/// bindings of the same names, making them typed bindings
+fn attach_signatures<'s>(
+    bindings: &mut [(bool, Vec<Cst<'s>>, SrcPos<'s>)],
+    sigs: &[(Vec<Cst<'s>>, SrcPos<'s>)],
+) -> PRes<'s, ()> {
+    let mut sigs_map: BTreeMap<&'s str, (&Cst<'s>, &SrcPos<'s>)> = BTreeMap::new();
+    for &(ref sig, ref pos) in sigs {
+        let (name_c, typ) = two(sig, pos)?;
+        let name = ident_s(name_c)?;
+        if let Some(&(_, prev_pos)) = sigs_map.get(name) {
+            return Err(SigDuplDef {
+                pos: pos.clone(),
+                name,
+                prev_pos: prev_pos.clone(),
+            });
+        }
+        sigs_map.insert(name, (typ, pos));
+    }
+    for &mut (ref mut is_typed, ref mut csts, ref pos) in bindings.iter_mut() {
+        // The name of a binding pattern, like `x` or `(f x)`
+        let name = match csts.first() {
+            Some(&Cst::Ident(s, _)) => s,
+            Some(&Cst::Sexpr(ref xs, _)) => match xs.first() {
+                Some(&Cst::Ident(s, _)) => s,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if let Some((typ, sig_pos)) = sigs_map.remove(name) {
+            if *is_typed {
+                return Err(ConflictingSig {
+                    pos: sig_pos.clone(),
+                    name,
+                    binding_pos: pos.clone(),
+                });
+            }
+            csts.insert(1, typ.clone());
+            *is_typed = true;
+        }
+    }
+    match sigs_map.into_iter().next() {
+        Some((name, (_, pos))) => Err(OrphanSig(pos.clone(), name)),
+        None => Ok(()),
+    }
+}
+
+/// Returns whether `cst` is a subpattern of a record pattern by field name, like `[x px]`
+fn is_field_pattern(cst: &Cst, fields: &[Ident]) -> bool {
+    match *cst {
+        Cst::Sexpr(ref xs, _) if xs.len() == 2 => ident_s(&xs[0])
+            .map(|s| fields.iter().any(|f| f.s == s))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Returns whether `cst` is an `import` item
+fn is_import(cst: &Cst) -> bool {
+    match *cst {
+        Cst::Sexpr(ref xs, _) => xs.first().and_then(|x| ident_s(x).ok()) == Some("import"),
+        _ => false,
+    }
+}
+
+/// Collect all identifiers occuring in `cst` into `idents`
+fn cst_idents<'s>(cst: &Cst<'s>, idents: &mut BTreeSet<&'s str>) {
+    match *cst {
+        Cst::Ident(s, _) => {
+            idents.insert(s);
+        }
+        Cst::Sexpr(ref xs, _) => for x in xs {
+            cst_idents(x, idents)
+        },
+        Cst::Num(..) | Cst::Str(..) => (),
+    }
+}
+
+/// Collect the names defined by the top level item `(first_s rest...)` into `defs`
+///
+/// Malformed items are ignored here, as they are reported when actually parsed.
+fn top_level_item_defs<'s>(first_s: &str, rest: &[Cst<'s>], defs: &mut BTreeSet<&'s str>) {
+    // The name of a binding pattern or data type head, like `x`, `(f x)`, or `(List t)`
+    fn head_name<'s>(c: &Cst<'s>) -> Option<&'s str> {
+        match *c {
+            Cst::Ident(s, _) => Some(s),
+            Cst::Sexpr(ref xs, _) => xs.first().and_then(|x| ident_s(x).ok()),
+            _ => None,
+        }
+    }
+    match first_s {
+        "extern" | "define" | "define:" | "macro" | "type" => {
+            defs.extend(rest.first().and_then(head_name));
+        }
+        "record" => {
+            defs.extend(rest.first().and_then(head_name));
+            for field in rest.iter().skip(1) {
+                if let Cst::Sexpr(ref xs, _) = *field {
+                    if head_name(field) != Some("deriving") {
+                        defs.extend(xs.first().and_then(head_name));
+                    }
+                }
+            }
+        }
+        "data" | "newtype" => defs.extend(
+            rest.iter()
+                .filter_map(head_name)
+                .filter(|&name| name != "deriving"),
+        ),
+        "class" => {
+            let class_name = match rest.first() {
+                Some(&Cst::Sexpr(ref head, _)) if head_name(&rest[0]) == Some("constrain") => {
+                    head.last().and_then(head_name)
+                }
+                Some(head) => head_name(head),
+                None => None,
+            };
+            defs.extend(class_name);
+            for item in rest.iter().skip(1) {
+                if let Cst::Sexpr(ref xs, _) = *item {
+                    if xs.len() > 1 && head_name(item) == Some(":") {
+                        defs.extend(head_name(&xs[1]));
+                    }
+                }
+            }
+        }
+        _ => (),
     }
 }
 
@@ -1312,17 +3436,32 @@ impl<'tvg, 's> Parser<'tvg, 's> {
 ///
 /// Given the name of a file that contains the program entry point,
 /// read, lex, and parse the source, and include imported modules
-/// as needed
+/// as needed. Also returns the graph of imports between the modules
 pub fn parse_program<'s>(
     filename: CanonPathBuf,
     sources: &'s AddMap<CanonPathBuf, String>,
     type_var_gen: &mut TypeVarGen,
-) -> Ast<'s> {
+) -> (Ast<'s>, ImportGraph<'s>) {
     let mut parser = Parser::new(sources, type_var_gen);
-    parser.parse_file(filename).unwrap_or_else(|e| {
+    let ast = parser.parse_file(filename).unwrap_or_else(|e| {
         e.print();
         exit()
-    })
+    });
+    parser.import_graph.warn_unused_imports();
+    (ast, parser.import_graph)
+}
+
+/// Returns the Abstract Syntax Tree of the program in the source `src`, which must not import
+/// other modules
+#[cfg(test)]
+pub fn parse_test_program<'s>(
+    src: &str,
+    sources: &'s AddMap<CanonPathBuf, String>,
+    type_var_gen: &mut TypeVarGen,
+) -> Ast<'s> {
+    Parser::new(sources, type_var_gen)
+        .parse_src(CanonPathBuf::new_dummy("test.kvs"), src)
+        .unwrap_or_else(|e| panic!("{:?}", e))
 }
 
 // TODO: Fix all passings of `pos` to functions like `first`, `split_first`, `two`, etc.
@@ -1330,11 +3469,14 @@ pub fn parse_program<'s>(
 
 #[cfg(test)]
 mod test {
+    use lib::CanonPathBuf;
     use lib::collections::AddMap;
-    use lib::front::lex::Cst;
+    use lib::front::cst::Cst;
     use lib::front::*;
     use lib::front::ast::*;
+    use lib::front::import_graph::Import;
     use super::Parser;
+    use super::PErr::*;
 
     fn dummy_cident(s: &str) -> Cst {
         Cst::Ident(s, SrcPos::new_dummy())
@@ -1362,6 +3504,7 @@ mod test {
                     s: "Foo",
                     pos: SrcPos::new_dummy(),
                 },
+                params: vec![],
                 variants: vec![
                     AdtVariant {
                         name: dummy_ident("Foo"),
@@ -1369,8 +3512,179 @@ mod test {
                         pos: SrcPos::new_dummy(),
                     },
                 ],
+                derivings: vec![],
+                is_newtype: false,
+                fields: None,
                 pos: SrcPos::new_dummy(),
             })
         )
     }
+
+    #[test]
+    fn test_module_defs_and_refs() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut parser = Parser::new(&sources, &mut tvg);
+        let module = CanonPathBuf::new_dummy("test.kvs");
+        parser
+            .parse_src(module.clone(), "(data Foo Bar) (define x (new Bar))")
+            .unwrap();
+        let m = &parser.import_graph.modules[&module];
+        assert_eq!(m.defs, ["Foo", "Bar", "x"].iter().cloned().collect());
+        assert!(m.refs.contains("Bar"));
+        assert!(!m.refs.contains("Baz"));
+    }
+
+    #[test]
+    fn test_unused_imports() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut parser = Parser::new(&sources, &mut tvg);
+        let (a, b, c) = (
+            CanonPathBuf::new_dummy("a.kvs"),
+            CanonPathBuf::new_dummy("b.kvs"),
+            CanonPathBuf::new_dummy("c.kvs"),
+        );
+        parser.parse_src(b.clone(), "(define y 1)").unwrap();
+        parser.parse_src(c.clone(), "(define z 2)").unwrap();
+        parser.parse_src(a.clone(), "(define x y)").unwrap();
+        for (name, path) in vec![("b", b), ("c", c)] {
+            parser.import_graph.get_mut(&a).imports.push(Import {
+                name,
+                path,
+                pos: SrcPos::new_dummy(),
+            })
+        }
+        let unused = parser.import_graph.unused_imports();
+        assert_eq!(unused.len(), 1);
+        assert_eq!(unused[0].name, "c");
+    }
+
+    #[test]
+    fn test_parse_default_decl() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut default_of = |src: &str| {
+            Parser::new(&sources, &mut tvg)
+                .parse_src(CanonPathBuf::new_dummy("test.kvs"), src)
+                .map(|ast| ast.default_type.to_string())
+        };
+        assert_eq!(default_of("(define x 1)"), Ok("Int64".to_string()));
+        assert_eq!(default_of("(default Float64)"), Ok("Float64".to_string()));
+        assert_eq!(
+            default_of("(default Float64) (default Float64)"),
+            Ok("Float64".to_string())
+        );
+        match default_of("(default Bool)") {
+            Err(InvalidDefaultType(_)) => (),
+            r => panic!("expected invalid default type, found {:?}", r),
+        }
+        match default_of("(default Float64) (default Int32)") {
+            Err(ConflictingDefault { .. }) => (),
+            r => panic!("expected conflicting default, found {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_attach_signatures() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let ast = Parser::new(&sources, &mut tvg)
+            .parse_src(
+                CanonPathBuf::new_dummy("test.kvs"),
+                "(: f (-> Int64 Int64))
+                 (define (f x) x)
+                 (define y (let [(: z Int64) [z 1]] z))",
+            )
+            .unwrap();
+        let global = |name: &str| {
+            ast.globals
+                .bindings()
+                .find(|b| b.ident.s == name)
+                .expect("binding not found")
+        };
+        assert_eq!(global("f").sig.body.to_string(), "(-> Int64 Int64)");
+        let z_sig = match global("y").val {
+            Expr::Let(ref l) => l.bindings.bindings().next().unwrap().sig.body.to_string(),
+            _ => panic!("value of `y` is not a let"),
+        };
+        assert_eq!(z_sig, "Int64");
+    }
+
+    #[test]
+    fn test_attach_signatures_errors() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut parse = |src: &str| {
+            Parser::new(&sources, &mut tvg)
+                .parse_src(CanonPathBuf::new_dummy("test.kvs"), src)
+                .map(|_| ())
+        };
+        match parse("(: z Int64) (define x 1)") {
+            Err(OrphanSig(_, "z")) => (),
+            r => panic!("expected orphan signature, found {:?}", r),
+        }
+        match parse("(: x Int64) (: x Int64) (define x 1)") {
+            Err(SigDuplDef { name: "x", .. }) => (),
+            r => panic!("expected duplicate signature, found {:?}", r),
+        }
+        match parse("(: x Int64) (define: x Int64 1)") {
+            Err(ConflictingSig { name: "x", .. }) => (),
+            r => panic!("expected conflicting signature, found {:?}", r),
+        }
+        match parse("(define y (let [(: z Int64) [w 1]] w))") {
+            Err(OrphanSig(_, "z")) => (),
+            r => panic!("expected orphan signature in let, found {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_primitive_forms() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let mut parse_y = |src: &str| {
+            let ast = Parser::new(&sources, &mut tvg)
+                .parse_src(CanonPathBuf::new_dummy("test.kvs"), src)
+                .unwrap();
+            let y = ast.globals.bindings().find(|b| b.ident.s == "y").unwrap();
+            match y.val {
+                Expr::New(_) => "new",
+                Expr::App(_) => "app",
+                _ => "other",
+            }
+        };
+        assert_eq!(
+            parse_y(
+                "(extern c_f (-> RealWorld (Cons Nil RealWorld)))
+                 (define y (primitive-io c_f))"
+            ),
+            "new"
+        );
+        assert_eq!(
+            parse_y(
+                "(define (return x) x)
+                 (define (bind m f) (f m))
+                 (define (do x) x)
+                 (define (primitive-io f) f)
+                 (define y (primitive-io (do (bind (return 1) return))))"
+            ),
+            "app"
+        );
+    }
+
+    #[test]
+    fn test_deriving_without_std() {
+        let sources = AddMap::new();
+        let mut tvg = TypeVarGen::new(0);
+        let res = Parser::new(&sources, &mut tvg)
+            .parse_src(
+                CanonPathBuf::new_dummy("test.kvs"),
+                "(data Color Red Green (deriving Eq))",
+            )
+            .map(|_| ());
+        match res {
+            Err(DerivingWithoutStd(_, "Eq")) => (),
+            r => panic!("expected error for deriving without std, found {:?}", r),
+        }
+    }
 }
@@ -21,6 +21,7 @@ fn subst_type_func<'src>(
     match *f {
         TypeFunc::Const(c) => TypeFunc::Const(c),
         TypeFunc::Poly(ref p) => TypeFunc::Poly(subst_poly(p, s)),
+        TypeFunc::Var(tv) => TypeFunc::Var(tv),
     }
 }
 
@@ -31,6 +32,11 @@ pub fn subst<'src>(t: &Type<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) ->
             .cloned()
             .map(|t2| subst(&t2, s))
             .unwrap_or(t.clone()),
+        // The head variable may be substituted by a type constructor, or a partial application
+        Type::App(box TypeFunc::Var(tv), ref ts) => {
+            let args = ts.iter().map(|t2| subst(t2, s)).collect();
+            subst(&Type::Var(tv), s).applied_to(args)
+        }
         Type::App(ref c, ref ts) => Type::App(
             Box::new(subst_type_func(c, s)),
             ts.iter().map(|t2| subst(t2, s)).collect(),
@@ -40,6 +46,30 @@ pub fn subst<'src>(t: &Type<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) ->
     }
 }
 
+/// Apply substitutions in `s` to all types in the pattern `p`
+fn subst_pattern<'src>(p: &mut Pattern<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) {
+    match *p {
+        Pattern::NumLit(ref mut n) => n.typ = subst(&n.typ, s),
+        Pattern::Variable(ref mut v) => v.typ = subst(&v.typ, s),
+        Pattern::Deconstr(ref mut d) => for sub in &mut d.subpatts {
+            subst_pattern(sub, s)
+        },
+        Pattern::As(ref mut a) => {
+            a.var.typ = subst(&a.var.typ, s);
+            subst_pattern(&mut a.patt, s)
+        }
+        Pattern::Or(ref mut o) => for alt in &mut o.alts {
+            subst_pattern(alt, s)
+        },
+        Pattern::Cons(ref mut c) => {
+            c.typ = subst(&c.typ, s);
+            subst_pattern(&mut c.car, s);
+            subst_pattern(&mut c.cdr, s)
+        }
+        Pattern::Wild(_) | Pattern::Nil(_) | Pattern::StrLit(_) => (),
+    }
+}
+
 /// Apply substitutions in `s` to type variables in types in `e`
 pub fn subst_expr<'src>(e: &mut Expr<'src>, s: &mut BTreeMap<TVar<'src>, Type<'src>>) {
     match *e {
@@ -100,12 +130,14 @@ pub fn subst_expr<'src>(e: &mut Expr<'src>, s: &mut BTreeMap<TVar<'src>, Type<'s
             m.typ = subst(&m.typ, s);
             for case in &mut m.cases {
                 case.patt_typ = subst(&case.patt_typ, s);
-                for v in case.patt.variables_mut() {
-                    v.typ = subst(&v.typ, s);
+                subst_pattern(&mut case.patt, s);
+                if let Some(ref mut guard) = case.guard {
+                    subst_expr(guard, s);
                 }
                 subst_expr(&mut case.body, s);
             }
         }
+        Expr::Hole(ref mut h) => h.typ = subst(&h.typ, s),
         Expr::Nil(_) | Expr::StrLit(_) | Expr::Bool(_) => (),
     }
 }
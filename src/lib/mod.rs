@@ -20,7 +20,7 @@ fn map_of<K: cmp::Ord, V>(k: K, v: V) -> BTreeMap<K, V> {
 }
 
 /// A path-buffer that is guaranteed to be canonical
-#[derive(PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct CanonPathBuf(PathBuf);
 
 impl CanonPathBuf {
@@ -30,6 +30,12 @@ impl CanonPathBuf {
             .map(|pb| CanonPathBuf(pb))
     }
 
+    /// A path that does not need to exist, for sources that are not read from a file
+    #[cfg(test)]
+    pub fn new_dummy(path: &str) -> Self {
+        CanonPathBuf(PathBuf::from(path))
+    }
+
     pub fn path(&self) -> &Path {
         self.0.as_ref()
     }
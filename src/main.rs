@@ -67,8 +67,6 @@
 //       a user expression does.
 //       Lint for unnecessarily specific types in function signatures
 // TODO: Implement some of current warnings, and maybe errors, as lints.
-// TODO: Optionally enabled used of `Coerce` trait to allow implicit coercion between
-//       'coerceable' type pairs. E.g. `Int32` to `UInt8`, or `
 // TODO: Add frontends for existing laanguages to easily port projects
 // TODO: Prioritize more specialized implementations of traits over more general implementations.
 //       E.g. `(impl Drop (Vec String))` comes before
@@ -99,7 +97,7 @@ use lib::collections::AddMap;
 use lib::back::compile;
 use lib::front::inference::infer_types;
 use lib::front::parse::parse_program;
-use std::{env, fmt, time};
+use std::{env, fmt, fs, time};
 
 mod lib;
 
@@ -113,6 +111,8 @@ pub enum Emission {
     Obj,
     /// An executable binary
     Exe,
+    /// The graph of module imports in the DOT format
+    ImportGraph,
 }
 impl<S: AsRef<str> + fmt::Display> From<S> for Emission {
     fn from(s: S) -> Emission {
@@ -121,6 +121,7 @@ impl<S: AsRef<str> + fmt::Display> From<S> for Emission {
             "llvm-bc" => Emission::LlvmBc,
             "obj" => Emission::Obj,
             "exe" => Emission::Exe,
+            "import-graph" => Emission::ImportGraph,
             _ => panic!("Unknown emission type `{}`", s),
         }
     }
@@ -149,10 +150,15 @@ fn main() {
             "",
             "emit",
             "Specify the type of output for the compiler to emit",
-            "llvm-ir|llvm-bc|obj|exe",
+            "llvm-ir|llvm-bc|obj|exe|import-graph",
         )
         .optmulti("l", "", "Link with <LIBRARY>", "LIBRARY")
         .optmulti("L", "", "Add <PATH> to the library search path", "PATH")
+        .optflag(
+            "",
+            "deny-defaulting",
+            "Make it an error for an ambiguous type to be defaulted",
+        )
         .optflag("h", "help", "Display this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -187,20 +193,45 @@ fn main() {
         .unwrap_or(Emission::Exe);
     let link_libs = matches.opt_strs("l");
     let lib_paths = matches.opt_strs("L");
+    let deny_defaulting = matches.opt_present("deny-defaulting");
 
     println!("    Compiling {}", inp_filename.path().display());
 
     let mut type_var_generator = lib::front::TypeVarGen::new(0);
     let sources = AddMap::new();
 
-    let mut ast = time_action(
+    let (mut ast, import_graph) = time_action(
         || parse_program(inp_filename, &sources, &mut type_var_generator),
         |t| println!("    Parsed source in {}s", t),
     );
     //println!("parsed:\n\n{}", ast);
 
+    if let Emission::ImportGraph = emission {
+        let dot_filename = if explicit_out_filename {
+            out_filename
+        } else {
+            out_filename.with_extension("dot")
+        };
+        let mut dot_file = fs::File::create(dot_filename.path()).unwrap_or_else(|e| {
+            panic!(
+                "Failed to open file `{}`, {}",
+                dot_filename.path().display(),
+                e
+            )
+        });
+        import_graph.write_dot(&mut dot_file).unwrap_or_else(|e| {
+            panic!(
+                "Failed to write import graph to `{}`, {}",
+                dot_filename.path().display(),
+                e
+            )
+        });
+        println!("    Wrote import graph to {}", dot_filename.path().display());
+        return;
+    }
+
     time_action(
-        || infer_types(&mut ast, &mut type_var_generator),
+        || infer_types(&mut ast, &mut type_var_generator, deny_defaulting),
         |t| println!("    Infered types and monomorphization in {}s", t),
     );
     //println!("inferred:\n\n{}", ast);